@@ -0,0 +1,349 @@
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+
+use crate::matrix::ModerationAction;
+
+/// What a slash command typed in the composer asks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Sends a message to the current room, as an emote if `emote` is set.
+    Send {
+        body: String,
+        emote: bool,
+    },
+    /// Joins a room by alias, ID or link.
+    Join(String),
+    /// Leaves the current room.
+    Leave,
+    Invite(OwnedUserId),
+    SetDisplayName(String),
+    SetTopic(String),
+    /// Sends a message to a user in our DM with them, starting one if needed.
+    DirectMessage(OwnedUserId, String),
+    /// Kicks or bans a member of the current room, with a reason.
+    Moderate(OwnedUserId, ModerationAction, String),
+    Devtools,
+}
+
+/// A command of the registry, e.g. `/invite`.
+pub(crate) struct SlashCommand {
+    pub name: &'static str,
+    /// The arguments, shown after the name in usage hints.
+    pub args: &'static str,
+    pub description: &'static str,
+    /// Parses the arguments, returning `None` when they don't fit `args`.
+    parse: fn(&str) -> Option<Result<Action, String>>,
+}
+
+impl SlashCommand {
+    pub fn usage(&self) -> String {
+        format!("/{} {}", self.name, self.args)
+            .trim_end()
+            .to_owned()
+    }
+}
+
+/// Every command the composer understands, in the order they are suggested.
+/// Adding a command only takes an entry here.
+pub(crate) static COMMANDS: &[SlashCommand] = &[
+    SlashCommand {
+        name: "me",
+        args: "<action>",
+        description: "Describe what you are doing",
+        parse: |args| text(args).map(|body| Ok(Action::Send { body, emote: true })),
+    },
+    SlashCommand {
+        name: "shrug",
+        args: "[message]",
+        description: "Prepend ¯\\_(ツ)_/¯ to a message",
+        parse: |args| {
+            let body = match args.trim() {
+                "" => "¯\\_(ツ)_/¯".to_owned(),
+                message => format!("¯\\_(ツ)_/¯ {message}"),
+            };
+            Some(Ok(Action::Send { body, emote: false }))
+        },
+    },
+    SlashCommand {
+        name: "join",
+        args: "<room address or link>",
+        description: "Join a room",
+        parse: |args| single(args).map(|target| Ok(Action::Join(target.to_owned()))),
+    },
+    SlashCommand {
+        name: "part",
+        args: "",
+        description: "Leave the current room",
+        parse: |args| args.trim().is_empty().then_some(Ok(Action::Leave)),
+    },
+    SlashCommand {
+        name: "invite",
+        args: "<user ID>",
+        description: "Invite a user to the current room",
+        parse: |args| single(args).map(|user_id| user(user_id).map(Action::Invite)),
+    },
+    SlashCommand {
+        name: "nick",
+        args: "<display name>",
+        description: "Change your display name",
+        parse: |args| text(args).map(|name| Ok(Action::SetDisplayName(name))),
+    },
+    SlashCommand {
+        name: "topic",
+        args: "<topic>",
+        description: "Change the topic of the current room",
+        parse: |args| text(args).map(|topic| Ok(Action::SetTopic(topic))),
+    },
+    SlashCommand {
+        name: "msg",
+        args: "<user ID> <message>",
+        description: "Send a direct message to a user",
+        parse: |args| {
+            let (user_id, message) = args.trim().split_once(char::is_whitespace)?;
+            let message = text(message)?;
+            Some(user(user_id).map(|user_id| Action::DirectMessage(user_id, message)))
+        },
+    },
+    SlashCommand {
+        name: "kick",
+        args: "<user ID> [reason]",
+        description: "Remove a member from the current room",
+        parse: |args| moderation(args, ModerationAction::Kick),
+    },
+    SlashCommand {
+        name: "ban",
+        args: "<user ID> [reason]",
+        description: "Ban a member from the current room",
+        parse: |args| moderation(args, ModerationAction::Ban),
+    },
+    SlashCommand {
+        name: "devtools",
+        args: "",
+        description: "Inspect the state of the current room",
+        parse: |args| args.trim().is_empty().then_some(Ok(Action::Devtools)),
+    },
+];
+
+/// Parses `input` if it is a slash command, returning `None` for text to send
+/// as is. Text starting with `//` is sent with the first slash removed.
+pub(crate) fn parse(input: &str) -> Option<Result<Action, String>> {
+    let input = input.strip_prefix('/')?;
+
+    if input.starts_with('/') {
+        return Some(Ok(Action::Send {
+            body: input.to_owned(),
+            emote: false,
+        }));
+    }
+
+    let (name, args) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let Some(command) = COMMANDS.iter().find(|command| command.name == name) else {
+        return Some(Err(format!(
+            "Unknown command /{name}, start the message with // to send it as text"
+        )));
+    };
+
+    Some((command.parse)(args).unwrap_or_else(|| Err(format!("Usage: {}", command.usage()))))
+}
+
+/// The commands whose name starts with what was typed after the slash, while
+/// no arguments were typed yet.
+pub(crate) fn suggestions(input: &str) -> Vec<&'static SlashCommand> {
+    match input.strip_prefix('/') {
+        Some(name) if !name.starts_with('/') && !name.contains(char::is_whitespace) => COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(name))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The whole argument string, if not blank.
+fn text(args: &str) -> Option<String> {
+    Some(args.trim().to_owned()).filter(|text| !text.is_empty())
+}
+
+/// A single word.
+fn single(args: &str) -> Option<&str> {
+    let args = args.trim();
+    (!args.is_empty() && !args.contains(char::is_whitespace)).then_some(args)
+}
+
+fn user(user_id: &str) -> Result<OwnedUserId, String> {
+    UserId::parse(user_id).map_err(|_| format!("`{user_id}` is not a valid user ID"))
+}
+
+fn moderation(args: &str, action: ModerationAction) -> Option<Result<Action, String>> {
+    let args = args.trim();
+    let (user_id, reason) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+    if user_id.is_empty() {
+        return None;
+    }
+
+    Some(user(user_id).map(|user_id| Action::Moderate(user_id, action, reason.trim().to_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_id(user_id: &str) -> OwnedUserId {
+        UserId::parse(user_id).unwrap()
+    }
+
+    fn send(body: &str, emote: bool) -> Option<Result<Action, String>> {
+        Some(Ok(Action::Send {
+            body: body.to_owned(),
+            emote,
+        }))
+    }
+
+    fn error(message: &str) -> Option<Result<Action, String>> {
+        Some(Err(message.to_owned()))
+    }
+
+    #[test]
+    fn parses_valid_commands() {
+        let alice = user_id("@alice:example.org");
+        let cases = [
+            ("hello", None),
+            ("//me is not a command", send("/me is not a command", false)),
+            ("/me waves  ", send("waves", true)),
+            ("/shrug", send("¯\\_(ツ)_/¯", false)),
+            ("/shrug oh well", send("¯\\_(ツ)_/¯ oh well", false)),
+            (
+                "/join #room:example.org",
+                Some(Ok(Action::Join("#room:example.org".to_owned()))),
+            ),
+            ("/part", Some(Ok(Action::Leave))),
+            (
+                "/invite @alice:example.org",
+                Some(Ok(Action::Invite(alice.clone()))),
+            ),
+            (
+                "/nick Alice Liddell",
+                Some(Ok(Action::SetDisplayName("Alice Liddell".to_owned()))),
+            ),
+            (
+                "/topic All about tea",
+                Some(Ok(Action::SetTopic("All about tea".to_owned()))),
+            ),
+            (
+                "/msg @alice:example.org  hi there",
+                Some(Ok(Action::DirectMessage(
+                    alice.clone(),
+                    "hi there".to_owned(),
+                ))),
+            ),
+            (
+                "/kick @alice:example.org",
+                Some(Ok(Action::Moderate(
+                    alice.clone(),
+                    ModerationAction::Kick,
+                    String::new(),
+                ))),
+            ),
+            (
+                "/ban @alice:example.org too much spam",
+                Some(Ok(Action::Moderate(
+                    alice,
+                    ModerationAction::Ban,
+                    "too much spam".to_owned(),
+                ))),
+            ),
+            ("/devtools", Some(Ok(Action::Devtools))),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(parse(input), expected, "parsing `{input}`");
+        }
+    }
+
+    #[test]
+    fn rejects_missing_arguments() {
+        let cases = [
+            ("/me", "Usage: /me <action>"),
+            ("/me   ", "Usage: /me <action>"),
+            ("/join", "Usage: /join <room address or link>"),
+            ("/invite", "Usage: /invite <user ID>"),
+            ("/nick", "Usage: /nick <display name>"),
+            ("/topic ", "Usage: /topic <topic>"),
+            ("/msg", "Usage: /msg <user ID> <message>"),
+            ("/msg @alice:example.org", "Usage: /msg <user ID> <message>"),
+            (
+                "/msg @alice:example.org  ",
+                "Usage: /msg <user ID> <message>",
+            ),
+            ("/kick", "Usage: /kick <user ID> [reason]"),
+            ("/ban  ", "Usage: /ban <user ID> [reason]"),
+        ];
+
+        for (input, message) in cases {
+            assert_eq!(parse(input), error(message), "parsing `{input}`");
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_arguments() {
+        let cases = [
+            (
+                "/join #a:example.org #b:example.org",
+                "Usage: /join <room address or link>",
+            ),
+            ("/part now", "Usage: /part"),
+            (
+                "/invite @alice:example.org @bob:example.org",
+                "Usage: /invite <user ID>",
+            ),
+            ("/devtools please", "Usage: /devtools"),
+        ];
+
+        for (input, message) in cases {
+            assert_eq!(parse(input), error(message), "parsing `{input}`");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_user_ids() {
+        let cases = [
+            ("/invite alice", "`alice` is not a valid user ID"),
+            ("/msg alice hi", "`alice` is not a valid user ID"),
+            ("/kick @alice", "`@alice` is not a valid user ID"),
+            (
+                "/ban alice:example.org spam",
+                "`alice:example.org` is not a valid user ID",
+            ),
+        ];
+
+        for (input, message) in cases {
+            assert_eq!(parse(input), error(message), "parsing `{input}`");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_commands() {
+        assert_eq!(
+            parse("/dance now"),
+            error("Unknown command /dance, start the message with // to send it as text")
+        );
+    }
+
+    #[test]
+    fn suggests_commands_until_arguments_are_typed() {
+        let names = |input| {
+            suggestions(input)
+                .into_iter()
+                .map(|command| command.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("/m"), ["me", "msg"]);
+        assert_eq!(
+            names("/"),
+            COMMANDS.iter().map(|c| c.name).collect::<Vec<_>>()
+        );
+        assert!(names("/me ").is_empty());
+        assert!(names("//m").is_empty());
+        assert!(names("me").is_empty());
+    }
+}
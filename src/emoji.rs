@@ -0,0 +1,66 @@
+use emojis::{Emoji, Group};
+
+/// How many of the emoji we used last the picker remembers.
+pub(crate) const RECENT_EMOJI: usize = 32;
+
+/// How many emoji the `:shortcode:` autocomplete suggests at most.
+pub(crate) const SHORTCODE_SUGGESTIONS: usize = 8;
+
+/// The categories of the picker, in order, with the emoji on their tab.
+pub(crate) static GROUPS: &[(Group, &str)] = &[
+    (Group::SmileysAndEmotion, "😀"),
+    (Group::PeopleAndBody, "👋"),
+    (Group::AnimalsAndNature, "🐻"),
+    (Group::FoodAndDrink, "🍔"),
+    (Group::TravelAndPlaces, "🚗"),
+    (Group::Activities, "⚽"),
+    (Group::Objects, "💡"),
+    (Group::Symbols, "🔣"),
+    (Group::Flags, "🏁"),
+];
+
+pub(crate) fn group_name(group: Group) -> &'static str {
+    match group {
+        Group::SmileysAndEmotion => "Smileys & Emotion",
+        Group::PeopleAndBody => "People & Body",
+        Group::AnimalsAndNature => "Animals & Nature",
+        Group::FoodAndDrink => "Food & Drink",
+        Group::TravelAndPlaces => "Travel & Places",
+        Group::Activities => "Activities",
+        Group::Objects => "Objects",
+        Group::Symbols => "Symbols",
+        Group::Flags => "Flags",
+    }
+}
+
+/// The emoji with a shortcode containing `query`, those with a shortcode
+/// starting with it first.
+pub(crate) fn search(query: &str) -> Vec<&'static Emoji> {
+    let query = query.to_lowercase();
+    let (mut prefixed, contained): (Vec<_>, Vec<_>) = emojis::iter()
+        .filter(|emoji| emoji.shortcodes().any(|code| code.contains(&query)))
+        .partition(|emoji| emoji.shortcodes().any(|code| code.starts_with(&query)));
+
+    prefixed.extend(contained);
+    prefixed
+}
+
+/// The shortcode being typed at the end of `input`, without its colon, once
+/// it is long enough to be told from a time or a smiley like `:)`.
+pub(crate) fn shortcode_query(input: &str) -> Option<&str> {
+    let word = input.rsplit(char::is_whitespace).next()?;
+    let query = word.strip_prefix(':')?;
+
+    (query.len() >= 2
+        && query
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+')))
+    .then_some(query)
+}
+
+/// Moves `emoji` to the front of the recently used ones.
+pub(crate) fn remember(recent: &mut Vec<String>, emoji: &str) {
+    recent.retain(|used| used != emoji);
+    recent.insert(0, emoji.to_owned());
+    recent.truncate(RECENT_EMOJI);
+}
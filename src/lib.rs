@@ -1,74 +1,618 @@
-use iced::{advanced::Hasher, widget::scrollable::Properties};
-use matrix::Credentials;
-use matrix_sdk::ruma::OwnedRoomId;
-use std::{hash::Hash, str::FromStr, sync::Mutex};
+use commands::Action;
+use iced::widget::scrollable::Properties;
+use matrix::{
+    ConnectionState, Credentials, DirectoryUser, HistoryVisibility, ImagePack, JoinRule,
+    LoginFlows, Member, MemberList, ModerationAction, NewRoom, PackImage, PendingInvite,
+    PowerLevelSetting, PowerLevelSettings, PublicRoom, PublicRoomsPage, QueuedMessage,
+    Registration, RegistrationProgress, RegistrationStage, Role, RoomPreset, RoomSettings,
+    SendError, SessionInvalidated, StageResponse, StateEvent,
+};
+use matrix_sdk::{
+    reqwest::Url,
+    ruma::{
+        events::{room::message::RoomMessageEventContent, Mentions},
+        presence::PresenceState,
+        OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedServerName, OwnedTransactionId, OwnedUserId,
+        ServerName, TransactionId,
+    },
+    Room, RoomState,
+};
+use std::{path::PathBuf, str::FromStr};
+mod commands;
+mod emoji;
+mod loopback;
 mod matrix;
 mod style;
 
-use chrono::{DateTime, Local};
-use clap::Parser;
+use chrono::{DateTime, Days, Local, NaiveDate};
+use clap::{Parser, ValueEnum};
 use iced::{
     alignment::Vertical,
-    color, executor,
+    color, event, executor, font,
+    futures::{
+        channel::mpsc,
+        future::{self, AbortHandle},
+        SinkExt,
+    },
+    keyboard,
     theme::{self, Custom},
-    widget::{column, row, scrollable, svg, Button, Container, Scrollable, Text, TextInput},
-    Application, Color, Command, Length, Padding, Theme,
+    widget::{
+        column, image, row, scrollable, svg, text_editor, tooltip, Button, Checkbox, Container,
+        PickList, Scrollable, Space, Text, TextEditor, TextInput,
+    },
+    Application, Color, Command, Font, Length, Padding, Theme,
 };
 use log::{info, warn};
 use once_cell::sync::Lazy;
 use std::{
+    collections::{HashMap, HashSet},
     env,
-    sync::{
-        mpsc::{Receiver, Sender},
-        Arc,
-    },
+    future::Future,
+    sync::Arc,
 };
 
 #[derive(Default)]
 struct Flags {
+    username: Option<String>,
+    password: Option<String>,
+    clock: Clock,
+}
+
+/// How times of day are shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Clock {
+    /// 13:45
+    #[default]
+    #[value(name = "24h")]
+    TwentyFourHour,
+    /// 1:45 PM
+    #[value(name = "12h")]
+    TwelveHour,
+}
+
+impl Clock {
+    fn time_format(self) -> &'static str {
+        match self {
+            Self::TwentyFourHour => "%H:%M",
+            Self::TwelveHour => "%-I:%M %p",
+        }
+    }
+}
+
+#[derive(Default)]
+struct LoginForm {
+    username: String,
+    password: String,
+    /// Login flows offered by the homeserver, known once the username is entered.
+    flows: Option<LoginFlows>,
+    /// Page opened in the browser for a login that completes there.
+    browser_url: Option<Url>,
+    /// Cancels the login completed in the browser while it is waiting.
+    browser_login: Option<AbortHandle>,
+    error: Option<String>,
+    pending: bool,
+}
+
+#[derive(Default)]
+struct RegisterForm {
     username: String,
     password: String,
+    email: String,
+    token: String,
+    /// The registration in progress, once the homeserver asked for a stage.
+    registration: Option<Registration>,
+    stage: Option<RegistrationStage>,
+    /// Whether the validation link for the email stage was sent.
+    email_sent: bool,
+    error: Option<String>,
+    pending: bool,
+}
+
+struct NewRoomForm {
+    room: NewRoom,
+    error: Option<String>,
+    pending: bool,
+}
+
+impl Default for NewRoomForm {
+    fn default() -> Self {
+        Self {
+            room: NewRoom {
+                encrypted: true,
+                ..Default::default()
+            },
+            error: None,
+            pending: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct NewDmForm {
+    query: String,
+    /// Users found for `query`, `None` until a search completed.
+    results: Option<Vec<DirectoryUser>>,
+    searching: bool,
+    error: Option<String>,
+    pending: bool,
+}
+
+#[derive(Default)]
+struct JoinRoomForm {
+    /// Alias, room ID or link of the room to join.
+    target: String,
+    /// Server whose room directory is browsed, our homeserver if empty.
+    server: String,
+    search: String,
+    rooms: Vec<PublicRoom>,
+    next_batch: Option<String>,
+    loading: bool,
+    /// Whether a join is in progress.
+    pending: bool,
+    error: Option<String>,
+}
+
+/// An action on a room waiting for the user to confirm it.
+#[derive(Clone, Debug)]
+enum Confirmation {
+    Leave(OwnedRoomId),
+    Forget(OwnedRoomId),
+}
+
+struct PowerLevelsForm {
+    room_id: OwnedRoomId,
+    /// Levels as typed by the user, `None` while they are loading.
+    settings: Option<Vec<(PowerLevelSetting, String)>>,
+    events: Vec<(String, String)>,
+    /// Whether we are allowed to change the power levels.
+    editable: bool,
+    error: Option<String>,
+    pending: bool,
+}
+
+struct RoomSettingsForm {
+    room_id: OwnedRoomId,
+    /// Settings as loaded, to only send those that changed. `None` while they
+    /// are loading.
+    original: Option<RoomSettings>,
+    settings: RoomSettings,
+    /// Path of an image to upload as the new avatar.
+    avatar_path: String,
+    error: Option<String>,
+    pending: bool,
+}
+
+struct DevtoolsForm {
+    room_id: OwnedRoomId,
+    /// The state of the room, `None` while it is loading.
+    state: Option<Vec<StateEvent>>,
+    /// Index of the state event whose JSON is shown.
+    selected: Option<usize>,
+    error: Option<String>,
+}
+
+/// Adds images to our personal image pack, or removes them.
+#[derive(Default)]
+struct ImagePackForm {
+    shortcode: String,
+    path: String,
+    emoticon: bool,
+    sticker: bool,
+    pending: bool,
+    error: Option<String>,
+}
+
+/// A dialog shown in place of the current room.
+enum Dialog {
+    NewRoom(NewRoomForm),
+    NewDm(NewDmForm),
+    JoinRoom(JoinRoomForm),
+    PowerLevels(PowerLevelsForm),
+    RoomSettings(Box<RoomSettingsForm>),
+    Devtools(DevtoolsForm),
+    ImagePack(ImagePackForm),
 }
 
 #[derive(Clone, Debug)]
 struct Message {
+    room_id: OwnedRoomId,
+    /// `None` for messages sent from this client until the server echoes them.
+    event_id: Option<OwnedEventId>,
+    sender_id: OwnedUserId,
+    /// Display name of the sender in the room, followed by their user ID when
+    /// another member goes by the same name.
     sender: String,
+    sender_avatar: Option<OwnedMxcUri>,
+    kind: MessageKind,
     contents: String,
     timestamp: DateTime<Local>,
+    /// Custom emoji in `contents`, by the text they stand for, e.g.
+    /// `:blobcat:`.
+    emotes: Vec<(String, OwnedMxcUri)>,
+    /// The image of a sticker.
+    sticker: Option<OwnedMxcUri>,
+    /// Local echo details, only set for messages sent from this client.
+    outgoing: Option<Outgoing>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum MessageKind {
+    #[default]
+    Text,
+    /// Sent by bots, shown less prominently.
+    Notice,
+    /// An action, shown as "* alice waves".
+    Emote,
+    /// An image from an image pack, described by `contents`.
+    Sticker,
+    /// A member joining, leaving or changing their name, described by the
+    /// contents.
+    Membership,
+    /// A change of the room name or topic, described by the contents.
+    RoomChange,
+}
+
+/// An entry of the timeline of a room.
+#[derive(Debug)]
+enum TimelineItem<'a> {
+    /// The separator above the messages of a day.
+    Day(NaiveDate),
+    /// A message, under a header with its sender if first of a group.
+    Message(&'a Message, bool),
+    /// A run of room changes, collapsed into a summary when long enough.
+    Changes(Vec<&'a Message>),
+}
+
+impl MessageKind {
+    /// Whether the message is a compact line describing a change of the room.
+    fn is_state(self) -> bool {
+        matches!(self, Self::Membership | Self::RoomChange)
+    }
+}
+
+/// A reaction to a message, e.g. 👍.
+#[derive(Clone, Debug)]
+struct Reaction {
+    /// The message reacted to.
+    event_id: OwnedEventId,
+    key: String,
+    sender_id: OwnedUserId,
+}
+
+/// Where an emoji picked in the emoji picker goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum EmojiTarget {
+    Composer,
+    /// A reaction to a message of a room.
+    Reaction(OwnedRoomId, OwnedEventId),
+}
+
+/// A tab of the emoji picker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EmojiTab {
+    Recent,
+    Group(emojis::Group),
+    /// The custom emoji of an image pack, by its index.
+    Pack(usize),
+}
+
+/// An emoji shown in the emoji picker.
+enum PickerItem<'a> {
+    Emoji(&'a str),
+    Custom(&'a PackImage),
+}
+
+struct EmojiPicker {
+    target: EmojiTarget,
+    tab: EmojiTab,
+    /// Shortcode searched for, shown instead of the category when not empty.
+    search: String,
+}
+
+/// A user or room picked from the mention autocomplete, linked in the message
+/// where it was inserted.
+#[derive(Clone, Debug)]
+struct Pill {
+    label: String,
+    /// matrix.to link of the user or room.
+    link: String,
+    /// The mentioned user, `None` for rooms.
+    user_id: Option<OwnedUserId>,
+}
+
+#[derive(Clone, Debug)]
+struct Outgoing {
+    txn_id: OwnedTransactionId,
+    state: SendState,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SendState {
+    Sending,
+    Sent,
+    Failed(SendError),
 }
 
 #[derive(Default)]
 struct Client {
     username: String,
+    clock: Clock,
+    composer: text_editor::Content,
+    /// The text of `composer`, kept in sync with it.
     compose_value: String,
+    /// Unsent text of the composer of the rooms other than the current one,
+    /// by room ID.
+    drafts: HashMap<String, String>,
+    /// Bumped on every edit, so only the last of a burst of edits saves the
+    /// drafts.
+    draft_revision: u64,
+    /// The emoji picker, shown above the composer while set.
+    emoji_picker: Option<EmojiPicker>,
+    /// Emoji we used last, most recent first.
+    recent_emoji: Vec<String>,
+    /// The image packs usable in the current room, our personal pack first.
+    image_packs: Vec<ImagePack>,
+    /// The sticker picker, showing the stickers of the image pack at an
+    /// index, shown above the composer while set.
+    sticker_picker: Option<usize>,
+    /// Downloaded custom emoji and stickers, `None` while downloading.
+    media: HashMap<OwnedMxcUri, Option<image::Handle>>,
+    /// Reactions to each message, by key in the order they were first seen,
+    /// with who reacted.
+    reactions: HashMap<OwnedEventId, Vec<(String, Vec<OwnedUserId>)>>,
+    /// Tells Shift+Enter, which adds a line, from Enter, which sends.
+    modifiers: keyboard::Modifiers,
+    /// Why the slash command in the composer could not be run.
+    command_error: Option<String>,
+    /// Users and rooms picked from the autocomplete for the message in the
+    /// composer, by where their label starts in it.
+    pills: Vec<(usize, Pill)>,
     messages: Vec<Message>,
     client: Option<matrix_sdk::Client>,
     sync_token: Option<String>,
-    command_sender: Option<Sender<ClientMessage>>,
-    command_receiver: Option<Arc<Mutex<Receiver<ClientMessage>>>>,
+    /// Counts the logins, each of which starts its own sync loop.
+    login_count: u64,
     roomid: String,
+    outbox: Vec<QueuedMessage>,
+    connection: ConnectionState,
+    login: LoginForm,
+    /// The registration form, shown instead of the login form while set.
+    register: Option<RegisterForm>,
+    dialog: Option<Dialog>,
+    invites: Vec<PendingInvite>,
+    /// Room of the invite being accepted or declined.
+    invite_pending: Option<OwnedRoomId>,
+    invite_error: Option<String>,
+    confirmation: Option<Confirmation>,
+    /// Error of the last leave, forget or reaction, shown in the room header.
+    room_error: Option<String>,
+    /// Whether the rooms we left are listed below the others.
+    show_archive: bool,
+    /// Whether the member list of the current room is shown.
+    show_members: bool,
+    /// Members of the current room, `None` while they are loading.
+    members: Option<MemberList>,
+    members_error: Option<String>,
+    /// Member whose info card is open.
+    selected_member: Option<OwnedUserId>,
+    /// Reason given with a kick, ban or unban.
+    moderation_reason: String,
+    moderation_pending: bool,
+    moderation_error: Option<String>,
+    /// Runs of room changes shown in full, by their first event.
+    expanded_changes: HashSet<OwnedEventId>,
+    /// Avatar of each room with one.
+    room_avatars: HashMap<OwnedRoomId, OwnedMxcUri>,
+    /// Our own name and avatar in each room, for the local echo of messages.
+    own_members: HashMap<OwnedRoomId, (String, Option<OwnedMxcUri>)>,
+    /// Downloaded avatar thumbnails, `None` while downloading.
+    thumbnails: HashMap<OwnedMxcUri, Option<image::Handle>>,
+    session_invalidated: Option<SessionInvalidated>,
 }
 
 #[derive(Debug, Clone)]
 enum ClientMessage {
-    ComposerTyped(String),
+    ComposerEdited(text_editor::Action),
+    /// The composer is replaced with a suggestion.
+    ComposerFilled(String),
+    ModifiersChanged(keyboard::Modifiers),
     MessageSubmitted,
+    CommandFailed(String),
+    MentionChosen(Pill),
+    /// An emoji suggested for the `:shortcode` being typed was picked.
+    ShortcodeChosen(String),
+    EmojiPickerToggled(EmojiTarget),
+    EmojiTabSelected(EmojiTab),
+    EmojiSearchChanged(String),
+    EmojiChosen(String),
+    /// A custom emoji was picked, by its shortcode.
+    CustomEmojiChosen(String),
+    ImagePacksLoaded(OwnedRoomId, Vec<ImagePack>),
+    MediaLoaded(OwnedMxcUri, Vec<u8>),
+    StickerPickerToggled,
+    StickerPackSelected(usize),
+    StickerChosen(PackImage),
+    ImagePackOpened,
+    PackImageShortcodeChanged(String),
+    PackImagePathChanged(String),
+    PackImageEmoticonToggled(bool),
+    PackImageStickerToggled(bool),
+    PackImageSubmitted,
+    PackImageRemoved(String),
+    ImagePackUpdated(ImagePack),
+    ImagePackFailed(String),
+    RecentEmojiLoaded(Vec<String>),
+    NewReactions(Vec<Reaction>),
+    /// We react to a message of a room.
+    Reacted(OwnedRoomId, OwnedEventId, String),
+    /// The pill at an index is turned back into plain text.
+    PillRemoved(usize),
+    /// A DM was found or started to send a message given with `/msg`.
+    DirectMessageStarted(OwnedRoomId, String),
+    DevtoolsOpened,
+    RoomStateLoaded(Vec<StateEvent>),
+    RoomStateFailed(String),
+    StateEventSelected(usize),
+    LoginUsernameChanged(String),
+    LoginPasswordChanged(String),
+    LoginDiscover,
+    LoginFlowsDiscovered(LoginFlows),
+    LoginFlowsReset,
+    LoginSubmitted,
+    OidcLoginRequested,
+    SsoLoginRequested(Option<String>),
+    BrowserLoginOpened(Url),
+    BrowserLoginCancelled,
+    LoginRequired,
+    RegisterOpened,
+    RegisterCancelled,
+    RegisterUsernameChanged(String),
+    RegisterPasswordChanged(String),
+    RegisterEmailChanged(String),
+    RegisterTokenChanged(String),
+    RegisterSubmitted,
+    RegisterEmailRequested,
+    RegisterEmailSent(Box<Registration>),
+    RegisterStageSubmitted(StageResponse),
+    RegisterProgressed(RegistrationProgress),
+    RegisterStageFailed(String),
+    RegisterFailed(String),
+    LinkOpened(String),
     LoggedIn(matrix_sdk::Client, Option<String>),
-    FailedLogin,
-    NewMessage(Message),
+    FailedLogin(String),
+    LogoutRequested,
+    LoggedOut,
+    SessionInvalidated(SessionInvalidated),
+    ConnectionChanged(ConnectionState),
+    SyncTokenChanged(String),
+    NewMessages(Vec<Message>),
+    OutboxLoaded(Vec<QueuedMessage>),
+    DraftsLoaded(HashMap<String, String>),
+    /// Saves the drafts if they were not edited since the given revision.
+    DraftsSaveDue(u64),
+    MessageSent(OwnedTransactionId, OwnedEventId),
+    MessageFailed(OwnedTransactionId, SendError),
+    RetryMessage(OwnedTransactionId),
+    DiscardMessage(OwnedTransactionId),
     RoomChanged(OwnedRoomId),
+    NewRoomOpened,
+    DialogClosed,
+    NewRoomNameChanged(String),
+    NewRoomTopicChanged(String),
+    NewRoomAliasChanged(String),
+    NewRoomInvitesChanged(String),
+    NewRoomPublicToggled(bool),
+    NewRoomEncryptionToggled(bool),
+    NewRoomPresetSelected(RoomPreset),
+    NewRoomSubmitted,
+    RoomCreated(OwnedRoomId),
+    RoomCreationFailed(String),
+    NewDmOpened,
+    NewDmQueryChanged(String),
+    UserSearchSubmitted,
+    UsersFound(String, Vec<DirectoryUser>),
+    UserSearchFailed(String),
+    DmRequested(OwnedUserId),
+    DmFailed(String),
+    JoinRoomOpened,
+    JoinTargetChanged(String),
+    JoinSubmitted,
+    DirectoryServerChanged(String),
+    DirectorySearchChanged(String),
+    DirectorySearched,
+    DirectoryMoreRequested,
+    /// A page of the room directory, `true` if it continues the current list.
+    DirectoryLoaded(PublicRoomsPage, bool),
+    DirectoryFailed(String),
+    PublicRoomJoinRequested(OwnedRoomId),
+    JoinFailed(String),
+    InvitesChanged,
+    ChangesToggled(OwnedEventId),
+    AvatarsChanged(Vec<(OwnedRoomId, Option<OwnedMxcUri>)>),
+    AvatarLoaded(OwnedMxcUri, Vec<u8>),
+    InvitesLoaded(Vec<PendingInvite>),
+    InviteAccepted(OwnedRoomId),
+    /// Declines the invite to a room, ignoring its sender if `true`.
+    InviteDeclined(OwnedRoomId, bool),
+    InviteHandled(Option<OwnedRoomId>),
+    InviteFailed(String),
+    LeaveRequested(OwnedRoomId),
+    ForgetRequested(OwnedRoomId),
+    ConfirmationAccepted,
+    ConfirmationCancelled,
+    RoomLeft(OwnedRoomId),
+    RoomActionFailed(String),
+    ArchiveToggled,
+    MembersToggled,
+    MembersLoaded(OwnedRoomId, MemberList),
+    OwnMemberLoaded(OwnedRoomId, String, Option<OwnedMxcUri>),
+    MembersFailed(OwnedRoomId, String),
+    MemberSelected(Option<OwnedUserId>),
+    ModerationReasonChanged(String),
+    ModerationRequested(OwnedUserId, ModerationAction),
+    MemberPowerLevelRequested(OwnedUserId, i64),
+    ModerationDone,
+    ModerationFailed(String),
+    PowerLevelsOpened,
+    PowerLevelsLoaded(PowerLevelSettings),
+    PowerLevelsFailed(String),
+    PowerLevelChanged(PowerLevelSetting, String),
+    /// The level required for the event type at an index of the editor changed.
+    EventPowerLevelChanged(usize, String),
+    PowerLevelsSubmitted,
+    RoomSettingsOpened,
+    RoomSettingsLoaded(RoomSettings),
+    RoomSettingsFailed(String),
+    RoomSettingsNameChanged(String),
+    RoomSettingsTopicChanged(String),
+    RoomSettingsCanonicalAliasChanged(String),
+    RoomSettingsAltAliasesChanged(String),
+    RoomSettingsJoinRuleSelected(JoinRule),
+    RoomSettingsAllowedRoomsChanged(String),
+    RoomSettingsGuestAccessToggled(bool),
+    RoomSettingsHistoryVisibilitySelected(HistoryVisibility),
+    RoomSettingsEncryptionToggled(bool),
+    RoomSettingsSubmitted,
+    RoomAvatarPathChanged(String),
+    RoomAvatarUploadRequested,
+    RoomAvatarRemoveRequested,
+    RoomAvatarChanged(Option<OwnedMxcUri>),
     None,
 }
 
+/// How many users or rooms the mention autocomplete suggests at most.
+const MENTION_SUGGESTIONS: usize = 8;
+
+/// How many room changes in a row are collapsed into a summary.
+const COLLAPSED_CHANGES: usize = 3;
+
+/// How many emoji the picker shows per row.
+const EMOJI_COLUMNS: usize = 10;
+
+/// How many stickers the sticker picker shows per row.
+const STICKER_COLUMNS: usize = 6;
+
+/// How large stickers are shown in the timeline.
+const STICKER_SIZE: u16 = 128;
+
+/// How tall the composer grows before it scrolls.
+const COMPOSER_MAX_HEIGHT: f32 = 240.0;
+
+/// How long the composer has to be left alone before its draft is saved.
+const DRAFT_SAVE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
 static SCROLLABLE_ID: Lazy<scrollable::Id> = Lazy::new(scrollable::Id::unique);
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
     /// Account username (e.g. `@meow123:matrix.org`)
-    username: String,
+    username: Option<String>,
     /// Account password
-    password: String,
+    password: Option<String>,
+    /// Whether times are shown on a 24-hour or 12-hour clock
+    #[arg(long, value_enum, default_value_t)]
+    clock: Clock,
 }
 
 pub async fn run() -> anyhow::Result<()> {
@@ -79,6 +623,7 @@ pub async fn run() -> anyhow::Result<()> {
         flags: Flags {
             username: cli.username,
             password: cli.password,
+            clock: cli.clock,
         },
         ..Default::default()
     })
@@ -88,189 +633,4798 @@ pub async fn run() -> anyhow::Result<()> {
 impl Client {
     async fn send_message(
         client: matrix_sdk::Client,
-        roomid: String,
-        content: String,
-    ) -> Result<(), matrix_sdk::Error> {
-        let content =
-            matrix_sdk::ruma::events::room::message::RoomMessageEventContent::text_plain(content);
-        client
-            .get_room(&OwnedRoomId::from_str(&roomid).unwrap())
-            .unwrap()
+        queued: QueuedMessage,
+    ) -> Result<OwnedEventId, SendError> {
+        let roomid =
+            OwnedRoomId::from_str(&queued.room_id).map_err(|_| SendError::NoRoomSelected)?;
+        let room = client.get_room(&roomid).ok_or(SendError::RoomLeft)?;
+
+        if room.state() != RoomState::Joined {
+            return Err(SendError::RoomLeft);
+        }
+
+        let content = match (queued.html, queued.emote) {
+            (Some(html), true) => RoomMessageEventContent::emote_html(queued.body, html),
+            (Some(html), false) => RoomMessageEventContent::text_html(queued.body, html),
+            (None, true) => RoomMessageEventContent::emote_plain(queued.body),
+            (None, false) => RoomMessageEventContent::text_plain(queued.body),
+        };
+        // Always set, so clients only highlight the users we meant to mention.
+        let content = content.add_mentions(Mentions::with_user_ids(queued.mentions));
+        let response = room
             .send(content)
+            .with_transaction_id(&queued.txn_id)
             .await?;
-        Ok(())
+        Ok(response.event_id)
     }
-}
 
-impl Application for Client {
-    type Executor = executor::Default;
-    type Message = ClientMessage;
-    type Theme = Theme;
-    type Flags = Flags;
+    /// Sends a queued message in the background, reporting back whether the
+    /// homeserver accepted it.
+    fn send_queued(&self, queued: QueuedMessage) -> Command<ClientMessage> {
+        let Some(client) = self.client.clone() else {
+            return Command::none();
+        };
+
+        Command::perform(
+            async move {
+                let txn_id = queued.txn_id.clone();
+                let result = Client::send_message(client, queued).await;
+                (txn_id, result)
+            },
+            |(txn_id, result)| match result {
+                Ok(event_id) => ClientMessage::MessageSent(txn_id, event_id),
+                Err(err) => {
+                    warn!("Failed to send message {} with error {}", txn_id, err);
+                    ClientMessage::MessageFailed(txn_id, err)
+                }
+            },
+        )
+    }
+
+    fn log_in(credentials: Option<Credentials>) -> Command<ClientMessage> {
+        Command::perform(matrix::run(credentials), |res| match res {
+            Ok(Some((client, token))) => {
+                info!("Logged in as {}", client.user_id().unwrap());
+                ClientMessage::LoggedIn(client, token)
+            }
+            Ok(None) => ClientMessage::LoginRequired,
+            Err(err) => {
+                warn!("Failed to login with error {}", err);
+                ClientMessage::FailedLogin(err.to_string())
+            }
+        })
+    }
+
+    /// Runs a login that is completed in the browser, letting the login report
+    /// which page it opened on the way. It can be cancelled until it is done,
+    /// which also stops listening for the browser.
+    fn browser_login<F, Fut>(&mut self, login: F) -> Command<ClientMessage>
+    where
+        F: FnOnce(mpsc::Sender<ClientMessage>) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<matrix_sdk::Client>> + Send + 'static,
+    {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        self.login.browser_login = Some(abort_handle);
+
+        iced::command::channel(1, |mut sender| async move {
+            let message =
+                match future::Abortable::new(login(sender.clone()), abort_registration).await {
+                    Ok(Ok(client)) => ClientMessage::LoggedIn(client, None),
+                    Ok(Err(err)) => {
+                        warn!("Failed to login with error {}", err);
+                        ClientMessage::FailedLogin(err.to_string())
+                    }
+                    Err(future::Aborted) => {
+                        info!("Browser login cancelled");
+                        return;
+                    }
+                };
+            let _ = sender.send(message).await;
+        })
+    }
+
+    /// Drops the current session and returns to the login screen.
+    fn reset_session(&mut self) {
+        let username = std::mem::take(&mut self.username);
+
+        *self = Self {
+            login: LoginForm {
+                username,
+                ..Default::default()
+            },
+            clock: self.clock,
+            login_count: self.login_count,
+            ..Default::default()
+        };
+    }
+
+    /// The login form, also used to sign in again after a soft logout.
+    fn view_login(&self) -> iced::Element<'_, ClientMessage, Theme, iced::Renderer> {
+        let reauthenticating = self.session_invalidated.is_some();
+        let password_login = reauthenticating
+            || self
+                .login
+                .flows
+                .as_ref()
+                .is_some_and(|flows| flows.password);
+        let input_padding = Padding {
+            top: 12.0,
+            right: 12.0,
+            bottom: 12.0,
+            left: 15.0,
+        };
+
+        let mut username =
+            TextInput::new("Username (e.g. @meow123:matrix.org)", &self.login.username)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(input_padding);
+
+        if !self.login.pending && !reauthenticating && self.login.flows.is_none() {
+            username = username
+                .on_input(ClientMessage::LoginUsernameChanged)
+                .on_submit(ClientMessage::LoginDiscover);
+        }
+
+        let mut form = column![Text::new(env!("CARGO_PKG_NAME")).size(32), username]
+            .spacing(16)
+            .max_width(400);
+
+        if let Some(url) = &self.login.browser_url {
+            form = form.push(
+                column![
+                    Text::new("Continue in your browser. If it did not open, visit:"),
+                    Text::new(url.as_str()).size(12),
+                ]
+                .spacing(4),
+            );
+        }
+
+        if self.login.browser_login.is_some() {
+            form = form.push(
+                Button::new(Text::new("Cancel"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::BrowserLoginCancelled),
+            );
+        } else if password_login {
+            let mut password = TextInput::new("Password", &self.login.password)
+                .secure(true)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(input_padding);
+
+            if !self.login.pending {
+                password = password
+                    .on_input(ClientMessage::LoginPasswordChanged)
+                    .on_submit(ClientMessage::LoginSubmitted);
+            }
+
+            form = form.push(password);
+        }
+
+        if let Some(error) = &self.login.error {
+            form = form.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        let action = |label: String, message: ClientMessage| {
+            Button::new(Text::new(label))
+                .padding(Padding::from([8, 16]))
+                .on_press_maybe((!self.login.pending).then_some(message))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+        };
+
+        let mut actions = row![].spacing(8);
+
+        match &self.login.flows {
+            None if !reauthenticating => {
+                actions = actions.push(action(
+                    if self.login.pending {
+                        "Logging in…".into()
+                    } else {
+                        "Continue".into()
+                    },
+                    ClientMessage::LoginDiscover,
+                ));
+                actions = actions.push(action(
+                    "Create account".into(),
+                    ClientMessage::RegisterOpened,
+                ));
+            }
+            flows => {
+                if password_login {
+                    actions = actions.push(action(
+                        if self.login.pending {
+                            "Logging in…".into()
+                        } else {
+                            "Log in".into()
+                        },
+                        ClientMessage::LoginSubmitted,
+                    ));
+                }
+
+                if let Some(providers) = flows.as_ref().and_then(|flows| flows.sso.as_ref()) {
+                    if providers.is_empty() {
+                        actions = actions.push(action(
+                            "Continue with single sign-on".into(),
+                            ClientMessage::SsoLoginRequested(None),
+                        ));
+                    } else if let [provider] = providers.as_slice() {
+                        actions = actions.push(action(
+                            format!("Continue with {}", provider.name),
+                            ClientMessage::SsoLoginRequested(Some(provider.id.clone())),
+                        ));
+                    } else {
+                        // Several providers don't fit next to the other actions,
+                        // let the user pick one from a list instead.
+                        form = form.push(
+                            column(providers.iter().map(|provider| {
+                                action(
+                                    format!("Continue with {}", provider.name),
+                                    ClientMessage::SsoLoginRequested(Some(provider.id.clone())),
+                                )
+                                .width(Length::Fill)
+                                .into()
+                            }))
+                            .spacing(8),
+                        );
+                    }
+                }
+
+                if let Some(issuer) = flows.as_ref().and_then(|flows| flows.oidc_issuer.as_ref()) {
+                    let provider = Url::parse(issuer)
+                        .ok()
+                        .and_then(|url| url.host_str().map(str::to_owned))
+                        .unwrap_or_else(|| issuer.clone());
+
+                    actions = actions.push(action(
+                        format!("Continue with {provider}"),
+                        ClientMessage::OidcLoginRequested,
+                    ));
+                }
+
+                if !reauthenticating {
+                    actions = actions.push(action("Back".into(), ClientMessage::LoginFlowsReset));
+                }
+            }
+        }
+
+        if reauthenticating {
+            actions = actions.push(
+                Button::new(Text::new("Log out"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::LogoutRequested)
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+            );
+        }
+
+        Container::new(form.push(actions))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .padding(16)
+            .into()
+    }
+
+    /// The account registration form, walking through the stages the
+    /// homeserver asks for one at a time.
+    fn view_register<'a>(
+        &'a self,
+        register: &'a RegisterForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let input_padding = Padding {
+            top: 12.0,
+            right: 12.0,
+            bottom: 12.0,
+            left: 15.0,
+        };
+        let input = |placeholder: &str, value: &str| {
+            TextInput::new(placeholder, value)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(input_padding)
+        };
+        let action = |label: &str, message: ClientMessage| {
+            Button::new(Text::new(label.to_owned()))
+                .padding(Padding::from([8, 16]))
+                .on_press_maybe((!register.pending).then_some(message))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+        };
+
+        let mut form = column![Text::new("Create account").size(32)]
+            .spacing(16)
+            .max_width(400);
+        let mut actions = row![].spacing(8);
+
+        match &register.stage {
+            None => {
+                let mut username = input("Username (e.g. @meow123:matrix.org)", &register.username);
+                let mut password = input("Password", &register.password).secure(true);
+
+                if !register.pending {
+                    username = username
+                        .on_input(ClientMessage::RegisterUsernameChanged)
+                        .on_submit(ClientMessage::RegisterSubmitted);
+                    password = password
+                        .on_input(ClientMessage::RegisterPasswordChanged)
+                        .on_submit(ClientMessage::RegisterSubmitted);
+                }
+
+                form = form.push(username).push(password);
+                actions = actions.push(action(
+                    if register.pending {
+                        "Creating account…"
+                    } else {
+                        "Create account"
+                    },
+                    ClientMessage::RegisterSubmitted,
+                ));
+            }
+            Some(RegistrationStage::Terms(policies)) => {
+                form = form.push(Text::new("Please review the policies of this homeserver:"));
+                form = form.push(
+                    column(policies.iter().map(|policy| {
+                        Button::new(Text::new(policy.name.clone()).size(12))
+                            .on_press(ClientMessage::LinkOpened(policy.url.clone()))
+                            .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                            .into()
+                    }))
+                    .spacing(8),
+                );
+                actions = actions.push(action(
+                    "Accept and continue",
+                    ClientMessage::RegisterStageSubmitted(StageResponse::AcceptTerms),
+                ));
+            }
+            Some(RegistrationStage::EmailIdentity) => {
+                let mut email = input("Email address", &register.email);
+
+                if !register.pending {
+                    email = email
+                        .on_input(ClientMessage::RegisterEmailChanged)
+                        .on_submit(ClientMessage::RegisterEmailRequested);
+                }
+
+                form = form.push(email);
+
+                if register.email_sent {
+                    form = form.push(Text::new(
+                        "Follow the link we sent to your email address, then continue.",
+                    ));
+                    actions = actions
+                        .push(action(
+                            "Continue",
+                            ClientMessage::RegisterStageSubmitted(StageResponse::EmailValidated),
+                        ))
+                        .push(action("Resend", ClientMessage::RegisterEmailRequested));
+                } else {
+                    actions = actions.push(action(
+                        "Send verification email",
+                        ClientMessage::RegisterEmailRequested,
+                    ));
+                }
+            }
+            Some(RegistrationStage::RegistrationToken) => {
+                let mut token = input("Registration token", &register.token);
+                let submit = ClientMessage::RegisterStageSubmitted(
+                    StageResponse::RegistrationToken(register.token.clone()),
+                );
+
+                if !register.pending {
+                    token = token
+                        .on_input(ClientMessage::RegisterTokenChanged)
+                        .on_submit(submit.clone());
+                }
+
+                form = form.push(token);
+                actions = actions.push(action("Continue", submit));
+            }
+        }
+
+        if let Some(error) = &register.error {
+            form = form.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        actions = actions.push(action("Cancel", ClientMessage::RegisterCancelled));
+
+        Container::new(form.push(actions))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .padding(16)
+            .into()
+    }
+
+    /// The dialog to create a new room, shown in place of the current room.
+    fn view_new_room<'a>(
+        &'a self,
+        form: &'a NewRoomForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let input = |placeholder: &str, value: &str, on_input: fn(String) -> ClientMessage| {
+            let input = TextInput::new(placeholder, value)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(Padding {
+                    top: 12.0,
+                    right: 12.0,
+                    bottom: 12.0,
+                    left: 15.0,
+                });
+
+            if form.pending {
+                input
+            } else {
+                input
+                    .on_input(on_input)
+                    .on_submit(ClientMessage::NewRoomSubmitted)
+            }
+        };
+        let action = |label: &str, message: ClientMessage| {
+            Button::new(Text::new(label.to_owned()))
+                .padding(Padding::from([8, 16]))
+                .on_press_maybe((!form.pending).then_some(message))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+        };
+
+        let mut dialog = column![
+            Text::new("New room").size(24),
+            input("Name", &form.room.name, ClientMessage::NewRoomNameChanged),
+            input(
+                "Topic",
+                &form.room.topic,
+                ClientMessage::NewRoomTopicChanged
+            ),
+            input(
+                "Address (e.g. #reochat:matrix.org)",
+                &form.room.alias,
+                ClientMessage::NewRoomAliasChanged
+            ),
+            input(
+                "Invite (e.g. @meow123:matrix.org, @purr:matrix.org)",
+                &form.room.invites,
+                ClientMessage::NewRoomInvitesChanged
+            ),
+            Checkbox::new("Publish in the room directory", form.room.public)
+                .on_toggle_maybe((!form.pending).then_some(ClientMessage::NewRoomPublicToggled)),
+            Checkbox::new("Encrypt messages", form.room.encrypted).on_toggle_maybe(
+                (!form.pending).then_some(ClientMessage::NewRoomEncryptionToggled)
+            ),
+            PickList::new(
+                &RoomPreset::ALL[..],
+                Some(form.room.preset),
+                ClientMessage::NewRoomPresetSelected
+            ),
+        ]
+        .spacing(16)
+        .max_width(400);
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog.push(
+            row![
+                action(
+                    if form.pending {
+                        "Creating…"
+                    } else {
+                        "Create room"
+                    },
+                    ClientMessage::NewRoomSubmitted
+                ),
+                action("Cancel", ClientMessage::DialogClosed),
+            ]
+            .spacing(8),
+        );
+
+        Container::new(dialog)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into()
+    }
+
+    /// The dialog to start a direct message, searching the user directory.
+    fn view_new_dm<'a>(
+        &'a self,
+        form: &'a NewDmForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let busy = form.searching || form.pending;
+
+        let mut query = TextInput::new("Search for a name or user ID", &form.query)
+            .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+            .padding(Padding {
+                top: 12.0,
+                right: 12.0,
+                bottom: 12.0,
+                left: 15.0,
+            });
+
+        if !busy {
+            query = query
+                .on_input(ClientMessage::NewDmQueryChanged)
+                .on_submit(ClientMessage::UserSearchSubmitted);
+        }
+
+        let action = |label: &str, message: ClientMessage| {
+            Button::new(Text::new(label.to_owned()))
+                .padding(Padding::from([8, 16]))
+                .on_press_maybe((!busy).then_some(message))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+        };
+
+        let mut dialog = column![Text::new("New direct message").size(24), query]
+            .spacing(16)
+            .max_width(400);
+
+        match &form.results {
+            Some(users) if users.is_empty() => {
+                dialog = dialog.push(Text::new("No users found").size(12));
+            }
+            Some(users) => {
+                dialog = dialog.push(
+                    Scrollable::new(
+                        column(users.iter().map(|user| {
+                            let label = match &user.display_name {
+                                Some(name) => format!("{name} ({})", user.user_id),
+                                None => user.user_id.to_string(),
+                            };
+
+                            action(&label, ClientMessage::DmRequested(user.user_id.clone()))
+                                .width(Length::Fill)
+                                .into()
+                        }))
+                        .spacing(8),
+                    )
+                    .height(Length::Shrink),
+                );
+            }
+            None => {}
+        }
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog.push(
+            row![
+                action(
+                    if form.searching {
+                        "Searching…"
+                    } else if form.pending {
+                        "Opening…"
+                    } else {
+                        "Search"
+                    },
+                    ClientMessage::UserSearchSubmitted
+                ),
+                action("Cancel", ClientMessage::DialogClosed),
+            ]
+            .spacing(8),
+        );
+
+        Container::new(dialog)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into()
+    }
+
+    /// The dialog to join a room by its address, or from a room directory.
+    fn view_join_room<'a>(
+        &'a self,
+        form: &'a JoinRoomForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let busy = form.loading || form.pending;
+        let input = |placeholder: &str,
+                     value: &str,
+                     on_input: fn(String) -> ClientMessage,
+                     on_submit: ClientMessage| {
+            let input = TextInput::new(placeholder, value)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(Padding {
+                    top: 12.0,
+                    right: 12.0,
+                    bottom: 12.0,
+                    left: 15.0,
+                });
+
+            if busy {
+                input
+            } else {
+                input.on_input(on_input).on_submit(on_submit)
+            }
+        };
+        let action = |label: &str, message: ClientMessage| {
+            Button::new(Text::new(label.to_owned()))
+                .padding(Padding::from([8, 16]))
+                .on_press_maybe((!busy).then_some(message))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+        };
+
+        let joined = |room: &PublicRoom| {
+            self.client
+                .as_ref()
+                .and_then(|client| client.get_room(&room.room_id))
+                .is_some_and(|room| room.state() == RoomState::Joined)
+        };
+
+        let directory = column(form.rooms.iter().map(|room| {
+            let name = room
+                .name
+                .clone()
+                .or_else(|| room.alias.as_ref().map(ToString::to_string))
+                .unwrap_or_else(|| room.room_id.to_string());
+
+            let mut details = column![
+                Text::new(name),
+                Text::new(format!(
+                    "{} member{}",
+                    room.members,
+                    if room.members == 1 { "" } else { "s" }
+                ))
+                .size(12)
+            ]
+            .spacing(4)
+            .width(Length::Fill);
+
+            if let Some(alias) = &room.alias {
+                details = details.push(Text::new(alias.to_string()).size(12));
+            }
+            if let Some(topic) = &room.topic {
+                details = details.push(Text::new(topic).size(12));
+            }
+
+            let join = if joined(room) {
+                action("Open", ClientMessage::RoomChanged(room.room_id.clone()))
+            } else {
+                action(
+                    "Join",
+                    ClientMessage::PublicRoomJoinRequested(room.room_id.clone()),
+                )
+            };
+
+            row![details, join]
+                .align_items(iced::Alignment::Center)
+                .spacing(8)
+                .into()
+        }))
+        .spacing(16)
+        .padding(Padding::from([0, 20, 0, 0]))
+        .push_maybe(
+            form.next_batch
+                .is_some()
+                .then(|| action("Load more", ClientMessage::DirectoryMoreRequested)),
+        );
+
+        let mut dialog = column![
+            Text::new("Join a room").size(24),
+            row![
+                input(
+                    "Address or link (e.g. #reochat:matrix.org)",
+                    &form.target,
+                    ClientMessage::JoinTargetChanged,
+                    ClientMessage::JoinSubmitted
+                ),
+                action(
+                    if form.pending { "Joining…" } else { "Join" },
+                    ClientMessage::JoinSubmitted
+                ),
+            ]
+            .align_items(iced::Alignment::Center)
+            .spacing(8),
+            Text::new("Room directory").size(18),
+            row![
+                input(
+                    "Server (your homeserver if empty)",
+                    &form.server,
+                    ClientMessage::DirectoryServerChanged,
+                    ClientMessage::DirectorySearched
+                ),
+                input(
+                    "Search",
+                    &form.search,
+                    ClientMessage::DirectorySearchChanged,
+                    ClientMessage::DirectorySearched
+                ),
+                action(
+                    if form.loading { "Loading…" } else { "Search" },
+                    ClientMessage::DirectorySearched
+                ),
+            ]
+            .align_items(iced::Alignment::Center)
+            .spacing(8),
+        ]
+        .spacing(16)
+        .max_width(600);
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog
+            .push(Scrollable::new(directory).height(Length::Fill))
+            .push(
+                Button::new(Text::new("Close"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::DialogClosed)
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+            );
+
+        Container::new(dialog)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .into()
+    }
+
+    /// The invites waiting for an answer, listed above the rooms.
+    fn view_invites(&self) -> Option<iced::Element<'_, ClientMessage, Theme, iced::Renderer>> {
+        if self.invites.is_empty() {
+            return None;
+        }
+
+        let action = |label: &str, message: ClientMessage| {
+            Button::new(Text::new(label.to_owned()).size(12))
+                .on_press_maybe(self.invite_pending.is_none().then_some(message))
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+        };
+
+        let invites = column(self.invites.iter().map(|invite| {
+            let inviter = match (&invite.inviter_name, &invite.inviter) {
+                (Some(name), Some(user_id)) => format!("Invited by {name} ({user_id})"),
+                (None, Some(user_id)) => format!("Invited by {user_id}"),
+                _ => "Invited".to_owned(),
+            };
+
+            let mut preview =
+                column![Text::new(invite.name.clone()), Text::new(inviter).size(12)].spacing(4);
+
+            if let Some(alias) = &invite.alias {
+                preview = preview.push(Text::new(alias.to_string()).size(12));
+            }
+            if let Some(topic) = &invite.topic {
+                preview = preview.push(Text::new(topic).size(12));
+            }
+            if invite.members > 0 {
+                preview = preview.push(
+                    Text::new(format!(
+                        "{} member{}",
+                        invite.members,
+                        if invite.members == 1 { "" } else { "s" }
+                    ))
+                    .size(12),
+                );
+            }
+
+            let mut actions = row![
+                action(
+                    "Accept",
+                    ClientMessage::InviteAccepted(invite.room_id.clone())
+                ),
+                action(
+                    "Decline",
+                    ClientMessage::InviteDeclined(invite.room_id.clone(), false)
+                ),
+            ]
+            .spacing(8);
+
+            if invite.inviter.is_some() {
+                actions = actions.push(action(
+                    "Decline and ignore",
+                    ClientMessage::InviteDeclined(invite.room_id.clone(), true),
+                ));
+            }
+
+            preview.push(actions).into()
+        }))
+        .spacing(16);
+
+        Some(
+            column![Text::new("Invites").size(18), invites]
+                .push_maybe(
+                    self.invite_error
+                        .as_ref()
+                        .map(|error| Text::new(error).size(12).style(color!(0xff6b6b))),
+                )
+                .push(Text::new("Rooms").size(18))
+                .spacing(16)
+                .into(),
+        )
+    }
+
+    /// The toggle for the rooms we left, and the rooms themselves when shown.
+    fn view_archive(&self) -> Option<iced::Element<'_, ClientMessage, Theme, iced::Renderer>> {
+        let left_rooms = self.client.as_ref()?.left_rooms();
+
+        if left_rooms.is_empty() {
+            return None;
+        }
+
+        let toggle = Button::new(
+            Text::new(format!(
+                "{} archived rooms ({})",
+                if self.show_archive { "Hide" } else { "Show" },
+                left_rooms.len()
+            ))
+            .size(12),
+        )
+        .on_press(ClientMessage::ArchiveToggled)
+        .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)));
+
+        let rooms = self.show_archive.then(|| {
+            column(left_rooms.into_iter().map(|room| {
+                Button::new(Text::new(
+                    room.name().unwrap_or_else(|| room.room_id().to_string()),
+                ))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+                .on_press(ClientMessage::RoomChanged(room.room_id().into()))
+                .into()
+            }))
+            .spacing(16)
+        });
+
+        Some(column![toggle].push_maybe(rooms).spacing(16).into())
+    }
+
+    /// Loads the members of the current room for the member list.
+    fn load_members(&mut self) -> Command<ClientMessage> {
+        self.members = None;
+        self.members_error = None;
+
+        let (Some(client), Ok(room_id)) =
+            (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+        else {
+            return Command::none();
+        };
+
+        Command::perform(
+            matrix::room_members(client, room_id.clone()),
+            move |res| match res {
+                Ok(members) => ClientMessage::MembersLoaded(room_id, members),
+                Err(err) => {
+                    warn!("Failed to load members with error {}", err);
+                    ClientMessage::MembersFailed(room_id, err.to_string())
+                }
+            },
+        )
+    }
+
+    /// The member list of the current room, grouped by role, with the info
+    /// card of the selected member on top.
+    fn view_members(&self) -> iced::Element<'_, ClientMessage, Theme, iced::Renderer> {
+        let presence = |member: &Member| {
+            member.presence.as_ref().map(|presence| match presence {
+                PresenceState::Online => Text::new("Online").size(12).style(color!(0x8fd694)),
+                PresenceState::Unavailable => Text::new("Away").size(12).style(color!(0xf6c177)),
+                _ => Text::new("Offline").size(12).style(color!(0x9c9c9c)),
+            })
+        };
+
+        let mut panel = column![row![
+            Text::new("Members").size(18).width(Length::Fill),
+            Button::new(Text::new("Power levels").size(12))
+                .on_press(ClientMessage::PowerLevelsOpened)
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+        ]
+        .align_items(iced::Alignment::Center)]
+        .spacing(16);
+
+        if let Some(error) = &self.members_error {
+            return panel
+                .push(Text::new(error).size(12).style(color!(0xff6b6b)))
+                .width(240)
+                .into();
+        }
+
+        let Some(MemberList {
+            members,
+            permissions,
+        }) = &self.members
+        else {
+            return panel.push(Text::new("Loading…").size(12)).width(240).into();
+        };
+
+        if let Some(member) = self
+            .selected_member
+            .as_ref()
+            .and_then(|user_id| members.iter().find(|member| &member.user_id == user_id))
+        {
+            let mut card = column![
+                Text::new(member.name()),
+                Text::new(member.user_id.to_string()).size(12),
+                Text::new(format!("Power level {}", member.power_level)).size(12),
+            ]
+            .push_maybe(presence(member))
+            .spacing(4);
+
+            if let Some(status) = &member.status_msg {
+                card = card.push(Text::new(status).size(12));
+            }
+            if let Some(ago) = member.last_active_ago {
+                card = card.push(Text::new(format!("Active {}", format_ago(ago))).size(12));
+            }
+
+            let own_user = self.username == member.user_id.as_str();
+            let action = |label: &str, message: ClientMessage| {
+                Button::new(Text::new(label.to_owned()).size(12))
+                    .on_press_maybe((!self.moderation_pending).then_some(message))
+                    .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+            };
+            let mut actions = row![].spacing(8);
+
+            if !own_user {
+                actions = actions.push(action(
+                    "Message",
+                    ClientMessage::DmRequested(member.user_id.clone()),
+                ));
+            }
+
+            actions = actions.push(action("Close", ClientMessage::MemberSelected(None)));
+            card = card.push(actions);
+
+            // Members can only be moderated by someone of a higher level.
+            let moderation: Vec<_> = [
+                (
+                    "Kick",
+                    ModerationAction::Kick,
+                    permissions.kick && !member.banned,
+                ),
+                (
+                    "Ban",
+                    ModerationAction::Ban,
+                    permissions.ban && !member.banned,
+                ),
+                (
+                    "Unban",
+                    ModerationAction::Unban,
+                    permissions.ban && member.banned,
+                ),
+            ]
+            .into_iter()
+            .filter(|(_, _, allowed)| *allowed && !own_user && permissions.outranks(member))
+            .map(|(label, moderation, _)| {
+                action(
+                    label,
+                    ClientMessage::ModerationRequested(member.user_id.clone(), moderation),
+                )
+                .into()
+            })
+            .collect();
+
+            if !moderation.is_empty() {
+                let mut reason = TextInput::new("Reason (optional)", &self.moderation_reason)
+                    .size(12)
+                    .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                    .padding(8);
+
+                if !self.moderation_pending {
+                    reason = reason.on_input(ClientMessage::ModerationReasonChanged);
+                }
+
+                card = card.push(reason).push(row(moderation).spacing(8));
+            }
+
+            // Nobody can hand out a level above their own.
+            if permissions.change_power_levels && (own_user || permissions.outranks(member)) {
+                let levels: Vec<_> = Role::ALL
+                    .into_iter()
+                    .filter(|role| {
+                        role.power_level() <= permissions.power_level
+                            && role.power_level() != member.power_level
+                    })
+                    .map(|role| {
+                        let label = match role {
+                            Role::Admin => "Make admin",
+                            Role::Moderator => "Make moderator",
+                            Role::Member => "Make member",
+                        };
+
+                        action(
+                            label,
+                            ClientMessage::MemberPowerLevelRequested(
+                                member.user_id.clone(),
+                                role.power_level(),
+                            ),
+                        )
+                        .into()
+                    })
+                    .collect();
+
+                card = card.push(row(levels).spacing(8));
+            }
+
+            if let Some(error) = &self.moderation_error {
+                card = card.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+            }
+
+            panel = panel.push(card);
+        }
+
+        let groups = Role::ALL
+            .into_iter()
+            .map(|role| {
+                (
+                    role.to_string(),
+                    members
+                        .iter()
+                        .filter(|member| !member.banned && member.role == role)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .chain(std::iter::once((
+                "Banned".to_owned(),
+                members.iter().filter(|member| member.banned).collect(),
+            )));
+
+        let groups = groups.filter_map(|(title, members)| {
+            if members.is_empty() {
+                return None;
+            }
+
+            let entries = members.into_iter().map(|member| {
+                let mut entry = row![Text::new(member.name()).width(Length::Fill)]
+                    .push_maybe(presence(member))
+                    .spacing(8);
+
+                if member.invited {
+                    entry = entry.push(Text::new("Invited").size(12));
+                }
+
+                Button::new(entry)
+                    .width(Length::Fill)
+                    .on_press(ClientMessage::MemberSelected(Some(member.user_id.clone())))
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+                    .into()
+            });
+
+            Some(
+                column![Text::new(title).size(12)]
+                    .extend(entries)
+                    .spacing(8)
+                    .into(),
+            )
+        });
+
+        panel
+            .push(Scrollable::new(column(groups).spacing(16)).height(Length::Fill))
+            .width(240)
+            .into()
+    }
+
+    /// The editor of the power levels a room requires for each action.
+    fn view_power_levels<'a>(
+        &'a self,
+        form: &'a PowerLevelsForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let editable = form.editable && !form.pending;
+        let level_input =
+            |label: String, value: &'a str, on_input: Box<dyn Fn(String) -> ClientMessage + 'a>| {
+                let mut input = TextInput::new("0", value)
+                    .width(80)
+                    .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                    .padding(8);
+
+                if editable {
+                    input = input
+                        .on_input(on_input)
+                        .on_submit(ClientMessage::PowerLevelsSubmitted);
+                }
+
+                row![Text::new(label).width(Length::Fill), input]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8)
+                    .into()
+            };
+
+        let mut dialog = column![Text::new("Power levels").size(24)]
+            .spacing(16)
+            .max_width(500);
+
+        if !form.editable {
+            dialog = dialog.push(
+                Text::new("You are not allowed to change the power levels of this room").size(12),
+            );
+        }
+
+        match &form.settings {
+            Some(settings) => {
+                let mut levels = column(settings.iter().map(|(setting, value)| {
+                    let setting = *setting;
+                    level_input(
+                        setting.to_string(),
+                        value,
+                        Box::new(move |level| ClientMessage::PowerLevelChanged(setting, level)),
+                    )
+                }))
+                .spacing(8);
+
+                if !form.events.is_empty() {
+                    levels = levels.push(Text::new("Specific events").size(18)).extend(
+                        form.events
+                            .iter()
+                            .enumerate()
+                            .map(|(index, (event_type, value))| {
+                                level_input(
+                                    event_type.clone(),
+                                    value,
+                                    Box::new(move |level| {
+                                        ClientMessage::EventPowerLevelChanged(index, level)
+                                    }),
+                                )
+                            }),
+                    );
+                }
+
+                dialog = dialog.push(Scrollable::new(levels).height(Length::Fill));
+            }
+            None if form.error.is_none() => {
+                dialog = dialog.push(Text::new("Loading…").size(12));
+            }
+            None => {}
+        }
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog.push(
+            row![
+                Button::new(Text::new(if form.pending { "Saving…" } else { "Save" }))
+                    .padding(Padding::from([8, 16]))
+                    .on_press_maybe(
+                        (editable && form.settings.is_some())
+                            .then_some(ClientMessage::PowerLevelsSubmitted)
+                    )
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+                Button::new(Text::new("Close"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::DialogClosed)
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+            ]
+            .spacing(8),
+        );
+
+        Container::new(dialog)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .into()
+    }
+
+    /// The editor of the name, addresses and access rules of a room.
+    fn view_room_settings<'a>(
+        &'a self,
+        form: &'a RoomSettingsForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let Some(original) = &form.original else {
+            let mut dialog = column![Text::new("Room settings").size(24)]
+                .spacing(16)
+                .max_width(500);
+
+            dialog = match &form.error {
+                Some(error) => dialog.push(Text::new(error).size(12).style(color!(0xff6b6b))),
+                None => dialog.push(Text::new("Loading…").size(12)),
+            };
+            dialog = dialog.push(
+                Button::new(Text::new("Close"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::DialogClosed)
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+            );
+
+            return Container::new(dialog)
+                .width(Length::Fill)
+                .height(Length::Fill)
+                .center_x()
+                .into();
+        };
+
+        let settings = &form.settings;
+        let input = |placeholder: &str, value: &str, on_input: fn(String) -> ClientMessage| {
+            let input = TextInput::new(placeholder, value)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(Padding {
+                    top: 12.0,
+                    right: 12.0,
+                    bottom: 12.0,
+                    left: 15.0,
+                });
+
+            if form.pending {
+                input
+            } else {
+                input
+                    .on_input(on_input)
+                    .on_submit(ClientMessage::RoomSettingsSubmitted)
+            }
+        };
+        let action = |label: &str, message: Option<ClientMessage>| {
+            Button::new(Text::new(label.to_owned()))
+                .padding(Padding::from([8, 16]))
+                .on_press_maybe(message.filter(|_| !form.pending))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+        };
+        let labelled =
+            |label: &str, element: iced::Element<'a, ClientMessage, Theme, iced::Renderer>| {
+                row![Text::new(label.to_owned()).width(160), element]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8)
+            };
+
+        let mut avatar_path =
+            TextInput::new("Path of a PNG, JPEG, GIF or WebP image", &form.avatar_path)
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(8)
+                .width(Length::Fill);
+        if !form.pending {
+            avatar_path = avatar_path
+                .on_input(ClientMessage::RoomAvatarPathChanged)
+                .on_submit(ClientMessage::RoomAvatarUploadRequested);
+        }
+
+        let mut access = column![labelled(
+            "Who can join",
+            PickList::new(
+                &JoinRule::ALL[..],
+                Some(settings.join_rule),
+                ClientMessage::RoomSettingsJoinRuleSelected
+            )
+            .into()
+        ),]
+        .spacing(16);
+        if settings.join_rule == JoinRule::Restricted {
+            access = access.push(input(
+                "Rooms whose members can join (e.g. !abc:matrix.org)",
+                &settings.allowed_rooms,
+                ClientMessage::RoomSettingsAllowedRoomsChanged,
+            ));
+        }
+        access = access
+            .push(labelled(
+                "Who can read history",
+                PickList::new(
+                    &HistoryVisibility::ALL[..],
+                    Some(settings.history_visibility),
+                    ClientMessage::RoomSettingsHistoryVisibilitySelected,
+                )
+                .into(),
+            ))
+            .push(
+                Checkbox::new("Allow guests to join", settings.guest_access).on_toggle_maybe(
+                    (!form.pending).then_some(ClientMessage::RoomSettingsGuestAccessToggled),
+                ),
+            )
+            .push(
+                Checkbox::new(
+                    if original.encrypted {
+                        "Messages are encrypted"
+                    } else {
+                        "Encrypt messages (can't be turned off again)"
+                    },
+                    settings.encrypted,
+                )
+                .on_toggle_maybe(
+                    (!form.pending && !original.encrypted)
+                        .then_some(ClientMessage::RoomSettingsEncryptionToggled),
+                ),
+            );
+
+        let fields = column![
+            input(
+                "Name",
+                &settings.name,
+                ClientMessage::RoomSettingsNameChanged
+            ),
+            input(
+                "Topic",
+                &settings.topic,
+                ClientMessage::RoomSettingsTopicChanged
+            ),
+            Text::new("Avatar").size(18),
+            Text::new(match &settings.avatar_url {
+                Some(url) => url.to_string(),
+                None => "No avatar".to_owned(),
+            })
+            .size(12),
+            row![
+                avatar_path,
+                action(
+                    "Upload",
+                    (!form.avatar_path.trim().is_empty())
+                        .then_some(ClientMessage::RoomAvatarUploadRequested)
+                ),
+                action(
+                    "Remove",
+                    settings
+                        .avatar_url
+                        .is_some()
+                        .then_some(ClientMessage::RoomAvatarRemoveRequested)
+                ),
+            ]
+            .align_items(iced::Alignment::Center)
+            .spacing(8),
+            Text::new("Addresses").size(18),
+            input(
+                "Main address (e.g. #reochat:matrix.org)",
+                &settings.canonical_alias,
+                ClientMessage::RoomSettingsCanonicalAliasChanged
+            ),
+            input(
+                "Other addresses, separated by commas",
+                &settings.alt_aliases,
+                ClientMessage::RoomSettingsAltAliasesChanged
+            ),
+            Text::new("Access").size(18),
+            access,
+        ]
+        .spacing(16);
+
+        let mut dialog = column![
+            Text::new("Room settings").size(24),
+            Scrollable::new(fields).height(Length::Fill),
+        ]
+        .spacing(16)
+        .max_width(500);
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog.push(
+            row![
+                action(
+                    if form.pending { "Saving…" } else { "Save" },
+                    (form.settings != *original).then_some(ClientMessage::RoomSettingsSubmitted)
+                ),
+                Button::new(Text::new("Close"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::DialogClosed)
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+            ]
+            .spacing(8),
+        );
+
+        Container::new(dialog)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .into()
+    }
+
+    /// The avatar of `room`.
+    fn view_room_avatar<'a>(
+        &self,
+        room: &Room,
+        size: u16,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        self.view_avatar(
+            self.room_avatars.get(room.room_id()),
+            &room_name(room),
+            room.room_id().as_str(),
+            size,
+        )
+    }
+
+    /// The thumbnail of the avatar at `url`, or the initials of `name` on a
+    /// color of its own for `id` until it is downloaded.
+    fn view_avatar<'a>(
+        &self,
+        url: Option<&OwnedMxcUri>,
+        name: &str,
+        id: &str,
+        size: u16,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        if let Some(Some(handle)) = url.and_then(|url| self.thumbnails.get(url)) {
+            return image(handle.clone()).width(size).height(size).into();
+        }
+
+        Container::new(Text::new(initials(name)).size(size / 2))
+            .width(size)
+            .height(size)
+            .center_x()
+            .center_y()
+            .style(theme::Container::Custom(Box::new(style::ContainerAvatar {
+                color: avatar_color(id),
+                size: size.into(),
+            })))
+            .into()
+    }
+
+    /// Downloads the thumbnail of the avatar at `url` unless we already have it.
+    fn load_thumbnail(&mut self, url: OwnedMxcUri) -> Command<ClientMessage> {
+        let Some(client) = self.client.clone() else {
+            return Command::none();
+        };
+        if self.thumbnails.contains_key(&url) {
+            return Command::none();
+        }
+
+        self.thumbnails.insert(url.clone(), None);
+
+        Command::perform(
+            matrix::avatar_thumbnail(client, url.clone()),
+            |res| match res {
+                Ok(data) => ClientMessage::AvatarLoaded(url, data),
+                Err(err) => {
+                    warn!("Failed to download avatar with error {}", err);
+                    ClientMessage::None
+                }
+            },
+        )
+    }
+
+    /// Downloads a custom emoji or sticker, unless it already is.
+    fn load_media(&mut self, url: OwnedMxcUri) -> Command<ClientMessage> {
+        let Some(client) = self.client.clone() else {
+            return Command::none();
+        };
+        if self.media.contains_key(&url) {
+            return Command::none();
+        }
+
+        self.media.insert(url.clone(), None);
+
+        Command::perform(
+            matrix::media_thumbnail(client, url.clone()),
+            |res| match res {
+                Ok(data) => ClientMessage::MediaLoaded(url, data),
+                Err(err) => {
+                    warn!("Failed to download image with error {}", err);
+                    ClientMessage::None
+                }
+            },
+        )
+    }
+
+    /// Looks up our own name and avatar in `room_id` for the local echo, once
+    /// per room.
+    fn load_own_member(&self, room_id: &str) -> Command<ClientMessage> {
+        let (Some(client), Ok(room_id)) = (self.client.clone(), OwnedRoomId::from_str(room_id))
+        else {
+            return Command::none();
+        };
+        if self.own_members.contains_key(&room_id) {
+            return Command::none();
+        }
+
+        Command::perform(
+            matrix::own_member(client, room_id.clone()),
+            move |res| match res {
+                Ok((name, avatar)) => ClientMessage::OwnMemberLoaded(room_id, name, avatar),
+                Err(err) => {
+                    warn!("Failed to look up our own member with error {}", err);
+                    ClientMessage::None
+                }
+            },
+        )
+    }
+
+    /// Loads the image packs usable in the current room.
+    fn load_image_packs(&self) -> Command<ClientMessage> {
+        let (Some(client), Ok(room_id)) =
+            (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+        else {
+            return Command::none();
+        };
+
+        Command::perform(
+            matrix::image_packs(client, room_id.clone()),
+            move |res| match res {
+                Ok(packs) => ClientMessage::ImagePacksLoaded(room_id, packs),
+                Err(err) => {
+                    warn!("Failed to load image packs with error {}", err);
+                    ClientMessage::None
+                }
+            },
+        )
+    }
+
+    /// The custom emoji with a shortcode containing `query`.
+    fn custom_emoji(&self, query: &str) -> Vec<&PackImage> {
+        let query = query.to_lowercase();
+
+        self.image_packs
+            .iter()
+            .flat_map(|pack| &pack.images)
+            .filter(|image| image.emoticon && image.shortcode.to_lowercase().contains(&query))
+            .collect()
+    }
+
+    /// A downloaded custom emoji or sticker, or an empty space of its size
+    /// while it downloads.
+    fn view_media(
+        &self,
+        url: &OwnedMxcUri,
+        size: u16,
+    ) -> iced::Element<'_, ClientMessage, Theme, iced::Renderer> {
+        match self.media.get(url) {
+            Some(Some(handle)) => image(handle.clone()).width(size).height(size).into(),
+            _ => Space::new(size, size).into(),
+        }
+    }
+
+    /// The message shown in the timeline for `queued` until it is sent.
+    fn local_echo(&self, queued: &QueuedMessage) -> Option<Message> {
+        let sender_id = self.client.as_ref()?.user_id()?.to_owned();
+        let room_id = OwnedRoomId::from_str(&queued.room_id).ok()?;
+        let (sender, sender_avatar) = self
+            .own_members
+            .get(&room_id)
+            .cloned()
+            .unwrap_or_else(|| (self.username.clone(), None));
+
+        Some(Message {
+            room_id,
+            event_id: None,
+            sender_id,
+            sender,
+            sender_avatar,
+            kind: if queued.emote {
+                MessageKind::Emote
+            } else {
+                MessageKind::Text
+            },
+            contents: queued.body.clone(),
+            timestamp: Local::now(),
+            emotes: queued
+                .html
+                .as_deref()
+                .map(matrix::html_emotes)
+                .unwrap_or_default(),
+            sticker: None,
+            outgoing: Some(Outgoing {
+                txn_id: queued.txn_id.clone(),
+                state: SendState::Sending,
+            }),
+        })
+    }
+
+    /// The messages of the timeline, with a separator above each day and runs
+    /// of room changes collapsed into a summary.
+    fn view_timeline(&self) -> iced::Element<'_, ClientMessage, Theme, iced::Renderer> {
+        let today = Local::now().date_naive();
+        let mut items = Vec::new();
+
+        for item in timeline_items(&self.messages, &self.roomid) {
+            let changes = match item {
+                TimelineItem::Day(day) => {
+                    items.push(
+                        Container::new(Text::new(day_label(day, today)).size(12))
+                            .width(Length::Fill)
+                            .center_x()
+                            .padding(Padding::from([8, 0]))
+                            .into(),
+                    );
+                    continue;
+                }
+                TimelineItem::Message(msg, first) => {
+                    items.push(self.view_message(msg, first));
+                    continue;
+                }
+                TimelineItem::Changes(changes) => changes,
+            };
+
+            let summary = changes[0]
+                .event_id
+                .clone()
+                .filter(|_| changes.len() >= COLLAPSED_CHANGES);
+            let Some(event_id) = summary else {
+                items.extend(changes.into_iter().map(view_change));
+                continue;
+            };
+
+            let expanded = self.expanded_changes.contains(&event_id);
+            let label = if changes
+                .iter()
+                .all(|msg| msg.kind == MessageKind::Membership)
+            {
+                format!("{} membership changes", changes.len())
+            } else {
+                format!("{} room changes", changes.len())
+            };
+            items.push(
+                Button::new(
+                    Text::new(format!("{label} {}", if expanded { "▾" } else { "▸" })).size(12),
+                )
+                .padding(Padding::from([0, 0, 0, 40]))
+                .on_press(ClientMessage::ChangesToggled(event_id))
+                .style(theme::Button::Custom(Box::new(style::ButtonLink)))
+                .into(),
+            );
+            if expanded {
+                items.extend(changes.into_iter().map(view_change));
+            }
+        }
+
+        column(items)
+            .spacing(8)
+            .padding(Padding::from([0, 20, 0, 0]))
+            .width(Length::Fill)
+            .into()
+    }
+
+    /// A message of the timeline, under a header with the sender if `first`
+    /// of a group.
+    fn view_message<'a>(
+        &'a self,
+        msg: &'a Message,
+        first: bool,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let time_format = self.clock.time_format();
+
+        let mut header = row![].align_items(iced::Alignment::Center).spacing(8);
+        if first {
+            header = header.push(Text::new(&msg.sender)).push(
+                tooltip(
+                    Text::new(msg.timestamp.format(time_format).to_string()).size(12),
+                    Text::new(
+                        msg.timestamp
+                            .format(&format!("%A, %B %-d, %Y {time_format}"))
+                            .to_string(),
+                    )
+                    .size(12),
+                    tooltip::Position::Top,
+                )
+                .style(theme::Container::Box),
+            );
+        }
+        let avatar = if first {
+            self.view_avatar(
+                msg.sender_avatar.as_ref(),
+                &msg.sender,
+                msg.sender_id.as_str(),
+                32,
+            )
+        } else {
+            Space::with_width(32).into()
+        };
+        let show_header = first || msg.outgoing.is_some();
+
+        let mut notice = None;
+
+        if let Some(outgoing) = &msg.outgoing {
+            header = match &outgoing.state {
+                SendState::Sending => header.push(Text::new("Sending…").size(12)),
+                SendState::Sent => header.push(Text::new("Sent").size(12)),
+                SendState::Failed(err) => {
+                    notice = Some(
+                        Text::new(format!("Failed to send: {err}"))
+                            .size(12)
+                            .style(color!(0xff6b6b)),
+                    );
+
+                    header
+                        .push(
+                            Button::new(Text::new("Retry").size(12))
+                                .on_press(ClientMessage::RetryMessage(outgoing.txn_id.clone()))
+                                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                        )
+                        .push(
+                            Button::new(Text::new("Discard").size(12))
+                                .on_press(ClientMessage::DiscardMessage(outgoing.txn_id.clone()))
+                                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                        )
+                }
+            };
+        }
+
+        let contents = self.view_contents(msg);
+
+        let event_id = msg.event_id.as_ref().filter(|_| !msg.kind.is_state());
+        let react = event_id.map(|event_id| {
+            Button::new(
+                image(format!(
+                    "{}/resources/emoji.png",
+                    env!("CARGO_MANIFEST_DIR")
+                ))
+                .width(14)
+                .height(14),
+            )
+            .on_press(ClientMessage::EmojiPickerToggled(EmojiTarget::Reaction(
+                msg.room_id.clone(),
+                event_id.clone(),
+            )))
+            .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+        });
+
+        row![
+            avatar,
+            column![]
+                .push_maybe(show_header.then_some(header))
+                .push(contents)
+                .push_maybe(
+                    event_id.and_then(|event_id| self.view_reactions(&msg.room_id, event_id)),
+                )
+                .push_maybe(notice)
+                .spacing(4)
+                .width(Length::Fill)
+        ]
+        .push_maybe(react)
+        .spacing(8)
+        .into()
+    }
+
+    /// Queues `body` for `room_id`, linking the `pills` it contains, and shows
+    /// it in the timeline until it is sent. Pills are given by how far their
+    /// label starts from the end of the text, ignoring trailing whitespace.
+    fn queue_message(
+        &mut self,
+        room_id: String,
+        body: String,
+        emote: bool,
+        pills: &[(usize, Pill)],
+    ) -> Command<ClientMessage> {
+        let pills = locate_pills(&body, pills);
+
+        let mut spans = pills
+            .iter()
+            .map(|(index, pill)| {
+                (
+                    *index,
+                    pill.label.len(),
+                    format!(
+                        "<a href=\"{}\">{}</a>",
+                        escape_html(&pill.link),
+                        escape_html(&pill.label)
+                    ),
+                )
+            })
+            .collect::<Vec<_>>();
+        for image in self.custom_emoji("") {
+            let code = format!(":{}:", image.shortcode);
+            spans.extend(body.match_indices(&code).map(|(index, _)| {
+                (
+                    index,
+                    code.len(),
+                    format!(
+                        "<img data-mx-emoticon src=\"{}\" alt=\"{alt}\" title=\"{alt}\" height=\"32\" />",
+                        escape_html(image.url.as_str()),
+                        alt = escape_html(&code),
+                    ),
+                )
+            }));
+        }
+
+        let queued = QueuedMessage {
+            txn_id: TransactionId::new(),
+            room_id,
+            html: (!spans.is_empty()).then(|| rich_html(&body, spans)),
+            mentions: pills
+                .iter()
+                .filter_map(|(_, pill)| pill.user_id.clone())
+                .collect(),
+            body,
+            emote,
+        };
+
+        self.messages.extend(self.local_echo(&queued));
+        self.outbox.push(queued.clone());
+
+        Command::batch(vec![
+            scrollable::snap_to(SCROLLABLE_ID.clone(), scrollable::RelativeOffset::END),
+            self.save_outbox(),
+            self.load_own_member(&queued.room_id),
+            self.send_queued(queued),
+        ])
+    }
+
+    /// Runs what a message typed in the composer asks for in the current room.
+    fn run_action(&mut self, action: Action, pills: &[(usize, Pill)]) -> Command<ClientMessage> {
+        let (Some(client), Ok(room_id)) =
+            (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+        else {
+            return Command::none();
+        };
+        let done = |what: &'static str| {
+            move |res: anyhow::Result<()>| match res {
+                Ok(()) => ClientMessage::None,
+                Err(err) => {
+                    warn!("Failed to {} with error {}", what, err);
+                    ClientMessage::CommandFailed(err.to_string())
+                }
+            }
+        };
+
+        match action {
+            Action::Send { body, emote } => {
+                self.queue_message(self.roomid.clone(), body, emote, pills)
+            }
+            Action::Join(target) => Command::perform(
+                matrix::join_room(client, target, Vec::new()),
+                |res| match res {
+                    Ok(room_id) => ClientMessage::RoomChanged(room_id),
+                    Err(err) => {
+                        warn!("Failed to join room with error {}", err);
+                        ClientMessage::CommandFailed(err.to_string())
+                    }
+                },
+            ),
+            Action::Leave => Command::perform(
+                matrix::leave_room(client, room_id.clone(), false),
+                move |res| match res {
+                    Ok(()) => ClientMessage::RoomLeft(room_id),
+                    Err(err) => {
+                        warn!("Failed to leave room with error {}", err);
+                        ClientMessage::CommandFailed(err.to_string())
+                    }
+                },
+            ),
+            Action::Invite(user_id) => Command::perform(
+                matrix::invite_user(client, room_id, user_id),
+                done("invite user"),
+            ),
+            Action::SetDisplayName(name) => Command::perform(
+                matrix::set_display_name(client, name),
+                done("change display name"),
+            ),
+            Action::SetTopic(topic) => Command::perform(
+                matrix::set_topic(client, room_id, topic),
+                done("change topic"),
+            ),
+            Action::DirectMessage(user_id, body) => {
+                Command::perform(matrix::start_dm(client, user_id), |res| match res {
+                    Ok(room_id) => ClientMessage::DirectMessageStarted(room_id, body),
+                    Err(err) => {
+                        warn!("Failed to start direct message with error {}", err);
+                        ClientMessage::CommandFailed(err.to_string())
+                    }
+                })
+            }
+            Action::Moderate(user_id, action, reason) => Command::perform(
+                matrix::moderate(client, room_id, user_id, action, reason),
+                done("moderate member"),
+            ),
+            Action::Devtools => self.update(ClientMessage::DevtoolsOpened),
+        }
+    }
+
+    /// Users or rooms matching the mention being typed.
+    fn mention_suggestions(&self) -> Vec<Pill> {
+        let Some(typed) = mention_query(&self.compose_value) else {
+            return Vec::new();
+        };
+        let query = typed[1..].to_lowercase();
+
+        if typed.starts_with('@') {
+            let Some(members) = &self.members else {
+                return Vec::new();
+            };
+
+            members
+                .members
+                .iter()
+                .filter(|member| !member.banned)
+                .filter(|member| {
+                    member.name().to_lowercase().contains(&query)
+                        || member.user_id.as_str().to_lowercase().contains(&query)
+                })
+                .take(MENTION_SUGGESTIONS)
+                .map(|member| Pill {
+                    label: member.name().to_owned(),
+                    link: member.user_id.matrix_to_uri().to_string(),
+                    user_id: Some(member.user_id.clone()),
+                })
+                .collect()
+        } else {
+            let Some(client) = &self.client else {
+                return Vec::new();
+            };
+
+            client
+                .joined_rooms()
+                .iter()
+                .filter_map(|room| {
+                    let name = room_name(room);
+                    let alias = room.canonical_alias();
+                    let matches = name.to_lowercase().contains(&query)
+                        || alias
+                            .as_ref()
+                            .is_some_and(|alias| alias.as_str().to_lowercase().contains(&query));
+
+                    matches.then(|| match alias {
+                        Some(alias) => Pill {
+                            label: alias.to_string(),
+                            link: alias.matrix_to_uri().to_string(),
+                            user_id: None,
+                        },
+                        None => Pill {
+                            label: name,
+                            link: room.room_id().matrix_to_uri().to_string(),
+                            user_id: None,
+                        },
+                    })
+                })
+                .take(MENTION_SUGGESTIONS)
+                .collect()
+        }
+    }
+
+    /// Suggestions for the mention or slash command being typed, or the usage
+    /// of the command typed, and why it could not be run.
+    fn view_composer_hints(
+        &self,
+    ) -> Option<iced::Element<'_, ClientMessage, Theme, iced::Renderer>> {
+        let mentions = self.mention_suggestions();
+        let shortcode = emoji::shortcode_query(&self.compose_value);
+        let custom = shortcode
+            .map(|query| self.custom_emoji(query))
+            .unwrap_or_default();
+        let shortcodes = shortcode.map(emoji::search).unwrap_or_default();
+        let suggestions = commands::suggestions(&self.compose_value);
+        let mut hints = column![].spacing(4);
+
+        if !mentions.is_empty() {
+            hints = hints.extend(mentions.into_iter().map(|pill| {
+                let link = pill.link.clone();
+                Button::new(
+                    row![
+                        Text::new(pill.label.clone()),
+                        Text::new(link).size(12).style(color!(0xa6adc8)),
+                    ]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8),
+                )
+                .on_press(ClientMessage::MentionChosen(pill))
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                .into()
+            }));
+        } else if let (Some(shortcode), false) =
+            (shortcode, custom.is_empty() && shortcodes.is_empty())
+        {
+            let custom = custom
+                .into_iter()
+                .take(emoji::SHORTCODE_SUGGESTIONS)
+                .collect::<Vec<_>>();
+            let unicode = emoji::SHORTCODE_SUGGESTIONS - custom.len();
+
+            hints = hints.extend(custom.into_iter().map(|image| {
+                Button::new(
+                    row![
+                        self.view_media(&image.url, 20),
+                        Text::new(format!(":{}:", image.shortcode))
+                            .size(12)
+                            .style(color!(0xa6adc8)),
+                    ]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8),
+                )
+                .on_press(ClientMessage::ShortcodeChosen(format!(
+                    ":{}:",
+                    image.shortcode
+                )))
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                .into()
+            }));
+            hints = hints.extend(shortcodes.into_iter().take(unicode).map(|emoji| {
+                let code = emoji
+                    .shortcodes()
+                    .find(|code| code.contains(&shortcode.to_lowercase()))
+                    .unwrap_or_default();
+                Button::new(
+                    row![
+                        Text::new(emoji.as_str()),
+                        Text::new(format!(":{code}:"))
+                            .size(12)
+                            .style(color!(0xa6adc8)),
+                    ]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8),
+                )
+                .on_press(ClientMessage::ShortcodeChosen(emoji.as_str().to_owned()))
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                .into()
+            }));
+        } else if !suggestions.is_empty() {
+            hints = hints.extend(suggestions.into_iter().map(|command| {
+                Button::new(
+                    row![
+                        Text::new(command.usage()),
+                        Text::new(command.description)
+                            .size(12)
+                            .style(color!(0xa6adc8)),
+                    ]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8),
+                )
+                .on_press(ClientMessage::ComposerFilled(format!("/{} ", command.name)))
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                .into()
+            }));
+        } else if let Some(command) = self
+            .compose_value
+            .strip_prefix('/')
+            .and_then(|input| input.split_once(char::is_whitespace))
+            .and_then(|(name, _)| {
+                commands::COMMANDS
+                    .iter()
+                    .find(|command| command.name == name)
+            })
+        {
+            hints = hints.push(
+                Text::new(format!("{} — {}", command.usage(), command.description))
+                    .size(12)
+                    .style(color!(0xa6adc8)),
+            );
+        } else if self.command_error.is_none() {
+            return None;
+        }
+
+        Some(
+            hints
+                .push_maybe(
+                    self.command_error
+                        .as_ref()
+                        .map(|error| Text::new(error).size(12).style(color!(0xff6b6b))),
+                )
+                .into(),
+        )
+    }
+
+    /// The pills of the message in the composer, removed when pressed.
+    fn view_pills(&self) -> Option<iced::Element<'_, ClientMessage, Theme, iced::Renderer>> {
+        if self.pills.is_empty() {
+            return None;
+        }
+
+        Some(
+            row(self.pills.iter().enumerate().map(|(index, (_, pill))| {
+                Button::new(Text::new(format!("{} ×", pill.label)).size(12))
+                    .on_press(ClientMessage::PillRemoved(index))
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+                    .into()
+            }))
+            .spacing(4)
+            .into(),
+        )
+    }
+
+    /// The developer tools of a room, to inspect its state events.
+    fn view_devtools<'a>(
+        &'a self,
+        form: &'a DevtoolsForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let mut dialog = column![
+            Text::new("Developer tools").size(24),
+            Text::new(format!("Room ID: {}", form.room_id)).size(12),
+        ]
+        .spacing(16);
+
+        match &form.state {
+            Some(state) => {
+                let events = column(state.iter().enumerate().map(|(index, event)| {
+                    let label = if event.state_key.is_empty() {
+                        event.event_type.clone()
+                    } else {
+                        format!("{} {}", event.event_type, event.state_key)
+                    };
+
+                    Button::new(Text::new(label).size(12))
+                        .width(Length::Fill)
+                        .on_press(ClientMessage::StateEventSelected(index))
+                        .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                        .into()
+                }))
+                .spacing(4)
+                .padding(Padding::from([0, 12, 0, 0]));
+
+                let json = form
+                    .selected
+                    .and_then(|index| state.get(index))
+                    .map_or("Select a state event to see its contents", |event| {
+                        event.json.as_str()
+                    });
+
+                dialog = dialog.push(
+                    row![
+                        Scrollable::new(events)
+                            .width(Length::FillPortion(2))
+                            .height(Length::Fill),
+                        Scrollable::new(Text::new(json).size(12).font(Font::MONOSPACE))
+                            .width(Length::FillPortion(3))
+                            .height(Length::Fill),
+                    ]
+                    .spacing(16),
+                );
+            }
+            None if form.error.is_none() => {
+                dialog = dialog.push(Text::new("Loading…").size(12));
+            }
+            None => {}
+        }
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog.push(
+            Button::new(Text::new("Close"))
+                .padding(Padding::from([8, 16]))
+                .on_press(ClientMessage::DialogClosed)
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+        );
+
+        Container::new(dialog)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+
+    /// Replaces the text of the composer, with the cursor at its end.
+    fn set_composer(&mut self, text: String) {
+        self.composer = text_editor::Content::with_text(&text);
+        self.composer
+            .perform(text_editor::Action::Move(text_editor::Motion::DocumentEnd));
+        let old = std::mem::replace(&mut self.compose_value, text);
+        self.composer_changed(&old);
+    }
+
+    /// Reads the text of the composer after it was edited.
+    fn read_composer(&mut self) {
+        let text = self
+            .composer
+            .lines()
+            .map(|line| line.to_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let old = std::mem::replace(&mut self.compose_value, text);
+        self.composer_changed(&old);
+    }
+
+    /// Updates what depends on the text of the composer after it changed from
+    /// `old`.
+    fn composer_changed(&mut self, old: &str) {
+        self.command_error = None;
+        move_pills(&mut self.pills, old, &self.compose_value);
+    }
+
+    /// Saves the drafts once the composer was left alone for a moment.
+    fn schedule_drafts_save(&mut self) -> Command<ClientMessage> {
+        self.draft_revision += 1;
+        let revision = self.draft_revision;
+        Command::perform(tokio::time::sleep(DRAFT_SAVE_DELAY), move |_| {
+            ClientMessage::DraftsSaveDue(revision)
+        })
+    }
+
+    /// Writes the drafts, including the one in the composer, to disk.
+    fn save_drafts(&self) -> Command<ClientMessage> {
+        let mut drafts = self.drafts.clone();
+        if !self.roomid.is_empty() && !self.compose_value.trim().is_empty() {
+            drafts.insert(self.roomid.clone(), self.compose_value.clone());
+        }
+
+        Command::perform(matrix::save_drafts(drafts), |res| {
+            if let Err(err) = res {
+                warn!("Failed to persist drafts with error {}", err);
+            }
+            ClientMessage::None
+        })
+    }
+
+    /// Whether we reacted to `event_id` with `key`.
+    fn reacted(&self, event_id: &OwnedEventId, key: &str) -> bool {
+        let Some(user_id) = self.client.as_ref().and_then(|client| client.user_id()) else {
+            return false;
+        };
+
+        self.reactions.get(event_id).is_some_and(|keys| {
+            keys.iter()
+                .any(|(reaction, senders)| reaction == key && senders.iter().any(|s| s == user_id))
+        })
+    }
+
+    /// The emoji picker, with a tab for the recently used emoji, one per
+    /// category and one per image pack, or the emoji matching the shortcode
+    /// searched for.
+    fn view_emoji_picker<'a>(
+        &'a self,
+        picker: &'a EmojiPicker,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        // Reactions with custom emoji aren't supported yet.
+        let custom = picker.target == EmojiTarget::Composer;
+        let tab = |label: iced::Element<'a, ClientMessage, Theme, iced::Renderer>,
+                   name: &'a str,
+                   tab: EmojiTab| {
+            tooltip(
+                Button::new(label)
+                    .on_press(ClientMessage::EmojiTabSelected(tab))
+                    .style(if picker.search.is_empty() && picker.tab == tab {
+                        theme::Button::Custom(Box::new(style::ButtonMessageAction))
+                    } else {
+                        theme::Button::Text
+                    }),
+                Text::new(name).size(12),
+                tooltip::Position::Top,
+            )
+            .style(theme::Container::Box)
+            .into()
+        };
+        let mut tabs = row![tab(
+            Text::new("🕘").size(18).into(),
+            "Recently used",
+            EmojiTab::Recent
+        )]
+        .extend(emoji::GROUPS.iter().map(|&(group, label)| {
+            tab(
+                Text::new(label).size(18).into(),
+                emoji::group_name(group),
+                EmojiTab::Group(group),
+            )
+        }))
+        .align_items(iced::Alignment::Center)
+        .spacing(4);
+        if custom {
+            tabs = tabs.extend(
+                self.image_packs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, pack)| pack.images.iter().any(|image| image.emoticon))
+                    .map(|(index, pack)| {
+                        let icon = pack
+                            .avatar_url
+                            .as_ref()
+                            .or(pack.images.first().map(|image| &image.url));
+                        tab(
+                            match icon {
+                                Some(url) => self.view_media(url, 22),
+                                None => Text::new(initials(&pack.name)).size(14).into(),
+                            },
+                            &pack.name,
+                            EmojiTab::Pack(index),
+                        )
+                    }),
+            );
+        }
+
+        let (title, items): (&str, Vec<PickerItem<'_>>) = if !picker.search.is_empty() {
+            let mut items = Vec::new();
+            if custom {
+                items.extend(
+                    self.custom_emoji(&picker.search)
+                        .into_iter()
+                        .map(PickerItem::Custom),
+                );
+            }
+            items.extend(
+                emoji::search(&picker.search)
+                    .into_iter()
+                    .map(|emoji| PickerItem::Emoji(emoji.as_str())),
+            );
+            ("Results", items)
+        } else {
+            match picker.tab {
+                EmojiTab::Recent => (
+                    "Recently used",
+                    self.recent_emoji
+                        .iter()
+                        .map(|emoji| PickerItem::Emoji(emoji))
+                        .collect(),
+                ),
+                EmojiTab::Group(group) => (
+                    emoji::group_name(group),
+                    group
+                        .emojis()
+                        .map(|emoji| PickerItem::Emoji(emoji.as_str()))
+                        .collect(),
+                ),
+                EmojiTab::Pack(index) => match self.image_packs.get(index) {
+                    Some(pack) => (
+                        pack.name.as_str(),
+                        pack.images
+                            .iter()
+                            .filter(|image| image.emoticon)
+                            .map(PickerItem::Custom)
+                            .collect(),
+                    ),
+                    None => ("", Vec::new()),
+                },
+            }
+        };
+
+        let grid: iced::Element<'_, ClientMessage, Theme, iced::Renderer> = if items.is_empty() {
+            Text::new(if picker.search.is_empty() {
+                "No emoji used yet"
+            } else {
+                "No emoji found"
+            })
+            .size(12)
+            .style(color!(0xa6adc8))
+            .into()
+        } else {
+            column(items.chunks(EMOJI_COLUMNS).map(|chunk| {
+                row(chunk.iter().map(|item| match item {
+                    PickerItem::Emoji(emoji) => Button::new(Text::new(*emoji).size(20))
+                        .on_press(ClientMessage::EmojiChosen((*emoji).to_owned()))
+                        .style(theme::Button::Text)
+                        .into(),
+                    PickerItem::Custom(image) => tooltip(
+                        Button::new(self.view_media(&image.url, 26))
+                            .on_press(ClientMessage::CustomEmojiChosen(image.shortcode.clone()))
+                            .style(theme::Button::Text),
+                        Text::new(format!(":{}:", image.shortcode)).size(12),
+                        tooltip::Position::Top,
+                    )
+                    .style(theme::Container::Box)
+                    .into(),
+                }))
+                .into()
+            }))
+            .into()
+        };
+
+        Container::new(
+            column![
+                row![TextInput::new("Search by shortcode", &picker.search)
+                    .on_input(ClientMessage::EmojiSearchChanged)
+                    .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                    .padding(Padding {
+                        top: 8.0,
+                        right: 12.0,
+                        bottom: 8.0,
+                        left: 12.0,
+                    }),]
+                .push_maybe(custom.then(|| {
+                    Button::new(Text::new("Edit personal pack").size(12))
+                        .on_press(ClientMessage::ImagePackOpened)
+                        .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+                }))
+                .push(
+                    Button::new(Text::new("Close").size(12))
+                        .on_press(ClientMessage::EmojiPickerToggled(picker.target.clone()))
+                        .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                )
+                .align_items(iced::Alignment::Center)
+                .spacing(8),
+                tabs,
+                Text::new(title).size(12).style(color!(0xa6adc8)),
+                Scrollable::new(grid).height(200).width(Length::Fill),
+            ]
+            .spacing(8),
+        )
+        .padding(8)
+        .style(theme::Container::Box)
+        .into()
+    }
+
+    /// The sticker picker, with a tab per image pack with stickers.
+    fn view_sticker_picker(
+        &self,
+        selected: usize,
+    ) -> iced::Element<'_, ClientMessage, Theme, iced::Renderer> {
+        let packs = self
+            .image_packs
+            .iter()
+            .enumerate()
+            .filter(|(_, pack)| pack.images.iter().any(|image| image.sticker))
+            .collect::<Vec<_>>();
+        // The first pack with stickers when the selected one has none.
+        let selected = packs
+            .iter()
+            .find(|(index, _)| *index == selected)
+            .or(packs.first());
+
+        let tabs = row(packs.iter().map(|&(index, pack)| {
+            Button::new(Text::new(&pack.name).size(12))
+                .on_press(ClientMessage::StickerPackSelected(index))
+                .style(
+                    if selected.is_some_and(|(selected, _)| *selected == index) {
+                        theme::Button::Custom(Box::new(style::ButtonMessageAction))
+                    } else {
+                        theme::Button::Text
+                    },
+                )
+                .into()
+        }))
+        .spacing(4);
+
+        let grid: iced::Element<'_, ClientMessage, Theme, iced::Renderer> = match selected {
+            Some((_, pack)) => {
+                let stickers = pack
+                    .images
+                    .iter()
+                    .filter(|image| image.sticker)
+                    .collect::<Vec<_>>();
+
+                column(stickers.chunks(STICKER_COLUMNS).map(|chunk| {
+                    row(chunk.iter().map(|&sticker| {
+                        tooltip(
+                            Button::new(self.view_media(&sticker.url, 64))
+                                .on_press(ClientMessage::StickerChosen(sticker.clone()))
+                                .style(theme::Button::Text),
+                            Text::new(&sticker.body).size(12),
+                            tooltip::Position::Top,
+                        )
+                        .style(theme::Container::Box)
+                        .into()
+                    }))
+                    .into()
+                }))
+                .into()
+            }
+            None => Text::new("No stickers yet, add some to your personal pack")
+                .size(12)
+                .style(color!(0xa6adc8))
+                .into(),
+        };
+
+        Container::new(
+            column![
+                row![
+                    Text::new("Stickers").width(Length::Fill),
+                    Button::new(Text::new("Edit personal pack").size(12))
+                        .on_press(ClientMessage::ImagePackOpened)
+                        .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                    Button::new(Text::new("Close").size(12))
+                        .on_press(ClientMessage::StickerPickerToggled)
+                        .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                ]
+                .align_items(iced::Alignment::Center)
+                .spacing(8),
+                tabs,
+                Scrollable::new(grid).height(240).width(Length::Fill),
+            ]
+            .spacing(8),
+        )
+        .padding(8)
+        .style(theme::Container::Box)
+        .into()
+    }
+
+    /// Our personal image pack, with the images it has and a form to add one.
+    fn view_image_pack<'a>(
+        &'a self,
+        form: &'a ImagePackForm,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let images = self
+            .image_packs
+            .iter()
+            .find(|pack| pack.personal)
+            .map(|pack| pack.images.as_slice())
+            .unwrap_or_default();
+
+        let list: iced::Element<'_, ClientMessage, Theme, iced::Renderer> = if images.is_empty() {
+            Text::new("Your pack has no images yet")
+                .size(12)
+                .style(color!(0xa6adc8))
+                .into()
+        } else {
+            Scrollable::new(
+                column(images.iter().map(|image| {
+                    let usage = match (image.emoticon, image.sticker) {
+                        (true, true) => "Emoji and sticker",
+                        (true, false) => "Emoji",
+                        _ => "Sticker",
+                    };
+
+                    row![
+                        self.view_media(&image.url, 32),
+                        column![
+                            Text::new(format!(":{}:", image.shortcode)),
+                            Text::new(usage).size(12).style(color!(0xa6adc8)),
+                        ]
+                        .width(Length::Fill),
+                        Button::new(Text::new("Remove").size(12))
+                            .on_press_maybe((!form.pending).then(|| {
+                                ClientMessage::PackImageRemoved(image.shortcode.clone())
+                            }),)
+                            .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                    ]
+                    .align_items(iced::Alignment::Center)
+                    .spacing(8)
+                    .into()
+                }))
+                .spacing(8)
+                .padding(Padding::from([0, 12, 0, 0])),
+            )
+            .height(Length::Fill)
+            .into()
+        };
+
+        let mut shortcode = TextInput::new("Shortcode, e.g. blobcat", &form.shortcode)
+            .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+            .padding(8)
+            .width(Length::Fill);
+        let mut path = TextInput::new("Path of a PNG, JPEG, GIF or WebP image", &form.path)
+            .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+            .padding(8)
+            .width(Length::Fill);
+        if !form.pending {
+            shortcode = shortcode.on_input(ClientMessage::PackImageShortcodeChanged);
+            path = path
+                .on_input(ClientMessage::PackImagePathChanged)
+                .on_submit(ClientMessage::PackImageSubmitted);
+        }
+
+        let mut dialog = column![
+            Text::new("Personal image pack").size(24),
+            Text::new("Your custom emoji and stickers, usable in every room").size(12),
+            list,
+            Text::new("Add an image").size(18),
+            shortcode,
+            path,
+            row![
+                Checkbox::new("Emoji", form.emoticon).on_toggle_maybe(
+                    (!form.pending).then_some(ClientMessage::PackImageEmoticonToggled)
+                ),
+                Checkbox::new("Sticker", form.sticker).on_toggle_maybe(
+                    (!form.pending).then_some(ClientMessage::PackImageStickerToggled)
+                ),
+            ]
+            .spacing(16),
+        ]
+        .spacing(16);
+
+        if let Some(error) = &form.error {
+            dialog = dialog.push(Text::new(error).size(12).style(color!(0xff6b6b)));
+        }
+
+        dialog = dialog.push(
+            row![
+                Button::new(Text::new(if form.pending { "Saving…" } else { "Add" }))
+                    .padding(Padding::from([8, 16]))
+                    .on_press_maybe(
+                        (!form.pending
+                            && !form.shortcode.trim().is_empty()
+                            && !form.path.trim().is_empty())
+                        .then_some(ClientMessage::PackImageSubmitted)
+                    ),
+                Button::new(Text::new("Close"))
+                    .padding(Padding::from([8, 16]))
+                    .on_press(ClientMessage::DialogClosed),
+            ]
+            .spacing(8),
+        );
+
+        Container::new(dialog.padding(16).max_width(560))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+
+    /// The contents of a message, with its custom emoji inline.
+    fn view_contents<'a>(
+        &'a self,
+        msg: &'a Message,
+    ) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+        let text = |contents: &'a str| {
+            let text = Text::new(contents);
+            match msg.kind {
+                MessageKind::Emote => text.font(Font {
+                    style: font::Style::Italic,
+                    ..Font::DEFAULT
+                }),
+                MessageKind::Notice => text.style(color!(0xa6adc8)),
+                _ => text,
+            }
+        };
+
+        if let (MessageKind::Sticker, Some(url)) = (msg.kind, &msg.sticker) {
+            return tooltip(
+                self.view_media(url, STICKER_SIZE),
+                Text::new(&msg.contents).size(12),
+                tooltip::Position::Top,
+            )
+            .style(theme::Container::Box)
+            .into();
+        }
+
+        if msg.emotes.is_empty() {
+            return match msg.kind {
+                MessageKind::Emote => Text::new(format!("* {} {}", msg.sender, msg.contents))
+                    .font(Font {
+                        style: font::Style::Italic,
+                        ..Font::DEFAULT
+                    })
+                    .into(),
+                _ => text(&msg.contents).into(),
+            };
+        }
+
+        let mut contents = row![].align_items(iced::Alignment::Center);
+        if msg.kind == MessageKind::Emote {
+            contents = contents
+                .push(text("* "))
+                .push(text(&msg.sender))
+                .push(text(" "));
+        }
+
+        let mut rest = msg.contents.as_str();
+        // Each time, the custom emoji found first in what is left.
+        while let Some((index, alt, url)) = msg
+            .emotes
+            .iter()
+            .filter_map(|(alt, url)| Some((rest.find(alt.as_str())?, alt, url)))
+            .min_by_key(|(index, _, _)| *index)
+        {
+            if index > 0 {
+                contents = contents.push(text(&rest[..index]));
+            }
+            contents = contents.push(self.view_media(url, 24));
+            rest = &rest[index + alt.len()..];
+        }
+        if !rest.is_empty() {
+            contents = contents.push(text(rest));
+        }
+
+        contents.into()
+    }
+
+    /// The reactions to a message, pressed to add the same reaction.
+    fn view_reactions(
+        &self,
+        room_id: &OwnedRoomId,
+        event_id: &OwnedEventId,
+    ) -> Option<iced::Element<'_, ClientMessage, Theme, iced::Renderer>> {
+        let keys = self.reactions.get(event_id)?;
+
+        Some(
+            row(keys.iter().map(|(key, senders)| {
+                Button::new(Text::new(format!("{key} {}", senders.len())).size(12))
+                    .on_press(ClientMessage::Reacted(
+                        room_id.clone(),
+                        event_id.clone(),
+                        key.clone(),
+                    ))
+                    .style(theme::Button::Custom(Box::new(style::ButtonReaction {
+                        ours: self.reacted(event_id, key),
+                    })))
+                    .into()
+            }))
+            .spacing(4)
+            .into(),
+        )
+    }
+
+    /// Writes the recently used emoji to disk.
+    fn save_recent_emoji(&self) -> Command<ClientMessage> {
+        Command::perform(
+            matrix::save_recent_emoji(self.recent_emoji.clone()),
+            |res| {
+                if let Err(err) = res {
+                    warn!("Failed to persist recent emoji with error {}", err);
+                }
+                ClientMessage::None
+            },
+        )
+    }
+
+    /// Writes the current outbox to disk.
+    fn save_outbox(&self) -> Command<ClientMessage> {
+        Command::perform(matrix::save_outbox(self.outbox.clone()), |res| {
+            if let Err(err) = res {
+                warn!("Failed to persist outbox with error {}", err);
+            }
+            ClientMessage::None
+        })
+    }
+
+    fn send_state(&self, txn_id: &TransactionId) -> Option<&SendState> {
+        self.messages
+            .iter()
+            .filter_map(|msg| msg.outgoing.as_ref())
+            .find(|outgoing| outgoing.txn_id == txn_id)
+            .map(|outgoing| &outgoing.state)
+    }
+
+    fn set_send_state(&mut self, txn_id: &TransactionId, state: SendState) {
+        if let Some(outgoing) = self
+            .messages
+            .iter_mut()
+            .filter_map(|msg| msg.outgoing.as_mut())
+            .find(|outgoing| outgoing.txn_id == txn_id)
+        {
+            outgoing.state = state;
+        }
+    }
+}
+
+impl Application for Client {
+    type Executor = executor::Default;
+    type Message = ClientMessage;
+    type Theme = Theme;
+    type Flags = Flags;
+
+    fn new(flags: Self::Flags) -> (Self, iced::Command<Self::Message>) {
+        let client = Self {
+            login: LoginForm {
+                username: flags.username.clone().unwrap_or_default(),
+                pending: true,
+                ..Default::default()
+            },
+            clock: flags.clock,
+            ..Default::default()
+        };
+
+        let credentials = flags
+            .username
+            .zip(flags.password)
+            .map(|(username, password)| Credentials { username, password });
+
+        (client, Client::log_in(credentials))
+    }
+
+    fn title(&self) -> String {
+        env!("CARGO_PKG_NAME").into()
+    }
+
+    fn update(&mut self, message: Self::Message) -> iced::Command<Self::Message> {
+        match message {
+            ClientMessage::ComposerEdited(action) => {
+                if action == text_editor::Action::Edit(text_editor::Edit::Enter)
+                    && !self.modifiers.shift()
+                {
+                    return self.update(ClientMessage::MessageSubmitted);
+                }
+
+                let edit = action.is_edit();
+                self.composer.perform(action);
+                if !edit {
+                    return Command::none();
+                }
+
+                self.read_composer();
+                self.schedule_drafts_save()
+            }
+            ClientMessage::ComposerFilled(text) => {
+                self.set_composer(text);
+                self.schedule_drafts_save()
+            }
+            ClientMessage::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers;
+                Command::none()
+            }
+            ClientMessage::MessageSubmitted => {
+                if self.roomid.is_empty() || self.compose_value.trim().is_empty() {
+                    return Command::none();
+                }
+
+                let action = match commands::parse(&self.compose_value) {
+                    Some(Ok(action)) => action,
+                    Some(Err(err)) => {
+                        self.command_error = Some(err);
+                        return Command::none();
+                    }
+                    None => Action::Send {
+                        body: self.compose_value.clone(),
+                        emote: false,
+                    },
+                };
+
+                let pills = pills_from_end(&self.compose_value, std::mem::take(&mut self.pills));
+                self.set_composer(String::new());
+                Command::batch(vec![self.run_action(action, &pills), self.save_drafts()])
+            }
+            ClientMessage::MentionChosen(pill) => {
+                let typed = mention_query(&self.compose_value).map_or(0, str::len);
+                let mut text = self.compose_value.clone();
+                text.truncate(text.len() - typed);
+                let offset = text.len();
+                text.push_str(&pill.label);
+                text.push(' ');
+                self.set_composer(text);
+                self.pills.push((offset, pill));
+                self.schedule_drafts_save()
+            }
+            ClientMessage::ShortcodeChosen(emoji) => {
+                let typed =
+                    emoji::shortcode_query(&self.compose_value).map_or(0, |query| query.len() + 1);
+                let mut text = self.compose_value.clone();
+                text.truncate(text.len() - typed);
+                text.push_str(&emoji);
+                self.set_composer(text);
+                // Custom emoji, inserted as their `:shortcode:`, aren't
+                // remembered.
+                if emojis::get(&emoji).is_none() {
+                    return self.schedule_drafts_save();
+                }
+                emoji::remember(&mut self.recent_emoji, &emoji);
+                Command::batch(vec![self.schedule_drafts_save(), self.save_recent_emoji()])
+            }
+            ClientMessage::EmojiPickerToggled(target) => {
+                if self
+                    .emoji_picker
+                    .as_ref()
+                    .is_some_and(|picker| picker.target == target)
+                {
+                    self.emoji_picker = None;
+                } else {
+                    self.emoji_picker = Some(EmojiPicker {
+                        target,
+                        tab: if self.recent_emoji.is_empty() {
+                            EmojiTab::Group(emojis::Group::SmileysAndEmotion)
+                        } else {
+                            EmojiTab::Recent
+                        },
+                        search: String::new(),
+                    });
+                    self.sticker_picker = None;
+                    return self.load_image_packs();
+                }
+                Command::none()
+            }
+            ClientMessage::EmojiTabSelected(tab) => {
+                if let Some(picker) = &mut self.emoji_picker {
+                    picker.tab = tab;
+                    picker.search.clear();
+                }
+                Command::none()
+            }
+            ClientMessage::EmojiSearchChanged(search) => {
+                if let Some(picker) = &mut self.emoji_picker {
+                    picker.search = search;
+                }
+                Command::none()
+            }
+            ClientMessage::EmojiChosen(emoji) => {
+                let Some(picker) = self.emoji_picker.take() else {
+                    return Command::none();
+                };
+
+                match picker.target {
+                    EmojiTarget::Composer => {
+                        self.composer
+                            .perform(text_editor::Action::Edit(text_editor::Edit::Paste(
+                                Arc::new(emoji.clone()),
+                            )));
+                        self.read_composer();
+                        emoji::remember(&mut self.recent_emoji, &emoji);
+                        Command::batch(vec![self.schedule_drafts_save(), self.save_recent_emoji()])
+                    }
+                    EmojiTarget::Reaction(room_id, event_id) => {
+                        self.update(ClientMessage::Reacted(room_id, event_id, emoji))
+                    }
+                }
+            }
+            ClientMessage::CustomEmojiChosen(shortcode) => {
+                self.emoji_picker = None;
+                self.composer
+                    .perform(text_editor::Action::Edit(text_editor::Edit::Paste(
+                        Arc::new(format!(":{shortcode}:")),
+                    )));
+                self.read_composer();
+                self.schedule_drafts_save()
+            }
+            ClientMessage::ImagePacksLoaded(room_id, packs) => {
+                if self.roomid != room_id.as_str() {
+                    return Command::none();
+                }
+
+                self.image_packs = packs;
+                if self
+                    .sticker_picker
+                    .is_some_and(|index| index >= self.image_packs.len())
+                {
+                    self.sticker_picker = Some(0);
+                }
+                if let Some(picker) = &mut self.emoji_picker {
+                    if matches!(picker.tab, EmojiTab::Pack(index) if index >= self.image_packs.len())
+                    {
+                        picker.tab = EmojiTab::Recent;
+                    }
+                }
+
+                let urls = self
+                    .image_packs
+                    .iter()
+                    .flat_map(|pack| {
+                        pack.avatar_url
+                            .iter()
+                            .chain(pack.images.iter().map(|image| &image.url))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                Command::batch(urls.into_iter().map(|url| self.load_media(url)))
+            }
+            ClientMessage::MediaLoaded(url, data) => {
+                self.media
+                    .insert(url, Some(image::Handle::from_memory(data)));
+                Command::none()
+            }
+            ClientMessage::StickerPickerToggled => {
+                if self.sticker_picker.take().is_none() {
+                    self.sticker_picker = Some(0);
+                    self.emoji_picker = None;
+                    return self.load_image_packs();
+                }
+                Command::none()
+            }
+            ClientMessage::StickerPackSelected(index) => {
+                self.sticker_picker = Some(index);
+                Command::none()
+            }
+            ClientMessage::StickerChosen(sticker) => {
+                let (Some(client), Ok(room_id)) =
+                    (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+                else {
+                    return Command::none();
+                };
+
+                self.sticker_picker = None;
+                Command::perform(
+                    matrix::send_sticker(client, room_id, sticker),
+                    |res| match res {
+                        Ok(()) => ClientMessage::None,
+                        Err(err) => {
+                            warn!("Failed to send sticker with error {}", err);
+                            ClientMessage::RoomActionFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::ImagePackOpened => {
+                self.emoji_picker = None;
+                self.sticker_picker = None;
+                self.dialog = Some(Dialog::ImagePack(ImagePackForm {
+                    emoticon: true,
+                    sticker: true,
+                    ..Default::default()
+                }));
+                self.load_image_packs()
+            }
+            ClientMessage::PackImageShortcodeChanged(shortcode) => {
+                if let Some(Dialog::ImagePack(form)) = &mut self.dialog {
+                    form.shortcode = shortcode;
+                }
+                Command::none()
+            }
+            ClientMessage::PackImagePathChanged(path) => {
+                if let Some(Dialog::ImagePack(form)) = &mut self.dialog {
+                    form.path = path;
+                }
+                Command::none()
+            }
+            ClientMessage::PackImageEmoticonToggled(emoticon) => {
+                if let Some(Dialog::ImagePack(form)) = &mut self.dialog {
+                    form.emoticon = emoticon;
+                }
+                Command::none()
+            }
+            ClientMessage::PackImageStickerToggled(sticker) => {
+                if let Some(Dialog::ImagePack(form)) = &mut self.dialog {
+                    form.sticker = sticker;
+                }
+                Command::none()
+            }
+            ClientMessage::PackImageSubmitted => {
+                let (Some(client), Some(Dialog::ImagePack(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+                let path = form.path.trim();
+
+                if form.pending || path.is_empty() {
+                    return Command::none();
+                }
+
+                form.pending = true;
+                form.error = None;
+
+                Command::perform(
+                    matrix::add_pack_image(
+                        client,
+                        form.shortcode.clone(),
+                        PathBuf::from(path),
+                        form.emoticon,
+                        form.sticker,
+                    ),
+                    |res| match res {
+                        Ok(pack) => ClientMessage::ImagePackUpdated(pack),
+                        Err(err) => {
+                            warn!("Failed to add image to pack with error {}", err);
+                            ClientMessage::ImagePackFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::PackImageRemoved(shortcode) => {
+                let (Some(client), Some(Dialog::ImagePack(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                if form.pending {
+                    return Command::none();
+                }
+
+                form.pending = true;
+                form.error = None;
+
+                Command::perform(
+                    matrix::remove_pack_image(client, shortcode),
+                    |res| match res {
+                        Ok(pack) => ClientMessage::ImagePackUpdated(pack),
+                        Err(err) => {
+                            warn!("Failed to remove image from pack with error {}", err);
+                            ClientMessage::ImagePackFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::ImagePackUpdated(pack) => {
+                if let Some(Dialog::ImagePack(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.shortcode.clear();
+                    form.path.clear();
+                }
+
+                // The store only has the change after the next sync, so the
+                // packs aren't reloaded from it.
+                let urls = pack
+                    .images
+                    .iter()
+                    .map(|image| image.url.clone())
+                    .collect::<Vec<_>>();
+                match self.image_packs.iter_mut().find(|pack| pack.personal) {
+                    Some(personal) => *personal = pack,
+                    None => self.image_packs.insert(0, pack),
+                }
+                Command::batch(urls.into_iter().map(|url| self.load_media(url)))
+            }
+            ClientMessage::ImagePackFailed(err) => {
+                if let Some(Dialog::ImagePack(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::RecentEmojiLoaded(recent_emoji) => {
+                self.recent_emoji = recent_emoji;
+                Command::none()
+            }
+            ClientMessage::NewReactions(reactions) => {
+                for reaction in reactions {
+                    let keys = self.reactions.entry(reaction.event_id).or_default();
+                    match keys.iter_mut().find(|(key, _)| *key == reaction.key) {
+                        Some((_, senders)) => {
+                            if !senders.contains(&reaction.sender_id) {
+                                senders.push(reaction.sender_id);
+                            }
+                        }
+                        None => keys.push((reaction.key, vec![reaction.sender_id])),
+                    }
+                }
+                Command::none()
+            }
+            ClientMessage::Reacted(room_id, event_id, key) => {
+                let Some(client) = self.client.clone() else {
+                    return Command::none();
+                };
+                if self.reacted(&event_id, &key) {
+                    return Command::none();
+                }
+
+                emoji::remember(&mut self.recent_emoji, &key);
+                Command::batch(vec![
+                    Command::perform(
+                        matrix::send_reaction(client, room_id, event_id, key),
+                        |res| match res {
+                            Ok(()) => ClientMessage::None,
+                            Err(err) => {
+                                warn!("Failed to react with error {}", err);
+                                ClientMessage::RoomActionFailed(err.to_string())
+                            }
+                        },
+                    ),
+                    self.save_recent_emoji(),
+                ])
+            }
+            ClientMessage::PillRemoved(index) => {
+                if index < self.pills.len() {
+                    self.pills.remove(index);
+                }
+                Command::none()
+            }
+            ClientMessage::CommandFailed(err) => {
+                self.command_error = Some(err);
+                Command::none()
+            }
+            ClientMessage::DirectMessageStarted(room_id, body) => {
+                let send = self.queue_message(room_id.to_string(), body, false, &[]);
+                Command::batch(vec![self.update(ClientMessage::RoomChanged(room_id)), send])
+            }
+            ClientMessage::DevtoolsOpened => {
+                let (Some(client), Ok(room_id)) =
+                    (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+                else {
+                    return Command::none();
+                };
+
+                self.dialog = Some(Dialog::Devtools(DevtoolsForm {
+                    room_id: room_id.clone(),
+                    state: None,
+                    selected: None,
+                    error: None,
+                }));
+
+                Command::perform(matrix::room_state(client, room_id), |res| match res {
+                    Ok(state) => ClientMessage::RoomStateLoaded(state),
+                    Err(err) => {
+                        warn!("Failed to load room state with error {}", err);
+                        ClientMessage::RoomStateFailed(err.to_string())
+                    }
+                })
+            }
+            ClientMessage::RoomStateLoaded(state) => {
+                if let Some(Dialog::Devtools(form)) = &mut self.dialog {
+                    form.state = Some(state);
+                }
+                Command::none()
+            }
+            ClientMessage::RoomStateFailed(err) => {
+                if let Some(Dialog::Devtools(form)) = &mut self.dialog {
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::StateEventSelected(index) => {
+                if let Some(Dialog::Devtools(form)) = &mut self.dialog {
+                    form.selected = Some(index);
+                }
+                Command::none()
+            }
+            ClientMessage::LoginUsernameChanged(username) => {
+                self.login.username = username;
+                Command::none()
+            }
+            ClientMessage::LoginPasswordChanged(password) => {
+                self.login.password = password;
+                Command::none()
+            }
+            ClientMessage::LoginDiscover => {
+                if self.login.pending || self.login.username.is_empty() {
+                    return Command::none();
+                }
+
+                self.login.pending = true;
+                self.login.error = None;
+
+                Command::perform(
+                    matrix::discover_login_flows(self.login.username.clone()),
+                    |res| match res {
+                        Ok(flows) => ClientMessage::LoginFlowsDiscovered(flows),
+                        Err(err) => {
+                            warn!("Failed to discover login flows with error {}", err);
+                            ClientMessage::FailedLogin(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::LoginFlowsDiscovered(flows) => {
+                self.login.pending = false;
+
+                if !flows.password && flows.sso.is_none() && flows.oidc_issuer.is_none() {
+                    self.login.error =
+                        Some("This homeserver offers no login method we support".into());
+                    return Command::none();
+                }
+
+                self.login.flows = Some(flows);
+                Command::none()
+            }
+            ClientMessage::LoginFlowsReset => {
+                self.login.flows = None;
+                self.login.password.clear();
+                self.login.error = None;
+                Command::none()
+            }
+            ClientMessage::OidcLoginRequested => {
+                if self.login.pending {
+                    return Command::none();
+                }
+
+                self.login.pending = true;
+                self.login.error = None;
+                let username = self.login.username.clone();
+
+                self.browser_login(|sender| matrix::login_oidc(username, sender))
+            }
+            ClientMessage::SsoLoginRequested(identity_provider) => {
+                if self.login.pending {
+                    return Command::none();
+                }
+
+                self.login.pending = true;
+                self.login.error = None;
+                let username = self.login.username.clone();
+
+                self.browser_login(|sender| matrix::login_sso(username, identity_provider, sender))
+            }
+            ClientMessage::BrowserLoginOpened(url) => {
+                loopback::open_in_browser(&url);
+                self.login.browser_url = Some(url);
+                Command::none()
+            }
+            ClientMessage::BrowserLoginCancelled => {
+                if let Some(browser_login) = self.login.browser_login.take() {
+                    browser_login.abort();
+                }
+                self.login.pending = false;
+                self.login.browser_url = None;
+                Command::none()
+            }
+            ClientMessage::LoginSubmitted => {
+                if self.login.pending || self.login.password.is_empty() {
+                    return Command::none();
+                }
+
+                self.login.pending = true;
+                self.login.error = None;
+                let password = std::mem::take(&mut self.login.password);
+
+                if self.session_invalidated == Some(SessionInvalidated::SoftLogout) {
+                    return Command::perform(matrix::reauthenticate(password), |res| match res {
+                        Ok((client, token)) => ClientMessage::LoggedIn(client, token),
+                        Err(err) => {
+                            warn!("Failed to login with error {}", err);
+                            ClientMessage::FailedLogin(err.to_string())
+                        }
+                    });
+                }
+
+                Client::log_in(Some(Credentials {
+                    username: self.login.username.clone(),
+                    password,
+                }))
+            }
+            ClientMessage::LoginRequired => {
+                self.login.pending = false;
+                Command::none()
+            }
+            ClientMessage::RegisterOpened => {
+                self.register = Some(RegisterForm {
+                    username: self.login.username.clone(),
+                    ..Default::default()
+                });
+                Command::none()
+            }
+            ClientMessage::RegisterCancelled => {
+                let Some(registration) = self.register.take().and_then(|form| form.registration)
+                else {
+                    return Command::none();
+                };
+
+                Command::perform(matrix::abort_registration(registration), |res| {
+                    if let Err(err) = res {
+                        warn!("Failed to clean up registration with error {}", err);
+                    }
+                    ClientMessage::None
+                })
+            }
+            ClientMessage::RegisterUsernameChanged(username) => {
+                if let Some(register) = &mut self.register {
+                    register.username = username;
+                }
+                Command::none()
+            }
+            ClientMessage::RegisterPasswordChanged(password) => {
+                if let Some(register) = &mut self.register {
+                    register.password = password;
+                }
+                Command::none()
+            }
+            ClientMessage::RegisterEmailChanged(email) => {
+                if let Some(register) = &mut self.register {
+                    register.email = email;
+                }
+                Command::none()
+            }
+            ClientMessage::RegisterTokenChanged(token) => {
+                if let Some(register) = &mut self.register {
+                    register.token = token;
+                }
+                Command::none()
+            }
+            ClientMessage::RegisterSubmitted => {
+                let Some(register) = &mut self.register else {
+                    return Command::none();
+                };
+
+                if register.pending || register.username.is_empty() || register.password.is_empty()
+                {
+                    return Command::none();
+                }
+
+                register.pending = true;
+                register.error = None;
+
+                Command::perform(
+                    matrix::register(register.username.clone(), register.password.clone()),
+                    |res| match res {
+                        Ok(progress) => ClientMessage::RegisterProgressed(progress),
+                        Err(err) => {
+                            warn!("Failed to register with error {}", err);
+                            ClientMessage::RegisterFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RegisterEmailRequested => {
+                let Some(register) = &mut self.register else {
+                    return Command::none();
+                };
+                let Some(registration) = register.registration.clone() else {
+                    return Command::none();
+                };
+
+                if register.pending || register.email.is_empty() {
+                    return Command::none();
+                }
+
+                register.pending = true;
+                register.error = None;
+
+                Command::perform(
+                    matrix::request_registration_email(registration, register.email.clone()),
+                    |res| match res {
+                        Ok(registration) => {
+                            ClientMessage::RegisterEmailSent(Box::new(registration))
+                        }
+                        Err(err) => {
+                            warn!("Failed to request a validation email with error {}", err);
+                            ClientMessage::RegisterStageFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RegisterEmailSent(registration) => {
+                if let Some(register) = &mut self.register {
+                    register.registration = Some(*registration);
+                    register.email_sent = true;
+                    register.pending = false;
+                }
+                Command::none()
+            }
+            ClientMessage::RegisterStageSubmitted(response) => {
+                let Some(register) = &mut self.register else {
+                    return Command::none();
+                };
+                let Some(registration) = register.registration.clone() else {
+                    return Command::none();
+                };
+
+                if register.pending {
+                    return Command::none();
+                }
+
+                register.pending = true;
+                register.error = None;
+
+                Command::perform(
+                    matrix::complete_stage(registration, response),
+                    |res| match res {
+                        Ok(progress) => ClientMessage::RegisterProgressed(progress),
+                        Err(err) => {
+                            warn!("Failed to register with error {}", err);
+                            ClientMessage::RegisterFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RegisterProgressed(progress) => match progress {
+                RegistrationProgress::Stage {
+                    registration,
+                    stage,
+                    error,
+                } => {
+                    if let Some(register) = &mut self.register {
+                        register.registration = Some(*registration);
+                        register.stage = Some(stage);
+                        register.error = error;
+                        register.pending = false;
+                    }
+                    Command::none()
+                }
+                RegistrationProgress::Complete(client) => {
+                    self.update(ClientMessage::LoggedIn(client, None))
+                }
+            },
+            ClientMessage::RegisterStageFailed(err) => {
+                if let Some(register) = &mut self.register {
+                    register.error = Some(err);
+                    register.pending = false;
+                }
+                Command::none()
+            }
+            ClientMessage::RegisterFailed(err) => {
+                let Some(register) = &mut self.register else {
+                    return Command::none();
+                };
+
+                // The registration can't be resumed, start over from the first
+                // step with what the user entered.
+                let registration = register.registration.take();
+                *register = RegisterForm {
+                    username: std::mem::take(&mut register.username),
+                    password: std::mem::take(&mut register.password),
+                    error: Some(err),
+                    ..Default::default()
+                };
+
+                let Some(registration) = registration else {
+                    return Command::none();
+                };
+                Command::perform(matrix::abort_registration(registration), |res| {
+                    if let Err(err) = res {
+                        warn!("Failed to clean up registration with error {}", err);
+                    }
+                    ClientMessage::None
+                })
+            }
+            ClientMessage::LinkOpened(url) => {
+                match Url::parse(&url) {
+                    Ok(url) => loopback::open_in_browser(&url),
+                    Err(err) => warn!("Invalid link {}: {}", url, err),
+                }
+                Command::none()
+            }
+            ClientMessage::FailedLogin(err) => {
+                self.login.pending = false;
+                self.login.browser_url = None;
+                self.login.browser_login = None;
+                self.login.error = Some(err);
+                Command::none()
+            }
+            ClientMessage::LogoutRequested => {
+                let Some(client) = self.client.clone() else {
+                    return Command::none();
+                };
+
+                Command::perform(matrix::logout(client), |res| {
+                    if let Err(err) = res {
+                        warn!("Failed to remove session with error {}", err);
+                    }
+                    ClientMessage::LoggedOut
+                })
+            }
+            ClientMessage::LoggedOut => {
+                self.reset_session();
+                Command::none()
+            }
+            ClientMessage::SessionInvalidated(invalidated) => match invalidated {
+                SessionInvalidated::SoftLogout => {
+                    self.session_invalidated = Some(invalidated);
+                    self.login = LoginForm {
+                        username: self.username.clone(),
+                        error: Some("Your session has expired, please sign in again".into()),
+                        ..Default::default()
+                    };
+                    Command::none()
+                }
+                SessionInvalidated::LoggedOut => {
+                    self.reset_session();
+                    self.login.error = Some("You have been logged out".into());
+
+                    Command::perform(matrix::remove_session(), |res| {
+                        if let Err(err) = res {
+                            warn!("Failed to remove session with error {}", err);
+                        }
+                        ClientMessage::None
+                    })
+                }
+            },
+            ClientMessage::LoggedIn(client, sync_token) => {
+                self.username = client.user_id().unwrap().to_string();
+                self.login = LoginForm::default();
+                self.register = None;
+                self.session_invalidated = None;
+                // Storing the client starts the sync loop subscription.
+                self.client = Some(client);
+                self.sync_token = sync_token;
+                self.login_count += 1;
+                Command::batch(vec![
+                    Command::perform(matrix::load_outbox(), |res| match res {
+                        Ok(outbox) => ClientMessage::OutboxLoaded(outbox),
+                        Err(err) => {
+                            warn!("Failed to load outbox with error {}", err);
+                            ClientMessage::None
+                        }
+                    }),
+                    Command::perform(matrix::load_drafts(), |res| match res {
+                        Ok(drafts) => ClientMessage::DraftsLoaded(drafts),
+                        Err(err) => {
+                            warn!("Failed to load drafts with error {}", err);
+                            ClientMessage::None
+                        }
+                    }),
+                    Command::perform(matrix::load_recent_emoji(), |res| match res {
+                        Ok(recent_emoji) => ClientMessage::RecentEmojiLoaded(recent_emoji),
+                        Err(err) => {
+                            warn!("Failed to load recent emoji with error {}", err);
+                            ClientMessage::None
+                        }
+                    }),
+                    self.update(ClientMessage::InvitesChanged),
+                ])
+            }
+            ClientMessage::OutboxLoaded(outbox) => {
+                let mut commands = Vec::new();
+
+                for queued in outbox {
+                    if self.outbox.iter().any(|q| q.txn_id == queued.txn_id) {
+                        continue;
+                    }
+
+                    self.messages.extend(self.local_echo(&queued));
+                    self.outbox.push(queued.clone());
+                    commands.push(self.load_own_member(&queued.room_id));
+                    commands.push(self.send_queued(queued));
+                }
+
+                Command::batch(commands)
+            }
+            ClientMessage::DraftsLoaded(drafts) => {
+                for (room_id, draft) in drafts {
+                    if room_id != self.roomid {
+                        self.drafts.entry(room_id).or_insert(draft);
+                    } else if self.compose_value.is_empty() {
+                        self.set_composer(draft);
+                    }
+                }
+                Command::none()
+            }
+            ClientMessage::DraftsSaveDue(revision) => {
+                if revision != self.draft_revision {
+                    return Command::none();
+                }
+                self.save_drafts()
+            }
+            ClientMessage::MessageSent(txn_id, event_id) => {
+                // Our own messages are not echoed back by the sync, the event
+                // ID is needed to react to them and show their reactions.
+                if let Some(msg) = self.messages.iter_mut().find(|msg| {
+                    msg.outgoing
+                        .as_ref()
+                        .is_some_and(|outgoing| outgoing.txn_id == txn_id)
+                }) {
+                    msg.event_id = Some(event_id);
+                }
+                self.set_send_state(&txn_id, SendState::Sent);
+                self.outbox.retain(|queued| queued.txn_id != txn_id);
+                self.save_outbox()
+            }
+            ClientMessage::MessageFailed(txn_id, err) => {
+                let retry = match err {
+                    SendError::RateLimited {
+                        retry_after: Some(retry_after),
+                    } => Command::perform(tokio::time::sleep(retry_after), {
+                        let txn_id = txn_id.clone();
+                        move |_| ClientMessage::RetryMessage(txn_id)
+                    }),
+                    _ => Command::none(),
+                };
+
+                self.set_send_state(&txn_id, SendState::Failed(err));
+                retry
+            }
+            ClientMessage::RetryMessage(txn_id) => {
+                let Some(queued) = self.outbox.iter().find(|q| q.txn_id == txn_id).cloned() else {
+                    return Command::none();
+                };
+
+                if !matches!(self.send_state(&txn_id), Some(SendState::Failed(_))) {
+                    return Command::none();
+                }
+
+                self.set_send_state(&txn_id, SendState::Sending);
+                self.send_queued(queued)
+            }
+            ClientMessage::DiscardMessage(txn_id) => {
+                self.messages.retain(|msg| {
+                    msg.outgoing
+                        .as_ref()
+                        .is_none_or(|outgoing| outgoing.txn_id != txn_id)
+                });
+                self.outbox.retain(|queued| queued.txn_id != txn_id);
+                self.save_outbox()
+            }
+            ClientMessage::SyncTokenChanged(sync_token) => {
+                self.sync_token = Some(sync_token);
+                Command::none()
+            }
+            ClientMessage::ConnectionChanged(connection) => {
+                let reconnected = connection == ConnectionState::Online
+                    && self.connection != ConnectionState::Online;
+                self.connection = connection;
+
+                if !reconnected {
+                    return Command::none();
+                }
+
+                // Messages that failed while we were offline are resent now that
+                // the homeserver is reachable again.
+                let commands = self
+                    .messages
+                    .iter()
+                    .filter_map(|msg| msg.outgoing.as_ref())
+                    .filter(|outgoing| outgoing.state == SendState::Failed(SendError::Network))
+                    .map(|outgoing| outgoing.txn_id.clone())
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .map(|txn_id| self.update(ClientMessage::RetryMessage(txn_id)))
+                    .collect::<Vec<_>>();
+
+                Command::batch(commands)
+            }
+            ClientMessage::NewMessages(messages) => {
+                let mut commands = messages
+                    .iter()
+                    .filter_map(|msg| msg.sender_avatar.clone())
+                    .map(|url| self.load_thumbnail(url))
+                    .collect::<Vec<_>>();
+                commands.extend(
+                    messages
+                        .iter()
+                        .flat_map(|msg| msg.emotes.iter().map(|(_, url)| url).chain(&msg.sticker))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .map(|url| self.load_media(url)),
+                );
+
+                // Messages that were delayed or back-filled are slotted in by
+                // the time the server received them. A sync that starts over
+                // from an older token delivers some of them again.
+                let known = self
+                    .messages
+                    .iter()
+                    .filter_map(|msg| msg.event_id.clone())
+                    .collect::<HashSet<_>>();
+                for msg in messages {
+                    if msg
+                        .event_id
+                        .as_ref()
+                        .is_some_and(|event_id| known.contains(event_id))
+                    {
+                        continue;
+                    }
+
+                    let index = self
+                        .messages
+                        .partition_point(|other| other.timestamp <= msg.timestamp);
+                    self.messages.insert(index, msg);
+                }
+                commands.push(scrollable::snap_to(
+                    SCROLLABLE_ID.clone(),
+                    scrollable::RelativeOffset::END,
+                ));
+
+                Command::batch(commands)
+            }
+            ClientMessage::RoomChanged(roomid) => {
+                if self.roomid != roomid.as_str() {
+                    // The pills are not kept with the draft, its mentions
+                    // become plain text.
+                    let draft = std::mem::take(&mut self.compose_value);
+                    if !self.roomid.is_empty() && !draft.trim().is_empty() {
+                        self.drafts.insert(self.roomid.clone(), draft);
+                    }
+                    let draft = self.drafts.remove(roomid.as_str()).unwrap_or_default();
+                    self.pills.clear();
+                    self.emoji_picker = None;
+                    self.sticker_picker = None;
+                    self.image_packs.clear();
+                    self.set_composer(draft);
+                }
+                self.roomid = roomid.to_string();
+                self.confirmation = None;
+                self.room_error = None;
+                self.selected_member = None;
+                self.dialog = None;
+
+                // Also needed without the member list, to autocomplete mentions,
+                // like the packs for custom emoji.
+                Command::batch(vec![self.load_members(), self.load_image_packs()])
+            }
+            ClientMessage::NewRoomOpened => {
+                if !matches!(self.dialog, Some(Dialog::NewRoom(_))) {
+                    self.dialog = Some(Dialog::NewRoom(NewRoomForm::default()));
+                }
+                Command::none()
+            }
+            ClientMessage::DialogClosed => {
+                self.dialog = None;
+                Command::none()
+            }
+            ClientMessage::NewRoomNameChanged(name) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.name = name;
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomTopicChanged(topic) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.topic = topic;
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomAliasChanged(alias) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.alias = alias;
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomInvitesChanged(invites) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.invites = invites;
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomPublicToggled(public) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.public = public;
+                    // Keep the preset in line with the visibility, it can still
+                    // be changed afterwards.
+                    form.room.preset = if public {
+                        RoomPreset::Public
+                    } else {
+                        RoomPreset::Private
+                    };
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomEncryptionToggled(encrypted) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.encrypted = encrypted;
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomPresetSelected(preset) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.room.preset = preset;
+                }
+                Command::none()
+            }
+            ClientMessage::NewRoomSubmitted => {
+                let (Some(client), Some(Dialog::NewRoom(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                if form.pending {
+                    return Command::none();
+                }
+
+                form.pending = true;
+                form.error = None;
+
+                Command::perform(
+                    matrix::create_room(client, form.room.clone()),
+                    |res| match res {
+                        Ok(room_id) => ClientMessage::RoomCreated(room_id),
+                        Err(err) => {
+                            warn!("Failed to create room with error {}", err);
+                            ClientMessage::RoomCreationFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RoomCreated(room_id) => self.update(ClientMessage::RoomChanged(room_id)),
+            ClientMessage::RoomCreationFailed(err) => {
+                if let Some(Dialog::NewRoom(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::NewDmOpened => {
+                if !matches!(self.dialog, Some(Dialog::NewDm(_))) {
+                    self.dialog = Some(Dialog::NewDm(NewDmForm::default()));
+                }
+                Command::none()
+            }
+            ClientMessage::NewDmQueryChanged(query) => {
+                if let Some(Dialog::NewDm(form)) = &mut self.dialog {
+                    form.query = query;
+                }
+                Command::none()
+            }
+            ClientMessage::UserSearchSubmitted => {
+                let (Some(client), Some(Dialog::NewDm(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                if form.searching || form.query.trim().is_empty() {
+                    return Command::none();
+                }
+
+                form.searching = true;
+                form.error = None;
+                let query = form.query.clone();
+
+                Command::perform(
+                    matrix::search_users(client, query.clone()),
+                    move |res| match res {
+                        Ok(users) => ClientMessage::UsersFound(query, users),
+                        Err(err) => {
+                            warn!("Failed to search users with error {}", err);
+                            ClientMessage::UserSearchFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::UsersFound(query, users) => {
+                if let Some(Dialog::NewDm(form)) = &mut self.dialog {
+                    form.searching = false;
+                    // The query may have changed while we were searching.
+                    if form.query == query {
+                        form.results = Some(users);
+                    }
+                }
+                Command::none()
+            }
+            ClientMessage::UserSearchFailed(err) => {
+                if let Some(Dialog::NewDm(form)) = &mut self.dialog {
+                    form.searching = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::DmRequested(user_id) => {
+                let Some(client) = self.client.clone() else {
+                    return Command::none();
+                };
+
+                // Also requested from the member list, without the dialog.
+                if let Some(Dialog::NewDm(form)) = &mut self.dialog {
+                    if form.pending {
+                        return Command::none();
+                    }
+
+                    form.pending = true;
+                    form.error = None;
+                }
+
+                Command::perform(matrix::start_dm(client, user_id), |res| match res {
+                    Ok(room_id) => ClientMessage::RoomChanged(room_id),
+                    Err(err) => {
+                        warn!("Failed to start direct message with error {}", err);
+                        ClientMessage::DmFailed(err.to_string())
+                    }
+                })
+            }
+            ClientMessage::DmFailed(err) => {
+                if let Some(Dialog::NewDm(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.error = Some(err);
+                } else {
+                    self.room_error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::JoinRoomOpened => {
+                if matches!(self.dialog, Some(Dialog::JoinRoom(_))) {
+                    return Command::none();
+                }
+
+                self.dialog = Some(Dialog::JoinRoom(JoinRoomForm::default()));
+                self.update(ClientMessage::DirectorySearched)
+            }
+            ClientMessage::JoinTargetChanged(target) => {
+                if let Some(Dialog::JoinRoom(form)) = &mut self.dialog {
+                    form.target = target;
+                }
+                Command::none()
+            }
+            ClientMessage::JoinSubmitted => {
+                let (Some(client), Some(Dialog::JoinRoom(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                if form.pending || form.target.trim().is_empty() {
+                    return Command::none();
+                }
+
+                form.pending = true;
+                form.error = None;
+
+                Command::perform(
+                    matrix::join_room(client, form.target.clone(), Vec::new()),
+                    |res| match res {
+                        Ok(room_id) => ClientMessage::RoomChanged(room_id),
+                        Err(err) => {
+                            warn!("Failed to join room with error {}", err);
+                            ClientMessage::JoinFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::DirectoryServerChanged(server) => {
+                if let Some(Dialog::JoinRoom(form)) = &mut self.dialog {
+                    form.server = server;
+                }
+                Command::none()
+            }
+            ClientMessage::DirectorySearchChanged(search) => {
+                if let Some(Dialog::JoinRoom(form)) = &mut self.dialog {
+                    form.search = search;
+                }
+                Command::none()
+            }
+            ClientMessage::DirectorySearched | ClientMessage::DirectoryMoreRequested => {
+                let (Some(client), Some(Dialog::JoinRoom(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                let more = matches!(message, ClientMessage::DirectoryMoreRequested);
+
+                if form.loading || (more && form.next_batch.is_none()) {
+                    return Command::none();
+                }
+
+                form.loading = true;
+                form.error = None;
+                let since = if more { form.next_batch.clone() } else { None };
+
+                Command::perform(
+                    matrix::public_rooms(client, form.server.clone(), form.search.clone(), since),
+                    move |res| match res {
+                        Ok(page) => ClientMessage::DirectoryLoaded(page, more),
+                        Err(err) => {
+                            warn!("Failed to load the room directory with error {}", err);
+                            ClientMessage::DirectoryFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::DirectoryLoaded(page, more) => {
+                if let Some(Dialog::JoinRoom(form)) = &mut self.dialog {
+                    form.loading = false;
+                    if !more {
+                        form.rooms.clear();
+                    }
+                    form.rooms.extend(page.rooms);
+                    form.next_batch = page.next_batch;
+                }
+                Command::none()
+            }
+            ClientMessage::DirectoryFailed(err) => {
+                if let Some(Dialog::JoinRoom(form)) = &mut self.dialog {
+                    form.loading = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::PublicRoomJoinRequested(room_id) => {
+                let (Some(client), Some(Dialog::JoinRoom(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                if form.pending {
+                    return Command::none();
+                }
+
+                form.pending = true;
+                form.error = None;
+                // The room is listed by that server, so it can be joined through it.
+                let via: Vec<OwnedServerName> =
+                    ServerName::parse(form.server.trim()).into_iter().collect();
+
+                Command::perform(matrix::join_room(client, room_id.to_string(), via), |res| {
+                    match res {
+                        Ok(room_id) => ClientMessage::RoomChanged(room_id),
+                        Err(err) => {
+                            warn!("Failed to join room with error {}", err);
+                            ClientMessage::JoinFailed(err.to_string())
+                        }
+                    }
+                })
+            }
+            ClientMessage::JoinFailed(err) => {
+                if let Some(Dialog::JoinRoom(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::InvitesChanged => {
+                let Some(client) = self.client.clone() else {
+                    return Command::none();
+                };
+
+                Command::perform(matrix::pending_invites(client), |res| match res {
+                    Ok(invites) => ClientMessage::InvitesLoaded(invites),
+                    Err(err) => {
+                        warn!("Failed to load invites with error {}", err);
+                        ClientMessage::None
+                    }
+                })
+            }
+            ClientMessage::ChangesToggled(event_id) => {
+                if !self.expanded_changes.remove(&event_id) {
+                    self.expanded_changes.insert(event_id);
+                }
+                Command::none()
+            }
+            ClientMessage::AvatarsChanged(changed) => {
+                let mut commands = Vec::new();
+                for (room_id, url) in changed {
+                    match url {
+                        Some(url) => {
+                            commands.push(self.load_thumbnail(url.clone()));
+                            self.room_avatars.insert(room_id, url);
+                        }
+                        None => {
+                            self.room_avatars.remove(&room_id);
+                        }
+                    }
+                }
+
+                Command::batch(commands)
+            }
+            ClientMessage::AvatarLoaded(url, data) => {
+                self.thumbnails
+                    .insert(url, Some(image::Handle::from_memory(data)));
+                Command::none()
+            }
+            ClientMessage::InvitesLoaded(invites) => {
+                self.invites = invites;
+                Command::none()
+            }
+            ClientMessage::InviteAccepted(room_id) => {
+                let Some(client) = self.client.clone() else {
+                    return Command::none();
+                };
+
+                if self.invite_pending.is_some() {
+                    return Command::none();
+                }
+
+                self.invite_pending = Some(room_id.clone());
+                self.invite_error = None;
+
+                Command::perform(matrix::accept_invite(client, room_id), |res| match res {
+                    Ok(room_id) => ClientMessage::InviteHandled(Some(room_id)),
+                    Err(err) => {
+                        warn!("Failed to accept invite with error {}", err);
+                        ClientMessage::InviteFailed(err.to_string())
+                    }
+                })
+            }
+            ClientMessage::InviteDeclined(room_id, ignore_inviter) => {
+                let Some(client) = self.client.clone() else {
+                    return Command::none();
+                };
+
+                if self.invite_pending.is_some() {
+                    return Command::none();
+                }
+
+                self.invite_pending = Some(room_id.clone());
+                self.invite_error = None;
+
+                Command::perform(
+                    matrix::decline_invite(client, room_id, ignore_inviter),
+                    |res| match res {
+                        Ok(()) => ClientMessage::InviteHandled(None),
+                        Err(err) => {
+                            warn!("Failed to decline invite with error {}", err);
+                            ClientMessage::InviteFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::InviteHandled(joined) => {
+                self.invite_pending = None;
+
+                let mut commands = vec![self.update(ClientMessage::InvitesChanged)];
+                if let Some(room_id) = joined {
+                    commands.push(self.update(ClientMessage::RoomChanged(room_id)));
+                }
+
+                Command::batch(commands)
+            }
+            ClientMessage::InviteFailed(err) => {
+                self.invite_pending = None;
+                self.invite_error = Some(err);
+                self.update(ClientMessage::InvitesChanged)
+            }
+            ClientMessage::LeaveRequested(room_id) => {
+                self.confirmation = Some(Confirmation::Leave(room_id));
+                self.room_error = None;
+                Command::none()
+            }
+            ClientMessage::ForgetRequested(room_id) => {
+                self.confirmation = Some(Confirmation::Forget(room_id));
+                self.room_error = None;
+                Command::none()
+            }
+            ClientMessage::ConfirmationCancelled => {
+                self.confirmation = None;
+                Command::none()
+            }
+            ClientMessage::ConfirmationAccepted => {
+                let (Some(client), Some(confirmation)) =
+                    (self.client.clone(), self.confirmation.take())
+                else {
+                    return Command::none();
+                };
+
+                let (room_id, forget) = match confirmation {
+                    Confirmation::Leave(room_id) => (room_id, false),
+                    Confirmation::Forget(room_id) => (room_id, true),
+                };
+
+                Command::perform(
+                    matrix::leave_room(client, room_id.clone(), forget),
+                    move |res| match res {
+                        Ok(()) => ClientMessage::RoomLeft(room_id),
+                        Err(err) => {
+                            warn!("Failed to leave room with error {}", err);
+                            ClientMessage::RoomActionFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RoomLeft(room_id) => {
+                let had_draft = self.drafts.remove(room_id.as_str()).is_some();
+                if self.roomid != room_id.as_str() {
+                    return if had_draft {
+                        self.save_drafts()
+                    } else {
+                        Command::none()
+                    };
+                }
+
+                // Nothing of the room is kept, not even what was typed for it.
+                self.roomid.clear();
+                self.pills.clear();
+                self.emoji_picker = None;
+                self.sticker_picker = None;
+                self.image_packs.clear();
+                self.members = None;
+                self.members_error = None;
+                self.selected_member = None;
+                self.confirmation = None;
+                self.set_composer(String::new());
+                self.save_drafts()
+            }
+            ClientMessage::RoomActionFailed(err) => {
+                self.room_error = Some(err);
+                Command::none()
+            }
+            ClientMessage::ArchiveToggled => {
+                self.show_archive = !self.show_archive;
+                Command::none()
+            }
+            ClientMessage::MembersToggled => {
+                self.show_members = !self.show_members;
+                self.selected_member = None;
+
+                if self.show_members {
+                    self.load_members()
+                } else {
+                    Command::none()
+                }
+            }
+            ClientMessage::MembersLoaded(room_id, members) => {
+                // The room may have changed while the members were loading.
+                if self.roomid == room_id.as_str() {
+                    self.members = Some(members);
+                }
+                Command::none()
+            }
+            ClientMessage::OwnMemberLoaded(room_id, name, avatar) => {
+                // Echoes added before we knew are updated in place.
+                for msg in &mut self.messages {
+                    if msg.outgoing.is_some() && msg.room_id == room_id {
+                        msg.sender.clone_from(&name);
+                        msg.sender_avatar.clone_from(&avatar);
+                    }
+                }
+                let command = avatar
+                    .clone()
+                    .map_or_else(Command::none, |url| self.load_thumbnail(url));
+                self.own_members.insert(room_id, (name, avatar));
+                command
+            }
+            ClientMessage::MembersFailed(room_id, err) => {
+                if self.roomid == room_id.as_str() {
+                    self.members_error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::MemberSelected(user_id) => {
+                self.selected_member = user_id;
+                self.moderation_reason.clear();
+                self.moderation_error = None;
+                Command::none()
+            }
+            ClientMessage::ModerationReasonChanged(reason) => {
+                self.moderation_reason = reason;
+                Command::none()
+            }
+            ClientMessage::ModerationRequested(user_id, action) => {
+                let (Some(client), Ok(room_id)) =
+                    (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+                else {
+                    return Command::none();
+                };
+
+                if self.moderation_pending {
+                    return Command::none();
+                }
+
+                self.moderation_pending = true;
+                self.moderation_error = None;
+
+                Command::perform(
+                    matrix::moderate(
+                        client,
+                        room_id,
+                        user_id,
+                        action,
+                        std::mem::take(&mut self.moderation_reason),
+                    ),
+                    |res| match res {
+                        Ok(()) => ClientMessage::ModerationDone,
+                        Err(err) => {
+                            warn!("Failed to moderate member with error {}", err);
+                            ClientMessage::ModerationFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::MemberPowerLevelRequested(user_id, power_level) => {
+                let (Some(client), Ok(room_id)) =
+                    (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+                else {
+                    return Command::none();
+                };
+
+                if self.moderation_pending {
+                    return Command::none();
+                }
+
+                self.moderation_pending = true;
+                self.moderation_error = None;
+
+                Command::perform(
+                    matrix::set_member_power_level(client, room_id, user_id, power_level),
+                    |res| match res {
+                        Ok(()) => ClientMessage::ModerationDone,
+                        Err(err) => {
+                            warn!("Failed to change power level with error {}", err);
+                            ClientMessage::ModerationFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::ModerationDone => {
+                self.moderation_pending = false;
+                // Reload instead of waiting for the sync to reflect the change.
+                self.load_members()
+            }
+            ClientMessage::ModerationFailed(err) => {
+                self.moderation_pending = false;
+                self.moderation_error = Some(err);
+                Command::none()
+            }
+            ClientMessage::PowerLevelsOpened => {
+                let (Some(client), Ok(room_id)) =
+                    (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+                else {
+                    return Command::none();
+                };
+
+                self.dialog = Some(Dialog::PowerLevels(PowerLevelsForm {
+                    room_id: room_id.clone(),
+                    settings: None,
+                    events: Vec::new(),
+                    editable: self
+                        .members
+                        .as_ref()
+                        .is_some_and(|members| members.permissions.change_power_levels),
+                    error: None,
+                    pending: false,
+                }));
+
+                Command::perform(matrix::power_levels(client, room_id), |res| match res {
+                    Ok(settings) => ClientMessage::PowerLevelsLoaded(settings),
+                    Err(err) => {
+                        warn!("Failed to load power levels with error {}", err);
+                        ClientMessage::PowerLevelsFailed(err.to_string())
+                    }
+                })
+            }
+            ClientMessage::PowerLevelsLoaded(settings) => {
+                if let Some(Dialog::PowerLevels(form)) = &mut self.dialog {
+                    form.settings = Some(
+                        settings
+                            .settings
+                            .into_iter()
+                            .map(|(setting, level)| (setting, level.to_string()))
+                            .collect(),
+                    );
+                    form.events = settings
+                        .events
+                        .into_iter()
+                        .map(|(event_type, level)| (event_type, level.to_string()))
+                        .collect();
+                }
+                Command::none()
+            }
+            ClientMessage::PowerLevelsFailed(err) => {
+                if let Some(Dialog::PowerLevels(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::PowerLevelChanged(setting, level) => {
+                if let Some(Dialog::PowerLevels(PowerLevelsForm {
+                    settings: Some(settings),
+                    ..
+                })) = &mut self.dialog
+                {
+                    if let Some((_, value)) = settings.iter_mut().find(|(s, _)| *s == setting) {
+                        *value = level;
+                    }
+                }
+                Command::none()
+            }
+            ClientMessage::EventPowerLevelChanged(index, level) => {
+                if let Some(Dialog::PowerLevels(form)) = &mut self.dialog {
+                    if let Some((_, value)) = form.events.get_mut(index) {
+                        *value = level;
+                    }
+                }
+                Command::none()
+            }
+            ClientMessage::PowerLevelsSubmitted => {
+                let (Some(client), Some(Dialog::PowerLevels(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+                let Some(settings) = &form.settings else {
+                    return Command::none();
+                };
+
+                if form.pending || !form.editable {
+                    return Command::none();
+                }
+
+                let parse = |label: &str, level: &str| {
+                    level
+                        .trim()
+                        .parse::<i64>()
+                        .map_err(|_| format!("The level for \"{label}\" must be a number"))
+                };
+                let settings = settings
+                    .iter()
+                    .map(|(setting, level)| Ok((*setting, parse(&setting.to_string(), level)?)))
+                    .collect::<Result<Vec<_>, String>>()
+                    .and_then(|settings| {
+                        let events = form
+                            .events
+                            .iter()
+                            .map(|(event_type, level)| {
+                                Ok((event_type.clone(), parse(event_type, level)?))
+                            })
+                            .collect::<Result<Vec<_>, String>>()?;
+
+                        Ok(PowerLevelSettings { settings, events })
+                    });
+
+                let settings = match settings {
+                    Ok(settings) => settings,
+                    Err(err) => {
+                        form.error = Some(err);
+                        return Command::none();
+                    }
+                };
 
-    fn new(flags: Self::Flags) -> (Self, iced::Command<Self::Message>) {
-        let (command_sender, command_receiver) = std::sync::mpsc::channel();
+                form.pending = true;
+                form.error = None;
 
-        let client = Self {
-            username: flags.username.clone(),
-            command_sender: Some(command_sender.clone()),
-            command_receiver: Some(Arc::new(Mutex::new(command_receiver))),
-            ..Default::default()
-        };
+                Command::perform(
+                    matrix::set_power_levels(client, form.room_id.clone(), settings),
+                    |res| match res {
+                        Ok(()) => ClientMessage::DialogClosed,
+                        Err(err) => {
+                            warn!("Failed to change power levels with error {}", err);
+                            ClientMessage::PowerLevelsFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RoomSettingsOpened => {
+                let (Some(client), Ok(room_id)) =
+                    (self.client.clone(), OwnedRoomId::from_str(&self.roomid))
+                else {
+                    return Command::none();
+                };
 
-        let credentials = Credentials {
-            username: flags.username,
-            password: flags.password,
-        };
+                self.dialog = Some(Dialog::RoomSettings(Box::new(RoomSettingsForm {
+                    room_id: room_id.clone(),
+                    original: None,
+                    settings: RoomSettings::default(),
+                    avatar_path: String::new(),
+                    error: None,
+                    pending: false,
+                })));
 
-        (
-            client,
-            Command::perform(matrix::run(credentials), |res| {
-                let (client, token) = match res {
-                    Ok((client, token)) => (client, token),
+                Command::perform(matrix::room_settings(client, room_id), |res| match res {
+                    Ok(settings) => ClientMessage::RoomSettingsLoaded(settings),
                     Err(err) => {
-                        warn!("Failed to login with error {}", err);
-                        return ClientMessage::FailedLogin;
+                        warn!("Failed to load room settings with error {}", err);
+                        ClientMessage::RoomSettingsFailed(err.to_string())
                     }
+                })
+            }
+            ClientMessage::RoomSettingsLoaded(settings) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.original = Some(settings.clone());
+                    form.settings = settings;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsFailed(err) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.error = Some(err);
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsNameChanged(name) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.name = name;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsTopicChanged(topic) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.topic = topic;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsCanonicalAliasChanged(alias) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.canonical_alias = alias;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsAltAliasesChanged(aliases) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.alt_aliases = aliases;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsJoinRuleSelected(join_rule) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.join_rule = join_rule;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsAllowedRoomsChanged(rooms) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.allowed_rooms = rooms;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsGuestAccessToggled(guest_access) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.guest_access = guest_access;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsHistoryVisibilitySelected(history_visibility) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.history_visibility = history_visibility;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsEncryptionToggled(encrypted) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.settings.encrypted = encrypted;
+                }
+                Command::none()
+            }
+            ClientMessage::RoomSettingsSubmitted => {
+                let (Some(client), Some(Dialog::RoomSettings(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+                let Some(original) = form.original.clone() else {
+                    return Command::none();
                 };
-                info!("Logged in as {}", client.user_id().unwrap());
-                ClientMessage::LoggedIn(client, token)
-            }),
-        )
-    }
 
-    fn title(&self) -> String {
-        env!("CARGO_PKG_NAME").into()
-    }
+                if form.pending || form.settings == original {
+                    return Command::none();
+                }
 
-    fn update(&mut self, message: Self::Message) -> iced::Command<Self::Message> {
-        match message {
-            ClientMessage::ComposerTyped(s) => {
-                self.compose_value = s;
+                form.pending = true;
+                form.error = None;
+
+                Command::perform(
+                    matrix::save_room_settings(
+                        client,
+                        form.room_id.clone(),
+                        original,
+                        form.settings.clone(),
+                    ),
+                    |res| match res {
+                        Ok(()) => ClientMessage::DialogClosed,
+                        Err(err) => {
+                            warn!("Failed to change room settings with error {}", err);
+                            ClientMessage::RoomSettingsFailed(err.to_string())
+                        }
+                    },
+                )
+            }
+            ClientMessage::RoomAvatarPathChanged(path) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.avatar_path = path;
+                }
                 Command::none()
             }
-            ClientMessage::MessageSubmitted => match self.compose_value.as_str() {
-                "" => Command::none(),
-                _ => {
-                    let message = Message {
-                        sender: self.username.clone(),
-                        contents: self.compose_value.clone(),
-                        timestamp: Local::now(),
-                    };
-
-                    self.messages.push(message.clone());
-                    self.compose_value.clear();
-
-                    if let Some(client) = &self.client {
-                        let client_clone = client.clone();
-                        let roomid = self.roomid.clone();
-                        let content = message.contents.clone();
-                        return Command::batch(vec![
-                            scrollable::snap_to(
-                                SCROLLABLE_ID.clone(),
-                                scrollable::RelativeOffset::END,
-                            ),
-                            Command::perform(
-                                async move {
-                                    Client::send_message(client_clone, roomid, content)
-                                        .await
-                                        .unwrap();
-                                },
-                                |_| ClientMessage::None,
-                            ),
-                        ]);
-                    };
+            ClientMessage::RoomAvatarUploadRequested => {
+                let (Some(client), Some(Dialog::RoomSettings(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+                let path = form.avatar_path.trim();
 
-                    scrollable::snap_to(SCROLLABLE_ID.clone(), scrollable::RelativeOffset::END)
+                if form.pending || path.is_empty() {
+                    return Command::none();
                 }
-            },
-            ClientMessage::LoggedIn(client, sync_token) => {
-                self.client = Some(client.clone());
-                self.sync_token = sync_token.clone();
-                let command_sender = self.command_sender.clone().unwrap();
+
+                form.pending = true;
+                form.error = None;
+
                 Command::perform(
-                    async move { matrix::start_event_loop(client, sync_token, command_sender).await },
-                    |_| ClientMessage::FailedLogin,
+                    matrix::set_room_avatar(client, form.room_id.clone(), PathBuf::from(path)),
+                    |res| match res {
+                        Ok(url) => ClientMessage::RoomAvatarChanged(Some(url)),
+                        Err(err) => {
+                            warn!("Failed to change room avatar with error {}", err);
+                            ClientMessage::RoomSettingsFailed(err.to_string())
+                        }
+                    },
                 )
             }
-            ClientMessage::NewMessage(message) => {
-                self.messages.push(message);
-                scrollable::snap_to(SCROLLABLE_ID.clone(), scrollable::RelativeOffset::END)
+            ClientMessage::RoomAvatarRemoveRequested => {
+                let (Some(client), Some(Dialog::RoomSettings(form))) =
+                    (self.client.clone(), &mut self.dialog)
+                else {
+                    return Command::none();
+                };
+
+                if form.pending {
+                    return Command::none();
+                }
+
+                form.pending = true;
+                form.error = None;
+
+                Command::perform(
+                    matrix::remove_room_avatar(client, form.room_id.clone()),
+                    |res| match res {
+                        Ok(()) => ClientMessage::RoomAvatarChanged(None),
+                        Err(err) => {
+                            warn!("Failed to remove room avatar with error {}", err);
+                            ClientMessage::RoomSettingsFailed(err.to_string())
+                        }
+                    },
+                )
             }
-            ClientMessage::RoomChanged(roomid) => {
-                self.roomid = roomid.to_string();
+            ClientMessage::RoomAvatarChanged(url) => {
+                if let Some(Dialog::RoomSettings(form)) = &mut self.dialog {
+                    form.pending = false;
+                    form.avatar_path.clear();
+                    form.settings.avatar_url.clone_from(&url);
+                    if let Some(original) = &mut form.original {
+                        original.avatar_url = url;
+                    }
+                }
                 Command::none()
             }
-            ClientMessage::FailedLogin => Command::none(),
             ClientMessage::None => Command::none(),
         }
     }
 
     fn view(&self) -> iced::Element<'_, Self::Message, Self::Theme, iced::Renderer> {
-        let infobar = row![Text::new(
-            self.client
-                .clone()
-                .and_then(|client| {
-                    let binding = client.rooms();
-
-                    let clnt = binding
-                        .iter()
-                        .find(|room| room.room_id().to_string() == self.roomid);
+        if self.client.is_none() || self.session_invalidated.is_some() {
+            return match &self.register {
+                Some(register) if self.client.is_none() => self.view_register(register),
+                _ => self.view_login(),
+            };
+        }
 
-                    if clnt.is_none() {
-                        return None;
-                    }
+        let connection = match self.connection {
+            ConnectionState::Connecting => "Connecting…",
+            ConnectionState::Online => "Online",
+            ConnectionState::Reconnecting => "Reconnecting…",
+            ConnectionState::Offline => "Offline",
+        };
 
-                    let out = clnt.unwrap().name().unwrap_or_else(|| {
-                        clnt.unwrap()
-                            .direct_targets()
-                            .iter()
-                            .map(|id| id.to_string())
-                            .collect::<Vec<String>>()
-                            .join(", ")
-                    });
+        let selected_room = self
+            .client
+            .as_ref()
+            .zip(OwnedRoomId::from_str(&self.roomid).ok())
+            .and_then(|(client, room_id)| client.get_room(&room_id));
+        let room_joined = selected_room
+            .as_ref()
+            .is_some_and(|room| room.state() == RoomState::Joined);
 
-                    Some(out)
+        let members_toggle = selected_room.as_ref().map(|_| {
+            Button::new(
+                Text::new(if self.show_members {
+                    "Hide members"
+                } else {
+                    "Members"
                 })
-                .unwrap_or("".to_string())
-        )];
-
-        let timeline = Container::new(
-            Scrollable::new(
-                column(self.messages.clone().into_iter().map(|msg| {
-                    column![
-                        row![
-                            Text::new(msg.sender),
-                            Text::new(format!("{}", msg.timestamp.format("%H:%M"))).size(12)
-                        ]
-                        .align_items(iced::Alignment::Center)
-                        .spacing(8),
-                        Text::new(msg.contents)
-                    ]
-                    .into()
-                }))
-                .spacing(8)
-                .padding(Padding::from([0, 20, 0, 0]))
-                .width(Length::Fill),
+                .size(12),
             )
-            .id(SCROLLABLE_ID.clone()),
+            .on_press(ClientMessage::MembersToggled)
+            .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+        });
+
+        let settings_button = room_joined.then(|| {
+            Button::new(Text::new("Settings").size(12))
+                .on_press(ClientMessage::RoomSettingsOpened)
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+        });
+
+        let room_action = selected_room.as_ref().map(|room| {
+            let (label, message) = match room.state() {
+                RoomState::Left => (
+                    "Forget",
+                    ClientMessage::ForgetRequested(room.room_id().to_owned()),
+                ),
+                _ => (
+                    "Leave",
+                    ClientMessage::LeaveRequested(room.room_id().to_owned()),
+                ),
+            };
+
+            Button::new(Text::new(label).size(12))
+                .on_press(message)
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction)))
+        });
+
+        let title = selected_room.as_ref().map(|room| {
+            row![
+                self.view_room_avatar(room, 36),
+                column![Text::new(room_name(room))]
+                    .push_maybe(room.topic().map(|topic| view_topic(&topic)))
+                    .spacing(2),
+            ]
+            .align_items(iced::Alignment::Center)
+            .spacing(8)
+        });
+
+        let infobar = row![
+            Container::new(row![].push_maybe(title)).width(Length::Fill),
+            Text::new(connection).size(12).style(match self.connection {
+                ConnectionState::Online => color!(0x8fd694),
+                ConnectionState::Connecting | ConnectionState::Reconnecting => color!(0xf6c177),
+                ConnectionState::Offline => color!(0xff6b6b),
+            }),
+        ]
+        .push_maybe(members_toggle)
+        .push_maybe(settings_button)
+        .push_maybe(room_action)
+        .push(
+            Button::new(Text::new("Log out").size(12))
+                .on_press(ClientMessage::LogoutRequested)
+                .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
         )
-        .align_y(Vertical::Bottom)
-        .height(Length::Fill)
-        .width(Length::Fill);
+        .align_items(iced::Alignment::Center)
+        .spacing(8);
+
+        let confirmation = self.confirmation.as_ref().map(|confirmation| {
+            let (prompt, label) = match confirmation {
+                Confirmation::Leave(_) => (
+                    "Leave this room? Rejoining a private room needs a new invite.",
+                    "Leave",
+                ),
+                Confirmation::Forget(_) => (
+                    "Forget this room? It will be removed from your archive.",
+                    "Forget",
+                ),
+            };
+
+            row![
+                Text::new(prompt).size(12).width(Length::Fill),
+                Button::new(Text::new(label).size(12))
+                    .on_press(ClientMessage::ConfirmationAccepted)
+                    .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+                Button::new(Text::new("Cancel").size(12))
+                    .on_press(ClientMessage::ConfirmationCancelled)
+                    .style(theme::Button::Custom(Box::new(style::ButtonMessageAction))),
+            ]
+            .align_items(iced::Alignment::Center)
+            .spacing(8)
+        });
+        let room_error = self
+            .room_error
+            .as_ref()
+            .map(|error| Text::new(error).size(12).style(color!(0xff6b6b)));
+
+        let timeline =
+            Container::new(Scrollable::new(self.view_timeline()).id(SCROLLABLE_ID.clone()))
+                .align_y(Vertical::Bottom)
+                .height(Length::Fill)
+                .width(Length::Fill);
+
+        let composer_padding = Padding {
+            top: 12.0,
+            right: 12.0,
+            bottom: 12.0,
+            left: 15.0,
+        };
+        // The text editor has no placeholder, so a disabled text input tells
+        // why there is nothing to write to.
+        let compose_input: iced::Element<'_, Self::Message, Self::Theme, iced::Renderer> =
+            if room_joined {
+                Container::new(
+                    TextEditor::new(&self.composer)
+                        .on_action(ClientMessage::ComposerEdited)
+                        .padding(composer_padding)
+                        .style(theme::TextEditor::Custom(Box::new(
+                            style::TextEditorComposer,
+                        ))),
+                )
+                .max_height(COMPOSER_MAX_HEIGHT)
+                .into()
+            } else {
+                TextInput::new(
+                    if selected_room.is_some() {
+                        "You are no longer in this room"
+                    } else {
+                        "Select a room to start messaging"
+                    },
+                    "",
+                )
+                .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
+                .padding(composer_padding)
+                .into()
+            };
 
         let composer = Container::new(
             row![
-                TextInput::new("Message", &self.compose_value)
-                    .on_input(ClientMessage::ComposerTyped)
-                    .style(theme::TextInput::Custom(Box::new(style::TextInputComposer)))
-                    .on_submit(ClientMessage::MessageSubmitted)
-                    .padding(Padding {
-                        top: 12.0,
-                        right: 12.0,
-                        bottom: 12.0,
-                        left: 15.0,
-                    }),
+                compose_input,
+                Button::new(
+                    image(format!(
+                        "{}/resources/emoji.png",
+                        env!("CARGO_MANIFEST_DIR")
+                    ))
+                    .width(20)
+                    .height(20),
+                )
+                .padding(12)
+                .on_press_maybe(
+                    room_joined.then_some(ClientMessage::EmojiPickerToggled(EmojiTarget::Composer))
+                )
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
+                Button::new(
+                    svg::Svg::from_path(format!(
+                        "{}/resources/sticker.svg",
+                        env!("CARGO_MANIFEST_DIR"),
+                    ))
+                    .width(20)
+                    .height(20)
+                    .style(theme::Svg::custom_fn(|_theme| svg::Appearance {
+                        color: Some(color!(0xffffff)),
+                    })),
+                )
+                .padding(12)
+                .on_press_maybe(room_joined.then_some(ClientMessage::StickerPickerToggled))
+                .style(theme::Button::Custom(Box::new(style::ButtonRoomItem))),
                 Button::new(
                     svg::Svg::from_path(format!(
                         "{}/resources/send.svg",
@@ -288,47 +5442,101 @@ impl Application for Client {
                     bottom: 12.0,
                     left: 14.0,
                 })
-                .on_press(ClientMessage::MessageSubmitted)
+                .on_press_maybe(room_joined.then_some(ClientMessage::MessageSubmitted))
                 .style(theme::Button::Custom(Box::new(style::ButtonComposerSend))),
             ]
-            .align_items(iced::Alignment::Center)
+            .align_items(iced::Alignment::End)
             .spacing(8),
         )
         .width(Length::Fill);
 
-        let room = column![infobar, timeline, composer].spacing(16);
+        let room: iced::Element<'_, Self::Message, Self::Theme, iced::Renderer> = match &self.dialog
+        {
+            Some(Dialog::NewRoom(form)) => self.view_new_room(form),
+            Some(Dialog::NewDm(form)) => self.view_new_dm(form),
+            Some(Dialog::JoinRoom(form)) => self.view_join_room(form),
+            Some(Dialog::PowerLevels(form)) => self.view_power_levels(form),
+            Some(Dialog::RoomSettings(form)) => self.view_room_settings(form),
+            Some(Dialog::Devtools(form)) => self.view_devtools(form),
+            Some(Dialog::ImagePack(form)) => self.view_image_pack(form),
+            None => column![infobar]
+                .push_maybe(confirmation)
+                .push_maybe(room_error)
+                .push(timeline)
+                .push_maybe(self.view_composer_hints())
+                .push_maybe(
+                    self.emoji_picker
+                        .as_ref()
+                        .filter(|_| room_joined)
+                        .map(|picker| self.view_emoji_picker(picker)),
+                )
+                .push_maybe(
+                    self.sticker_picker
+                        .filter(|_| room_joined)
+                        .map(|selected| self.view_sticker_picker(selected)),
+                )
+                .push_maybe(self.view_pills())
+                .push(composer)
+                .spacing(16)
+                .into(),
+        };
 
         let room_list: Vec<
             iced::advanced::graphics::core::Element<'_, Self::Message, Self::Theme, iced::Renderer>,
         > = match &self.client {
             Some(client) => client
-                .rooms()
+                .joined_rooms()
                 .into_iter()
                 .map(|room| {
-                    Button::new(Text::new(room.name().unwrap_or_else(|| {
-                        room.direct_targets()
-                            .iter()
-                            .map(|id| id.to_string())
-                            .collect::<Vec<String>>()
-                            .join(", ")
-                    })))
+                    Button::new(
+                        row![
+                            self.view_room_avatar(&room, 24),
+                            Text::new(room_name(&room))
+                        ]
+                        .align_items(iced::Alignment::Center)
+                        .spacing(8),
+                    )
                     .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
                     .on_press(ClientMessage::RoomChanged(room.room_id().into()))
                     .into()
                 })
                 .collect(),
-            None => vec![].into(),
+            None => vec![],
         };
 
-        let rooms = Scrollable::new(column(room_list).spacing(16))
+        let rooms = column![
+            row![
+                Button::new(Text::new("New room"))
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+                    .on_press(ClientMessage::NewRoomOpened),
+                Button::new(Text::new("New DM"))
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+                    .on_press(ClientMessage::NewDmOpened),
+                Button::new(Text::new("Join"))
+                    .style(theme::Button::Custom(Box::new(style::ButtonRoomItem)))
+                    .on_press(ClientMessage::JoinRoomOpened),
+            ]
+            .spacing(8),
+            Scrollable::new(
+                column![]
+                    .push_maybe(self.view_invites())
+                    .push(column(room_list).spacing(16))
+                    .push_maybe(self.view_archive())
+                    .spacing(16)
+            )
             .direction(scrollable::Direction::Vertical(
                 Properties::new().width(0).scroller_width(0),
             ))
             .style(theme::Scrollable::Custom(Box::new(
                 style::ScrollableRoomList,
-            )));
+            ))),
+        ]
+        .spacing(16);
 
-        let content = row![rooms, room].spacing(16);
+        let members = (self.show_members && selected_room.is_some() && self.dialog.is_none())
+            .then(|| self.view_members());
+
+        let content = row![rooms, room].push_maybe(members).spacing(16);
 
         Container::new(content)
             .width(Length::Fill)
@@ -352,44 +5560,478 @@ impl Application for Client {
     }
 
     fn subscription(&self) -> iced::Subscription<Self::Message> {
-        if let Some(receiver) = &self.command_receiver {
-            iced::Subscription::from_recipe(PollMessages {
-                receiver: Arc::clone(receiver),
-            })
+        let modifiers = event::listen_with(|event, _status| match event {
+            iced::Event::Keyboard(keyboard::Event::ModifiersChanged(modifiers)) => {
+                Some(ClientMessage::ModifiersChanged(modifiers))
+            }
+            _ => None,
+        });
+
+        match &self.client {
+            Some(client) => iced::Subscription::batch(vec![
+                matrix::event_loop(client.clone(), self.sync_token.clone(), self.login_count),
+                modifiers,
+            ]),
+            None => modifiers,
+        }
+    }
+}
+
+/// Describes how long ago something happened, e.g. "5 min ago".
+fn format_ago(ago: std::time::Duration) -> String {
+    let minutes = ago.as_secs() / 60;
+
+    match minutes {
+        0 => "just now".to_owned(),
+        1..=59 => format!("{minutes} min ago"),
+        60..=1439 => format!("{} h ago", minutes / 60),
+        _ => format!("{} days ago", minutes / 1440),
+    }
+}
+
+/// The name of `room`, or the users it is a DM with if it has none.
+fn room_name(room: &Room) -> String {
+    room.name().unwrap_or_else(|| {
+        room.direct_targets()
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    })
+}
+
+/// The first letter of up to two words of `name`, skipping sigils like the
+/// `@` of user IDs.
+fn initials(name: &str) -> String {
+    name.split_whitespace()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()))
+        .take(2)
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// A color for the avatar placeholder of a room, always the same for an ID.
+fn avatar_color(id: &str) -> Color {
+    const COLORS: [Color; 6] = [
+        color!(0x0dbd8b),
+        color!(0x368bd6),
+        color!(0xac3ba8),
+        color!(0xe64f7a),
+        color!(0xff812d),
+        color!(0x2dc2c5),
+    ];
+
+    let hash = id.bytes().fold(0u32, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte.into())
+    });
+
+    COLORS[hash as usize % COLORS.len()]
+}
+
+/// `topic` on a single line, with the links in it opening in the browser.
+fn view_topic<'a>(topic: &str) -> iced::Element<'a, ClientMessage, Theme, iced::Renderer> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+
+    for word in topic.split_whitespace() {
+        if word.starts_with("https://") || word.starts_with("http://") {
+            if !text.is_empty() {
+                text.push(' ');
+                segments.push(Text::new(std::mem::take(&mut text)).size(12).into());
+            }
+            segments.push(
+                Button::new(Text::new(word.to_owned()).size(12))
+                    .padding(0)
+                    .on_press(ClientMessage::LinkOpened(word.to_owned()))
+                    .style(theme::Button::Custom(Box::new(style::ButtonLink)))
+                    .into(),
+            );
+            text.push(' ');
         } else {
-            iced::Subscription::none()
+            if !text.is_empty() || !segments.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(word);
         }
     }
+    if !text.trim().is_empty() {
+        segments.push(Text::new(text).size(12).into());
+    }
+
+    row(segments).into()
 }
 
-struct PollMessages {
-    receiver: Arc<Mutex<Receiver<ClientMessage>>>,
+/// A compact line describing a change of the room, such as "alice joined".
+fn view_change(msg: &Message) -> iced::Element<'_, ClientMessage, Theme, iced::Renderer> {
+    // Indented to line up with the text of the messages around it.
+    Container::new(Text::new(&msg.contents).size(12).style(color!(0xa6adc8)))
+        .padding(Padding::from([0, 0, 0, 40]))
+        .into()
 }
 
-impl iced::advanced::subscription::Recipe for PollMessages {
-    type Output = ClientMessage;
+/// The timeline of `room_id` out of the messages of all rooms, with a
+/// separator above each day and consecutive messages of a sender grouped.
+fn timeline_items<'a>(messages: &'a [Message], room_id: &str) -> Vec<TimelineItem<'a>> {
+    let messages = messages
+        .iter()
+        .filter(|msg| msg.room_id == room_id)
+        .collect::<Vec<_>>();
+    let mut items = Vec::new();
+    let mut previous_sender = None;
+    let mut previous_day = None;
+    let mut index = 0;
+
+    while let Some(&msg) = messages.get(index) {
+        let day = msg.timestamp.date_naive();
+        if previous_day != Some(day) {
+            items.push(TimelineItem::Day(day));
+            previous_day = Some(day);
+            previous_sender = None;
+        }
+
+        if msg.kind.is_state() {
+            let changes = messages[index..]
+                .iter()
+                .copied()
+                .take_while(|other| other.kind.is_state() && other.timestamp.date_naive() == day)
+                .collect::<Vec<_>>();
+            index += changes.len();
+            previous_sender = None;
+            items.push(TimelineItem::Changes(changes));
+            continue;
+        }
 
-    fn hash(&self, state: &mut Hasher) {
-        std::any::TypeId::of::<Self>().hash(state);
+        // Consecutive messages of a sender on the same day are grouped under
+        // a single header.
+        let first = previous_sender != Some(&msg.sender_id);
+        previous_sender = Some(&msg.sender_id);
+        items.push(TimelineItem::Message(msg, first));
+        index += 1;
     }
 
-    fn stream(
-        self: Box<Self>,
-        _input: iced::advanced::subscription::EventStream,
-    ) -> iced::advanced::graphics::futures::BoxStream<Self::Output> {
-        use iced::futures::StreamExt;
+    items
+}
 
-        let receiver = self.receiver.clone();
+/// The label of the separator above the messages of `day`.
+fn day_label(day: NaiveDate, today: NaiveDate) -> String {
+    if day == today {
+        "Today".to_owned()
+    } else if today.checked_sub_days(Days::new(1)) == Some(day) {
+        "Yesterday".to_owned()
+    } else {
+        day.format("%A, %B %-d, %Y").to_string()
+    }
+}
 
-        let stream = iced::futures::stream::unfold(receiver, |receiver| async move {
-            let message = {
-                let receiver = receiver.lock().unwrap();
-                receiver.recv().ok()
-            };
+/// Moves the `pills` of the composer along with the edit that turned `old`
+/// into `new`.
+///
+/// The edit replaced what lies between the start and the end the old and new
+/// text have in common. Pills after it move along, pills it touched are
+/// dropped.
+fn move_pills(pills: &mut Vec<(usize, Pill)>, old: &str, new: &str) {
+    let prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(old.len().min(new.len()), |((index, _), _)| index);
+    let suffix: usize = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum();
+    let edit_end = old.len() - suffix;
 
-            message.map(|msg| (msg, receiver))
-        });
+    pills.retain_mut(|(offset, pill)| {
+        if *offset + pill.label.len() <= prefix {
+            true
+        } else if *offset >= edit_end {
+            *offset = *offset + new.len() - old.len();
+            true
+        } else {
+            false
+        }
+    });
+}
+
+/// The `pills` of the composer by how far they start from the end of `typed`,
+/// ignoring trailing whitespace.
+///
+/// The body of a message sent with a command is the end of what was typed, so
+/// this finds the pills in it again.
+fn pills_from_end(typed: &str, pills: Vec<(usize, Pill)>) -> Vec<(usize, Pill)> {
+    let end = typed.trim_end().len();
+    pills
+        .into_iter()
+        .map(|(offset, pill)| (end.saturating_sub(offset), pill))
+        .collect()
+}
+
+/// Where in `body` the `pills`, given by [`pills_from_end`], start. Pills
+/// whose label isn't there anymore are left out.
+fn locate_pills<'a>(body: &str, pills: &'a [(usize, Pill)]) -> Vec<(usize, &'a Pill)> {
+    let end = body.trim_end().len();
+    pills
+        .iter()
+        .filter_map(|(from_end, pill)| {
+            let index = end.checked_sub(*from_end)?;
+            body.get(index..)?
+                .starts_with(&pill.label)
+                .then_some((index, pill))
+        })
+        .collect()
+}
+
+/// The mention being typed at the end of `input`, e.g. `@ali`.
+fn mention_query(input: &str) -> Option<&str> {
+    let word = input.rsplit(char::is_whitespace).next()?;
+    (word.starts_with('@') || word.starts_with('#')).then_some(word)
+}
+
+/// The HTML of `body`, with spans of it, given by their index and length,
+/// replaced with the HTML given for them.
+fn rich_html(body: &str, mut spans: Vec<(usize, usize, String)>) -> String {
+    spans.sort_by_key(|(index, _, _)| *index);
+
+    let mut html = String::new();
+    let mut rest = 0;
+    for (index, len, span) in spans {
+        // Skip spans overlapping an earlier one, e.g. a pill whose label is
+        // part of an earlier one.
+        if index < rest {
+            continue;
+        }
+
+        html.push_str(&text_html(&body[rest..index]));
+        html.push_str(&span);
+        rest = index + len;
+    }
+    html.push_str(&text_html(&body[rest..]));
+
+    html
+}
+
+/// Escapes `text`, keeping its line breaks.
+fn text_html(text: &str) -> String {
+    escape_html(text).replace('\n', "<br>")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(room_id: &str, sender_id: &str, kind: MessageKind, minute: u32) -> Message {
+        Message {
+            room_id: OwnedRoomId::from_str(room_id).unwrap(),
+            event_id: None,
+            sender_id: OwnedUserId::from_str(sender_id).unwrap(),
+            sender: sender_id.to_owned(),
+            sender_avatar: None,
+            kind,
+            contents: format!("{kind:?} at {minute}"),
+            timestamp: Local::now()
+                .date_naive()
+                .and_hms_opt(12, minute, 0)
+                .unwrap()
+                .and_local_timezone(Local)
+                .unwrap(),
+            emotes: Vec::new(),
+            sticker: None,
+            outgoing: None,
+        }
+    }
+
+    /// The timeline as text: messages with whether they start a group, runs
+    /// of room changes by their length.
+    fn layout(items: &[TimelineItem]) -> Vec<String> {
+        items
+            .iter()
+            .map(|item| match item {
+                TimelineItem::Day(_) => "day".to_owned(),
+                TimelineItem::Message(msg, first) => format!("{} {first}", msg.contents),
+                TimelineItem::Changes(changes) => format!("{} changes", changes.len()),
+            })
+            .collect()
+    }
+
+    fn pill(label: &str) -> Pill {
+        Pill {
+            label: label.to_owned(),
+            link: format!("https://matrix.to/#/{label}"),
+            user_id: None,
+        }
+    }
+
+    /// `pills` moved along with the edit from `old` to `new`, as
+    /// `(offset, label)`.
+    fn moved(old: &str, new: &str, pills: &[&str]) -> Vec<(usize, String)> {
+        let mut pills = pills
+            .iter()
+            .map(|label| (old.find(label).unwrap(), pill(label)))
+            .collect();
+        move_pills(&mut pills, old, new);
+        pills
+            .into_iter()
+            .map(|(offset, pill)| (offset, pill.label))
+            .collect()
+    }
+
+    /// Where the pills typed in `typed` end up in the body of the message it
+    /// sends.
+    fn sent(typed: &str, pills: &[(usize, &str)]) -> (String, Vec<usize>) {
+        let pills = pills
+            .iter()
+            .map(|(offset, label)| (*offset, pill(label)))
+            .collect();
+        let pills = pills_from_end(typed, pills);
+        let body = match commands::parse(typed) {
+            Some(Ok(Action::Send { body, .. })) => body,
+            None => typed.to_owned(),
+            other => panic!("`{typed}` doesn't send a message: {other:?}"),
+        };
+        let offsets = locate_pills(&body, &pills)
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .collect();
+        (body, offsets)
+    }
+
+    #[test]
+    fn pills_move_with_edits_before_them() {
+        assert_eq!(
+            moved("hi @Al and @Bo ", "oh, hi @Al and @Bo ", &["@Al", "@Bo"]),
+            [(7, "@Al".to_owned()), (15, "@Bo".to_owned())]
+        );
+        assert_eq!(
+            moved("hi @Al and @Bo ", "@Al and @Bo ", &["@Al", "@Bo"]),
+            [(0, "@Al".to_owned()), (8, "@Bo".to_owned())]
+        );
+        assert_eq!(
+            moved("hi @Al and @Bo ", "hi @Al or @Bo ", &["@Al", "@Bo"]),
+            [(3, "@Al".to_owned()), (10, "@Bo".to_owned())]
+        );
+    }
+
+    #[test]
+    fn pills_stay_with_edits_after_them() {
+        assert_eq!(
+            moved("hi @Al ", "hi @Al, how are you?", &["@Al"]),
+            [(3, "@Al".to_owned())]
+        );
+        assert_eq!(
+            moved("hi @Al ", "hi @Al", &["@Al"]),
+            [(3, "@Al".to_owned())]
+        );
+    }
+
+    #[test]
+    fn pills_are_dropped_with_edits_inside_them() {
+        assert!(moved("hi @Alice ", "hi @Alce ", &["@Alice"]).is_empty());
+        assert!(moved("hi @Alice ", "hi ", &["@Alice"]).is_empty());
+        assert!(moved("hi @Alice ", "hi @Al-ice ", &["@Alice"]).is_empty());
+        assert_eq!(
+            moved("@Al @Bo ", "@Al @B ", &["@Al", "@Bo"]),
+            [(0, "@Al".to_owned())]
+        );
+    }
+
+    #[test]
+    fn pills_move_by_bytes_in_multibyte_text() {
+        assert_eq!(
+            moved("héllo @Zoë ", "héllo 😀 @Zoë ", &["@Zoë"]),
+            [(12, "@Zoë".to_owned())]
+        );
+        assert_eq!(
+            moved("😀😀 @Zoë ", "😀 @Zoë ", &["@Zoë"]),
+            [(5, "@Zoë".to_owned())]
+        );
+        assert_eq!(
+            moved("@Zoë ", "@Zoë ünd ", &["@Zoë"]),
+            [(0, "@Zoë".to_owned())]
+        );
+        // Changing a character sharing its first byte with the original.
+        assert!(moved("@Zoë ", "@Zoé ", &["@Zoë"]).is_empty());
+    }
+
+    #[test]
+    fn pills_are_found_in_the_sent_body() {
+        // The first match of the label isn't necessarily the pill.
+        assert_eq!(
+            sent("Also @Al ", &[(5, "@Al")]),
+            ("Also @Al ".to_owned(), vec![5])
+        );
+        assert_eq!(
+            sent("@Al 😀 @Al", &[(9, "@Al")]),
+            ("@Al 😀 @Al".to_owned(), vec![9])
+        );
+    }
+
+    #[test]
+    fn pills_are_found_in_command_bodies() {
+        assert_eq!(
+            sent("/me  waves at @Al and @Zoë  ", &[(14, "@Al"), (22, "@Zoë")]),
+            ("waves at @Al and @Zoë".to_owned(), vec![9, 17])
+        );
+        assert_eq!(
+            sent("//me and @Al ", &[(9, "@Al")]),
+            ("/me and @Al ".to_owned(), vec![8])
+        );
+        assert_eq!(
+            sent("/shrug @Al", &[(7, "@Al")]),
+            ("¯\\_(ツ)_/¯ @Al".to_owned(), vec![14])
+        );
+    }
+
+    #[test]
+    fn timeline_only_shows_the_open_room() {
+        let messages = vec![
+            message("!a:example.org", "@alice:example.org", MessageKind::Text, 0),
+            message("!b:example.org", "@bob:example.org", MessageKind::Text, 1),
+            message("!a:example.org", "@alice:example.org", MessageKind::Text, 2),
+            message(
+                "!b:example.org",
+                "@bob:example.org",
+                MessageKind::Membership,
+                3,
+            ),
+            message(
+                "!a:example.org",
+                "@bob:example.org",
+                MessageKind::Membership,
+                4,
+            ),
+            message(
+                "!b:example.org",
+                "@bob:example.org",
+                MessageKind::Membership,
+                5,
+            ),
+            message(
+                "!a:example.org",
+                "@bob:example.org",
+                MessageKind::Membership,
+                6,
+            ),
+            message("!b:example.org", "@bob:example.org", MessageKind::Text, 7),
+        ];
 
-        stream.boxed()
+        assert_eq!(
+            layout(&timeline_items(&messages, "!a:example.org")),
+            ["day", "Text at 0 true", "Text at 2 false", "2 changes",]
+        );
+        assert_eq!(
+            layout(&timeline_items(&messages, "!b:example.org")),
+            ["day", "Text at 1 true", "2 changes", "Text at 7 true",]
+        );
+        assert!(timeline_items(&messages, "!c:example.org").is_empty());
     }
 }
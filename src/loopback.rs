@@ -0,0 +1,157 @@
+use std::{net::Ipv4Addr, process::Command, time::Duration};
+
+use anyhow::Context;
+use log::{info, warn};
+use matrix_sdk::reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// How long we wait for the browser to come back before giving up.
+const REDIRECT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Largest request head we are willing to read from the browser.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+const RESPONSE_BODY: &str = concat!(
+    "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>",
+    env!("CARGO_PKG_NAME"),
+    "</title></head><body><p>You are now logged in, you can close this window and return to ",
+    env!("CARGO_PKG_NAME"),
+    ".</p></body></html>"
+);
+
+/// A minimal HTTP listener on the loopback interface, waiting for the browser
+/// to be redirected back to us at the end of a browser based login.
+pub(crate) struct RedirectListener {
+    listener: TcpListener,
+    uri: Url,
+}
+
+impl RedirectListener {
+    /// Binds to a random free port on `127.0.0.1`.
+    pub async fn bind() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let uri = Url::parse(&format!("http://{}:{port}/", Ipv4Addr::LOCALHOST))?;
+
+        Ok(Self { listener, uri })
+    }
+
+    /// The URI the browser should be redirected to.
+    pub fn uri(&self) -> &Url {
+        &self.uri
+    }
+
+    /// Waits for the redirect and returns the full URL the browser requested.
+    pub async fn wait(self) -> anyhow::Result<Url> {
+        self.wait_for(REDIRECT_TIMEOUT).await
+    }
+
+    async fn wait_for(self, timeout: Duration) -> anyhow::Result<Url> {
+        tokio::time::timeout(timeout, self.accept())
+            .await
+            .context("Timed out waiting for the browser")?
+    }
+
+    async fn accept(&self) -> anyhow::Result<Url> {
+        loop {
+            let (mut stream, _) = self.listener.accept().await?;
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await?;
+                if read == 0 || request.len() > MAX_REQUEST_SIZE {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let request = String::from_utf8_lossy(&request);
+            let target = request
+                .lines()
+                .next()
+                .and_then(|line| line.strip_prefix("GET "))
+                .and_then(|line| line.split(' ').next());
+
+            // Browsers also ask for things like a favicon, those are not the
+            // redirect we are waiting for.
+            let Some(target) = target.filter(|target| target.contains('?')) else {
+                stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                    .await?;
+                continue;
+            };
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                RESPONSE_BODY.len(),
+                RESPONSE_BODY
+            );
+            stream.write_all(response.as_bytes()).await?;
+
+            return Ok(self.uri.join(target)?);
+        }
+    }
+}
+
+/// Opens `url` in the default browser of the system.
+pub(crate) fn open_in_browser(url: &Url) {
+    #[cfg(target_os = "macos")]
+    let mut command = Command::new("open");
+    // Unlike `cmd /C start`, this doesn't cut the URL off at its first `&`.
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = Command::new("rundll32");
+        command.arg("url.dll,FileProtocolHandler");
+        command
+    };
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let mut command = Command::new("xdg-open");
+
+    info!("Opening {url} in the browser");
+
+    if let Err(error) = command.arg(url.as_str()).spawn() {
+        warn!("Failed to open the browser: {error}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn returns_the_redirect() {
+        let listener = RedirectListener::bind().await.unwrap();
+        let uri = listener.uri().clone();
+        let redirect = tokio::spawn(listener.wait());
+
+        let favicon = matrix_sdk::reqwest::get(uri.join("favicon.ico").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(favicon.status(), 404);
+
+        let response = matrix_sdk::reqwest::get(uri.join("?loginToken=abc").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), RESPONSE_BODY);
+
+        let redirect = redirect.await.unwrap().unwrap();
+        assert_eq!(redirect, uri.join("?loginToken=abc").unwrap());
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_redirect() {
+        let listener = RedirectListener::bind().await.unwrap();
+
+        let error = listener
+            .wait_for(Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Timed out waiting for the browser");
+    }
+}
@@ -1,70 +1,2581 @@
 use std::{
+    any::TypeId,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    fmt,
     path::{Path, PathBuf},
-    sync::mpsc::Sender,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use chrono::Local;
-use log::info;
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use iced::futures::{channel::mpsc::Sender, SinkExt};
+use log::{info, warn};
 use matrix_sdk::{
     config::SyncSettings,
-    matrix_auth::MatrixSession,
+    deserialized_responses::RawAnySyncOrStrippedState,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    media::{MediaFormat, MediaRequest, MediaThumbnailSize},
+    oidc::{
+        self,
+        types::{
+            client_credentials::ClientCredentials,
+            iana::oauth::OAuthClientAuthenticationMethod,
+            oidc::ApplicationType,
+            registration::{ClientMetadata, Localized, VerifiedClientMetadata},
+            requests::GrantType,
+        },
+        AuthorizationResponse,
+    },
+    reqwest::Url,
+    room::RoomMember,
     ruma::{
-        api::client::filter::FilterDefinition,
-        events::room::message::{MessageType, OriginalSyncRoomMessageEvent},
+        api::client::{
+            account::{register, request_registration_token_via_email},
+            alias::create_alias,
+            directory::get_public_rooms_filtered,
+            error::{ErrorBody, ErrorKind},
+            filter::FilterDefinition,
+            media::get_content_thumbnail::v3::Method,
+            room::{create_room, Visibility},
+            session::get_login_types::v3::{IdentityProvider, LoginType},
+            state::get_state_events,
+            uiaa::{AuthData, AuthType, Dummy, RegistrationToken, UiaaInfo},
+        },
+        directory::Filter,
+        events::{
+            presence::PresenceEvent,
+            reaction::{OriginalSyncReactionEvent, ReactionEventContent},
+            relation::Annotation,
+            room::{
+                canonical_alias::RoomCanonicalAliasEventContent,
+                encryption::RoomEncryptionEventContent,
+                guest_access::{GuestAccess, RoomGuestAccessEventContent},
+                history_visibility::{self, RoomHistoryVisibilityEventContent},
+                join_rules::{self, AllowRule, Restricted, RoomJoinRulesEventContent},
+                member::{MembershipChange, MembershipState, OriginalSyncRoomMemberEvent},
+                message::{MessageFormat, MessageType, OriginalSyncRoomMessageEvent},
+                power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+                ImageInfo, MediaSource,
+            },
+            sticker::{OriginalSyncStickerEvent, StickerEventContent},
+            AnyGlobalAccountDataEventContent, AnySyncStateEvent, AnySyncTimelineEvent,
+            InitialStateEvent, StateEventType, SyncStateEvent, TimelineEventType,
+        },
+        matrix_uri::MatrixId,
+        presence::PresenceState,
+        serde::Raw,
+        ClientSecret, EventId, Int, MatrixToUri, MatrixUri, MilliSecondsSinceUnixEpoch,
+        OwnedClientSecret, OwnedEventId, OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId,
+        OwnedRoomOrAliasId, OwnedServerName, OwnedSessionId, OwnedTransactionId, OwnedUserId,
+        RoomAliasId, RoomId, ServerName, UInt, UserId,
     },
-    Client, Error, LoopCtrl, Room, RoomState,
+    AuthApi, Client, Error, HttpError, Room, RoomMemberships, RoomState, SessionChange,
+    SessionMeta,
 };
 use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{loopback::RedirectListener, room_name, ClientMessage, Message, MessageKind, Reaction};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ClientSession {
+    homeserver: String,
+    db_path: PathBuf,
+    passphrase: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FullSession {
+    client_session: ClientSession,
+    user_session: UserSession,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sync_token: Option<String>,
+}
+
+/// The authentication data of a session, depending on how we logged in.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum UserSession {
+    // Tried first, as an OIDC session also has all the fields of a Matrix one.
+    Oidc(Box<OidcSession>),
+    Matrix(MatrixSession),
+}
+
+impl UserSession {
+    fn user_id(&self) -> &UserId {
+        match self {
+            UserSession::Oidc(session) => &session.user.meta.user_id,
+            UserSession::Matrix(session) => &session.meta.user_id,
+        }
+    }
+}
+
+/// An OIDC user session along with the client registration it belongs to.
+#[derive(Debug, Serialize, Deserialize)]
+struct OidcSession {
+    client_id: String,
+    #[serde(deserialize_with = "deserialize_client_metadata")]
+    client_metadata: VerifiedClientMetadata,
+    #[serde(flatten)]
+    user: oidc::UserSession,
+}
+
+fn deserialize_client_metadata<'de, D>(deserializer: D) -> Result<VerifiedClientMetadata, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    ClientMetadata::deserialize(deserializer)?
+        .validate()
+        .map_err(serde::de::Error::custom)
+}
+
+/// An outgoing message that has not been acknowledged by the homeserver yet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct QueuedMessage {
+    pub txn_id: OwnedTransactionId,
+    pub room_id: String,
+    pub body: String,
+    /// Whether the body describes an action, as sent by `/me`.
+    #[serde(default)]
+    pub emote: bool,
+    /// The body with mentions linked, if it has any.
+    #[serde(default)]
+    pub html: Option<String>,
+    /// Users mentioned on purpose, the only ones whose clients highlight it.
+    #[serde(default)]
+    pub mentions: Vec<OwnedUserId>,
+}
+
+/// Reasons an outgoing message could not be delivered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SendError {
+    NoRoomSelected,
+    RoomLeft,
+    Network,
+    RateLimited { retry_after: Option<Duration> },
+    Forbidden,
+    Other(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NoRoomSelected => write!(f, "No room selected"),
+            SendError::RoomLeft => write!(f, "You are no longer in this room"),
+            SendError::Network => write!(f, "Could not reach the homeserver"),
+            SendError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "Rate limited, retrying in {}s",
+                retry_after.as_secs().max(1)
+            ),
+            SendError::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            SendError::Forbidden => write!(f, "You are not allowed to send messages here"),
+            SendError::Other(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<Error> for SendError {
+    fn from(error: Error) -> Self {
+        match error.client_api_error_kind() {
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
+                return SendError::RateLimited {
+                    retry_after: *retry_after_ms,
+                }
+            }
+            Some(ErrorKind::Forbidden) => return SendError::Forbidden,
+            _ => {}
+        }
+
+        match error {
+            Error::Http(HttpError::Reqwest(_)) => SendError::Network,
+            Error::WrongRoomState(_) => SendError::RoomLeft,
+            error => SendError::Other(error.to_string()),
+        }
+    }
+}
+
+pub(crate) struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Restores the previous session, or logs in with `credentials` when there is
+/// none. Returns `None` if there is nothing to log in with.
+pub async fn run(
+    credentials: Option<Credentials>,
+) -> anyhow::Result<Option<(Client, Option<String>)>> {
+    let data_dir = Path::new("data");
+    let session_file = data_dir.join("session");
+
+    let (client, sync_token) = if session_file.exists() {
+        restore_session(&session_file).await?
+    } else if let Some(credentials) = credentials {
+        (login(credentials, data_dir, &session_file).await?, None)
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some((client, sync_token)))
+}
+
+/// How the homeserver invalidated our access token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SessionInvalidated {
+    /// The token expired, logging in again with the same device keeps our keys.
+    SoftLogout,
+    /// The device was logged out, e.g. from another client.
+    LoggedOut,
+}
+
+impl SessionInvalidated {
+    fn from_error(error: &Error) -> Option<Self> {
+        match error.client_api_error_kind()? {
+            ErrorKind::UnknownToken { soft_logout: true } => Some(Self::SoftLogout),
+            ErrorKind::UnknownToken { soft_logout: false } => Some(Self::LoggedOut),
+            _ => None,
+        }
+    }
+}
+
+/// Logs out from the homeserver and removes all local data of the session.
+pub async fn logout(client: Client) -> anyhow::Result<()> {
+    // An OIDC session is revoked at its provider, not at the homeserver.
+    let result = match client.auth_api() {
+        Some(AuthApi::Oidc(oidc)) => oidc.logout().await.map(|_| ()).map_err(anyhow::Error::from),
+        _ => client
+            .matrix_auth()
+            .logout()
+            .await
+            .map(|_| ())
+            .map_err(anyhow::Error::from),
+    };
+    if let Err(error) = result {
+        // The local data is removed anyway, we don't want to stay logged in
+        // just because the homeserver is unreachable.
+        warn!("Error logging out from the homeserver: {error}");
+    }
+
+    remove_session().await
+}
+
+/// Removes the persisted session, its store, the outbox, the drafts and the
+/// recently used emoji.
+pub async fn remove_session() -> anyhow::Result<()> {
+    let data_dir = Path::new("data");
+    let session_file = data_dir.join("session");
+    let outbox_file = data_dir.join("outbox");
+    let drafts_file = data_dir.join("drafts");
+    let recent_emoji_file = data_dir.join("recent_emoji");
+
+    let session_lock = SESSION_LOCK.lock().await;
+    if session_file.exists() {
+        let serialized_session = fs::read_to_string(&session_file).await?;
+        let full_session: FullSession = serde_json::from_str(&serialized_session)?;
+
+        if full_session.client_session.db_path.exists() {
+            fs::remove_dir_all(&full_session.client_session.db_path).await?;
+        }
+        fs::remove_file(&session_file).await?;
+    }
+    drop(session_lock);
+
+    // Snapshots taken before the logout must not bring the outbox back.
+    let mut written = OUTBOX_WRITTEN.lock().await;
+    *written = OUTBOX_REVISION.load(Ordering::SeqCst) - 1;
+    if outbox_file.exists() {
+        fs::remove_file(&outbox_file).await?;
+    }
+    drop(written);
+
+    if drafts_file.exists() {
+        fs::remove_file(&drafts_file).await?;
+    }
+
+    if recent_emoji_file.exists() {
+        fs::remove_file(&recent_emoji_file).await?;
+    }
+
+    info!("Session removed");
+
+    Ok(())
+}
+
+/// Logs in again after a soft logout, reusing the device and its crypto store.
+pub async fn reauthenticate(password: String) -> anyhow::Result<(Client, Option<String>)> {
+    let data_dir = Path::new("data");
+    let session_file = data_dir.join("session");
+
+    let serialized_session = fs::read_to_string(&session_file).await?;
+    let FullSession {
+        client_session,
+        user_session,
+        sync_token,
+    } = serde_json::from_str(&serialized_session)?;
+
+    let UserSession::Matrix(user_session) = user_session else {
+        anyhow::bail!("This session can only be renewed through your identity provider");
+    };
+
+    let client = Client::builder()
+        .homeserver_url(&client_session.homeserver)
+        .sqlite_store(&client_session.db_path, Some(&client_session.passphrase))
+        .handle_refresh_tokens()
+        .build()
+        .await?;
+
+    info!("Logging in again as {}…", user_session.meta.user_id);
+
+    client
+        .matrix_auth()
+        .login_username(&user_session.meta.user_id, &password)
+        .device_id(user_session.meta.device_id.as_str())
+        .initial_device_display_name(env!("CARGO_PKG_NAME"))
+        .await?;
+
+    let user_session = client
+        .matrix_auth()
+        .session()
+        .expect("A logged-in client should have a session");
+    update_session(&session_file, |full_session| {
+        full_session.user_session = UserSession::Matrix(user_session);
+    })
+    .await?;
+
+    Ok((client, sync_token))
+}
+
+/// The ways the homeserver of a user lets them log in.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LoginFlows {
+    pub password: bool,
+    /// Identity providers offered for single sign-on, `None` if SSO isn't
+    /// supported. Empty when the homeserver doesn't list them.
+    pub sso: Option<Vec<IdentityProvider>>,
+    /// Issuer of the OIDC provider the homeserver delegates authentication to.
+    pub oidc_issuer: Option<String>,
+}
+
+/// Asks the homeserver of `username` which login flows it supports.
+pub async fn discover_login_flows(username: String) -> anyhow::Result<LoginFlows> {
+    let server_name = server_name(&username)?;
+
+    let client = Client::builder()
+        .server_name(&server_name)
+        .build()
+        .await
+        .map_err(|error| anyhow::anyhow!("Error checking the homeserver: {error}"))?;
+
+    login_flows(&client).await
+}
+
+async fn login_flows(client: &Client) -> anyhow::Result<LoginFlows> {
+    let login_types = client.matrix_auth().get_login_types().await?;
+
+    Ok(LoginFlows {
+        password: login_types
+            .flows
+            .iter()
+            .any(|flow| matches!(flow, LoginType::Password(_))),
+        sso: login_types.flows.into_iter().find_map(|flow| match flow {
+            LoginType::Sso(sso) => Some(sso.identity_providers),
+            _ => None,
+        }),
+        oidc_issuer: client
+            .oidc()
+            .authentication_server_info()
+            .map(|info| info.issuer.clone()),
+    })
+}
+
+/// The store of a browser login that has not completed, removed when the login
+/// fails or is cancelled by dropping its future.
+#[derive(Default)]
+struct PendingStore(Option<PathBuf>);
+
+impl Drop for PendingStore {
+    fn drop(&mut self) {
+        if let Some(db_path) = self.0.take() {
+            let _ = std::fs::remove_dir_all(db_path);
+        }
+    }
+}
+
+/// Logs in through the OIDC provider of the homeserver, using the
+/// authorization code flow in the system browser.
+///
+/// The browser is redirected back to a listener on the loopback interface once
+/// the user has authorized us. `sender` is told which URL to open in the
+/// browser, which is also shown in case it has to be opened by hand.
+pub async fn login_oidc(username: String, sender: Sender<ClientMessage>) -> anyhow::Result<Client> {
+    let data_dir = Path::new("data");
+    let session_file = data_dir.join("session");
+
+    // Declared before the client, so the store is closed when it is removed.
+    let mut pending_store = PendingStore::default();
+    let (client, client_session) = build_client(&username, data_dir).await?;
+    pending_store.0 = Some(client_session.db_path.clone());
+
+    let user_session = authorize_oidc(&client, sender).await?;
+    persist_session(&session_file, client_session, user_session).await?;
+    pending_store.0 = None;
+
+    Ok(client)
+}
+
+/// Logs in through single sign-on in the system browser, optionally with a
+/// specific identity provider.
+///
+/// The homeserver redirects the browser back to a listener on the loopback
+/// interface with a login token, which is then exchanged for a session.
+pub async fn login_sso(
+    username: String,
+    identity_provider: Option<String>,
+    sender: Sender<ClientMessage>,
+) -> anyhow::Result<Client> {
+    let data_dir = Path::new("data");
+    let session_file = data_dir.join("session");
+
+    // Declared before the client, so the store is closed when it is removed.
+    let mut pending_store = PendingStore::default();
+    let (client, client_session) = build_client(&username, data_dir).await?;
+    pending_store.0 = Some(client_session.db_path.clone());
+
+    let user_session = authorize_sso(&client, identity_provider.as_deref(), sender).await?;
+    persist_session(&session_file, client_session, user_session).await?;
+    pending_store.0 = None;
+
+    Ok(client)
+}
+
+async fn authorize_sso(
+    client: &Client,
+    identity_provider: Option<&str>,
+    mut sender: Sender<ClientMessage>,
+) -> anyhow::Result<UserSession> {
+    let matrix_auth = client.matrix_auth();
+    let listener = RedirectListener::bind().await?;
+
+    let url = matrix_auth
+        .get_sso_login_url(listener.uri().as_str(), identity_provider)
+        .await?;
+    let url = Url::parse(&url)?;
+
+    sender.send(ClientMessage::BrowserLoginOpened(url)).await?;
+
+    let redirect = listener.wait().await?;
+    let login_token = redirect
+        .query_pairs()
+        .find_map(|(key, value)| (key == "loginToken").then(|| value.into_owned()))
+        .context("The homeserver did not send a login token")?;
+
+    matrix_auth
+        .login_token(&login_token)
+        .initial_device_display_name(env!("CARGO_PKG_NAME"))
+        .await?;
+
+    info!("Logged in as {}", client.user_id().unwrap());
+
+    Ok(UserSession::Matrix(
+        matrix_auth
+            .session()
+            .expect("A logged-in client should have a session"),
+    ))
+}
+
+async fn authorize_oidc(
+    client: &Client,
+    mut sender: Sender<ClientMessage>,
+) -> anyhow::Result<UserSession> {
+    let oidc = client.oidc();
+    let issuer_info = oidc
+        .authentication_server_info()
+        .cloned()
+        .context("The homeserver does not support OIDC")?;
+
+    let listener = RedirectListener::bind().await?;
+    let redirect_uri = listener.uri().clone();
+
+    let client_metadata = ClientMetadata {
+        application_type: Some(ApplicationType::Native),
+        redirect_uris: Some(vec![redirect_uri.clone()]),
+        grant_types: Some(vec![GrantType::AuthorizationCode, GrantType::RefreshToken]),
+        token_endpoint_auth_method: Some(OAuthClientAuthenticationMethod::None),
+        client_name: Some(Localized::new(env!("CARGO_PKG_NAME").to_owned(), [])),
+        client_uri: Some(Localized::new(
+            Url::parse(env!("CARGO_PKG_REPOSITORY"))?,
+            [],
+        )),
+        ..Default::default()
+    }
+    .validate()?;
+
+    let registration = oidc
+        .register_client(&issuer_info.issuer, client_metadata.clone(), None)
+        .await?;
+    info!("Registered OIDC client {}", registration.client_id);
+
+    oidc.restore_registered_client(
+        issuer_info,
+        client_metadata.clone(),
+        ClientCredentials::None {
+            client_id: registration.client_id.clone(),
+        },
+    );
+
+    let authorization = oidc.login(redirect_uri, None)?.build().await?;
+
+    sender
+        .send(ClientMessage::BrowserLoginOpened(authorization.url))
+        .await?;
+
+    let redirect = listener.wait().await?;
+
+    match AuthorizationResponse::parse_uri(&redirect)? {
+        AuthorizationResponse::Success(code) => oidc.finish_authorization(code).await?,
+        AuthorizationResponse::Error(error) => {
+            oidc.abort_authorization(&authorization.state).await;
+            anyhow::bail!("Authorization failed: {}", error.error.error);
+        }
+    }
+
+    oidc.finish_login().await?;
+
+    info!("Logged in as {}", client.user_id().unwrap());
+
+    Ok(UserSession::Oidc(Box::new(OidcSession {
+        client_id: registration.client_id,
+        client_metadata,
+        user: oidc
+            .user_session()
+            .expect("A logged-in client should have a session"),
+    })))
+}
+
+/// An account registration waiting for the user to complete a stage of the
+/// user-interactive authentication.
+#[derive(Clone, Debug)]
+pub(crate) struct Registration {
+    client: Client,
+    client_session: ClientSession,
+    localpart: String,
+    password: String,
+    uiaa_info: UiaaInfo,
+    client_secret: OwnedClientSecret,
+    email_sid: Option<OwnedSessionId>,
+    send_attempt: u32,
+}
+
+/// A registration stage that needs input from the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RegistrationStage {
+    /// The policies of the homeserver have to be accepted.
+    Terms(Vec<Policy>),
+    /// An email address has to be validated through a link sent to it.
+    EmailIdentity,
+    /// A token handed out by the homeserver admins has to be entered.
+    RegistrationToken,
+}
+
+/// A policy the homeserver asks new users to accept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Policy {
+    pub name: String,
+    pub url: String,
+}
+
+/// What the user answered to a [`RegistrationStage`].
+#[derive(Clone, Debug)]
+pub(crate) enum StageResponse {
+    AcceptTerms,
+    EmailValidated,
+    RegistrationToken(String),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum RegistrationProgress {
+    /// The homeserver wants another stage completed. `error` explains why the
+    /// previous attempt was refused, if it was.
+    Stage {
+        registration: Box<Registration>,
+        stage: RegistrationStage,
+        error: Option<String>,
+    },
+    Complete(Client),
+}
+
+const TERMS_AUTH_TYPE: &str = "m.login.terms";
+
+/// Starts registering `username`, a full user ID whose server part names the
+/// homeserver to register on.
+pub async fn register(username: String, password: String) -> anyhow::Result<RegistrationProgress> {
+    let data_dir = Path::new("data");
+
+    let localpart = username
+        .trim_start_matches('@')
+        .split_once(':')
+        .map(|(localpart, _)| localpart.to_owned())
+        .context("Expected a full user ID, e.g. `@alice:matrix.org`")?;
+    let (client, client_session) = build_client(&username, data_dir).await?;
+
+    info!("Registering {username}…");
+
+    let registration = Registration {
+        client,
+        client_session,
+        localpart,
+        password,
+        uiaa_info: UiaaInfo::new(Vec::new(), Default::default()),
+        client_secret: ClientSecret::new(),
+        email_sid: None,
+        send_attempt: 0,
+    };
+
+    // Nothing is left behind when the first attempt already fails.
+    let progress = continue_registration(registration.clone(), None).await;
+    if progress.is_err() {
+        abort_registration(registration).await?;
+    }
+
+    progress
+}
+
+/// Completes the current stage of `registration` with `response`.
+pub async fn complete_stage(
+    registration: Registration,
+    response: StageResponse,
+) -> anyhow::Result<RegistrationProgress> {
+    let session = registration.uiaa_info.session.clone();
+
+    let auth = match response {
+        StageResponse::AcceptTerms => AuthData::new(TERMS_AUTH_TYPE, session, Default::default())?,
+        StageResponse::EmailValidated => {
+            let sid = registration
+                .email_sid
+                .as_ref()
+                .context("No verification email was sent yet")?;
+
+            AuthData::new(
+                AuthType::EmailIdentity.as_str(),
+                session,
+                serde_json::from_value(serde_json::json!({
+                    "threepid_creds": {
+                        "sid": sid,
+                        "client_secret": registration.client_secret,
+                    },
+                }))?,
+            )?
+        }
+        StageResponse::RegistrationToken(token) => {
+            let mut auth = RegistrationToken::new(token);
+            auth.session = session;
+            AuthData::RegistrationToken(auth)
+        }
+    };
+
+    continue_registration(registration, Some(auth)).await
+}
+
+/// Asks the homeserver to send a validation link to `email`.
+pub async fn request_registration_email(
+    mut registration: Registration,
+    email: String,
+) -> anyhow::Result<Registration> {
+    registration.send_attempt += 1;
+
+    let response = registration
+        .client
+        .send(
+            request_registration_token_via_email::v3::Request::new(
+                registration.client_secret.clone(),
+                email,
+                UInt::from(registration.send_attempt),
+            ),
+            None,
+        )
+        .await?;
+    registration.email_sid = Some(response.sid);
+
+    Ok(registration)
+}
+
+/// Gives up on `registration`, removing the store that was created for it.
+pub async fn abort_registration(registration: Registration) -> anyhow::Result<()> {
+    if registration.client_session.db_path.exists() {
+        fs::remove_dir_all(&registration.client_session.db_path).await?;
+    }
+
+    Ok(())
+}
+
+async fn continue_registration(
+    mut registration: Registration,
+    mut auth: Option<AuthData>,
+) -> anyhow::Result<RegistrationProgress> {
+    let session_file = Path::new("data").join("session");
+
+    loop {
+        let mut request = register::v3::Request::new();
+        request.username = Some(registration.localpart.clone());
+        request.password = Some(registration.password.clone());
+        request.initial_device_display_name = Some(env!("CARGO_PKG_NAME").to_owned());
+        request.refresh_token = true;
+        request.auth = auth.take();
+
+        let error = match registration.client.matrix_auth().register(request).await {
+            Ok(response) => {
+                let access_token = response
+                    .access_token
+                    .context("The homeserver did not log in the new account")?;
+                let device_id = response
+                    .device_id
+                    .context("The homeserver did not create a device")?;
+
+                let user_session = MatrixSession {
+                    meta: SessionMeta {
+                        user_id: response.user_id,
+                        device_id,
+                    },
+                    tokens: MatrixSessionTokens {
+                        access_token,
+                        refresh_token: response.refresh_token,
+                    },
+                };
+                registration
+                    .client
+                    .restore_session(user_session.clone())
+                    .await?;
+
+                info!("Registered {}", user_session.meta.user_id);
+
+                persist_session(
+                    &session_file,
+                    registration.client_session,
+                    UserSession::Matrix(user_session),
+                )
+                .await?;
+
+                return Ok(RegistrationProgress::Complete(registration.client));
+            }
+            Err(error) => error,
+        };
+
+        let Some(uiaa_info) = error.as_uiaa_response() else {
+            abort_registration(registration).await?;
+            return Err(error.into());
+        };
+        registration.uiaa_info = uiaa_info.clone();
+
+        let Some(stage) = next_stage(&registration.uiaa_info) else {
+            abort_registration(registration).await?;
+            anyhow::bail!("The homeserver requires registration steps that are not supported");
+        };
+
+        let stage = match stage {
+            AuthType::Dummy => {
+                let mut dummy = Dummy::new();
+                dummy.session = registration.uiaa_info.session.clone();
+                auth = Some(AuthData::Dummy(dummy));
+                continue;
+            }
+            AuthType::EmailIdentity => RegistrationStage::EmailIdentity,
+            AuthType::RegistrationToken => RegistrationStage::RegistrationToken,
+            _ => RegistrationStage::Terms(terms_policies(&registration.uiaa_info)),
+        };
+
+        let error = registration
+            .uiaa_info
+            .auth_error
+            .as_ref()
+            .map(|error| error.message.clone());
+
+        return Ok(RegistrationProgress::Stage {
+            registration: Box::new(registration),
+            stage,
+            error,
+        });
+    }
+}
+
+/// Picks the next stage of the first flow we can complete entirely.
+fn next_stage(uiaa_info: &UiaaInfo) -> Option<AuthType> {
+    let supported = |stage: &AuthType| {
+        matches!(
+            stage,
+            AuthType::Dummy | AuthType::EmailIdentity | AuthType::RegistrationToken
+        ) || stage.as_str() == TERMS_AUTH_TYPE
+    };
+
+    uiaa_info
+        .flows
+        .iter()
+        .filter(|flow| flow.stages.starts_with(&uiaa_info.completed))
+        .find(|flow| flow.stages.iter().all(supported))
+        .and_then(|flow| flow.stages.get(uiaa_info.completed.len()))
+        .cloned()
+}
+
+/// Reads the policies to accept from the parameters of the terms stage,
+/// preferring their English version.
+fn terms_policies(uiaa_info: &UiaaInfo) -> Vec<Policy> {
+    #[derive(Deserialize)]
+    struct PolicyTranslation {
+        name: String,
+        url: String,
+    }
+
+    let params: serde_json::Value =
+        serde_json::from_str(uiaa_info.params.get()).unwrap_or_default();
+    let Some(policies) = params[TERMS_AUTH_TYPE]["policies"].as_object() else {
+        return Vec::new();
+    };
+
+    policies
+        .values()
+        .filter_map(|policy| {
+            let policy = policy.as_object()?;
+            let translation = policy
+                .get("en")
+                .or_else(|| policy.values().find(|value| value.is_object()))?;
+            let PolicyTranslation { name, url } =
+                serde_json::from_value(translation.clone()).ok()?;
+
+            Some(Policy { name, url })
+        })
+        .collect()
+}
+
+/// The preset a room is created with, setting its join rules, history
+/// visibility and the power levels of the invitees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum RoomPreset {
+    #[default]
+    Private,
+    Public,
+    /// Like a private chat, but invitees start out with the same power level
+    /// as the creator.
+    TrustedPrivate,
+}
+
+impl RoomPreset {
+    pub const ALL: [Self; 3] = [Self::Private, Self::Public, Self::TrustedPrivate];
+}
+
+impl fmt::Display for RoomPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Private => write!(f, "Private chat"),
+            Self::Public => write!(f, "Public chat"),
+            Self::TrustedPrivate => write!(f, "Trusted private chat"),
+        }
+    }
+}
+
+impl From<RoomPreset> for create_room::v3::RoomPreset {
+    fn from(preset: RoomPreset) -> Self {
+        match preset {
+            RoomPreset::Private => Self::PrivateChat,
+            RoomPreset::Public => Self::PublicChat,
+            RoomPreset::TrustedPrivate => Self::TrustedPrivateChat,
+        }
+    }
+}
+
+/// Settings of a room to create, as entered by the user.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NewRoom {
+    pub name: String,
+    pub topic: String,
+    /// Alias to publish, either a full `#alias:server` or only its localpart.
+    pub alias: String,
+    /// Whether the room is published in the room directory.
+    pub public: bool,
+    pub encrypted: bool,
+    /// User IDs to invite, separated by commas or whitespace.
+    pub invites: String,
+    pub preset: RoomPreset,
+}
+
+/// Creates a room and returns its ID.
+pub async fn create_room(client: Client, new_room: NewRoom) -> anyhow::Result<OwnedRoomId> {
+    let invite = new_room
+        .invites
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|user_id| !user_id.is_empty())
+        .map(|user_id| {
+            UserId::parse(user_id).with_context(|| format!("`{user_id}` is not a valid user ID"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Aliases can only be created on our own homeserver, which is where the
+    // localpart ends up anyway.
+    let alias = new_room.alias.trim().trim_start_matches('#');
+    let alias = match alias.split_once(':') {
+        Some((localpart, server)) => {
+            let own_server = client.user_id().context("Not logged in")?.server_name();
+            if server != own_server.as_str() {
+                anyhow::bail!("The alias must be on your homeserver, `{own_server}`");
+            }
+            localpart
+        }
+        None => alias,
+    };
+
+    let mut request = create_room::v3::Request::new();
+    request.name = Some(new_room.name.trim().to_owned()).filter(|name| !name.is_empty());
+    request.topic = Some(new_room.topic.trim().to_owned()).filter(|topic| !topic.is_empty());
+    request.room_alias_name = Some(alias.to_owned()).filter(|alias| !alias.is_empty());
+    request.visibility = if new_room.public {
+        Visibility::Public
+    } else {
+        Visibility::Private
+    };
+    request.preset = Some(new_room.preset.into());
+    request.invite = invite;
+
+    if new_room.encrypted {
+        request.initial_state =
+            vec![
+                InitialStateEvent::new(RoomEncryptionEventContent::with_recommended_defaults())
+                    .to_raw_any(),
+            ];
+    }
+
+    let room = client.create_room(request).await?;
+
+    info!("Created room {}", room.room_id());
+
+    Ok(room.room_id().to_owned())
+}
+
+/// How many users a search of the user directory returns at most.
+const USER_SEARCH_LIMIT: u64 = 20;
+
+/// A user found in the user directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DirectoryUser {
+    pub user_id: OwnedUserId,
+    pub display_name: Option<String>,
+}
+
+/// Searches the user directory of the homeserver for `term`.
+///
+/// Homeservers usually only list users sharing a room with us, so a full user
+/// ID is also offered when the directory doesn't know about it.
+pub async fn search_users(client: Client, term: String) -> anyhow::Result<Vec<DirectoryUser>> {
+    let response = client.search_users(term.trim(), USER_SEARCH_LIMIT).await?;
+
+    let mut users: Vec<_> = response
+        .results
+        .into_iter()
+        .map(|user| DirectoryUser {
+            user_id: user.user_id,
+            display_name: user.display_name,
+        })
+        .collect();
+
+    if let Ok(user_id) = UserId::parse(term.trim()) {
+        if !users.iter().any(|user| user.user_id == user_id) {
+            users.insert(
+                0,
+                DirectoryUser {
+                    user_id,
+                    display_name: None,
+                },
+            );
+        }
+    }
+
+    Ok(users)
+}
+
+/// Returns the direct message room with `user_id`, creating an encrypted one
+/// if we don't share one yet.
+pub async fn start_dm(client: Client, user_id: OwnedUserId) -> anyhow::Result<OwnedRoomId> {
+    let existing = client.joined_rooms().into_iter().find(|room| {
+        let targets = room.direct_targets();
+        targets.len() == 1 && targets.contains(&user_id)
+    });
+
+    if let Some(room) = existing {
+        return Ok(room.room_id().to_owned());
+    }
+
+    // Also records the room in the `m.direct` account data.
+    let room = client.create_dm(&user_id).await?;
+
+    info!(
+        "Created direct message room {} with {user_id}",
+        room.room_id()
+    );
+
+    Ok(room.room_id().to_owned())
+}
+
+/// Joins a room given as `#alias:server`, `!id:server`, or a matrix.to or
+/// `matrix:` URI pointing to one. `via` lists servers to join through, on top
+/// of those named by the URI.
+pub async fn join_room(
+    client: Client,
+    target: String,
+    via: Vec<OwnedServerName>,
+) -> anyhow::Result<OwnedRoomId> {
+    let target = target.trim();
+
+    let (room, mut servers): (OwnedRoomOrAliasId, _) = if let Ok(uri) = MatrixToUri::parse(target) {
+        (room_from_matrix_id(uri.id())?, uri.via().to_vec())
+    } else if let Ok(uri) = MatrixUri::parse(target) {
+        (room_from_matrix_id(uri.id())?, uri.via().to_vec())
+    } else {
+        let room = OwnedRoomOrAliasId::try_from(target)
+            .ok()
+            .context("Expected a room alias, a room ID or a link to a room")?;
+        (room, Vec::new())
+    };
+
+    servers.extend(via);
+
+    // Our homeserver may not know a room only given by its ID, the server that
+    // created it is the best guess to join through.
+    if servers.is_empty() {
+        if let Some(server_name) = room.server_name() {
+            servers.push(server_name.to_owned());
+        }
+    }
+
+    let room = client.join_room_by_id_or_alias(&room, &servers).await?;
+
+    info!("Joined room {}", room.room_id());
+
+    Ok(room.room_id().to_owned())
+}
+
+fn room_from_matrix_id(id: &MatrixId) -> anyhow::Result<OwnedRoomOrAliasId> {
+    match id {
+        MatrixId::Room(room_id) => Ok(room_id.clone().into()),
+        MatrixId::RoomAlias(alias) => Ok(alias.clone().into()),
+        MatrixId::Event(room, _) => Ok(room.clone()),
+        _ => anyhow::bail!("This link doesn't point to a room"),
+    }
+}
+
+/// How many rooms a page of the room directory holds.
+const PUBLIC_ROOMS_LIMIT: u32 = 30;
+
+/// A room listed in a room directory.
+#[derive(Clone, Debug)]
+pub(crate) struct PublicRoom {
+    pub room_id: OwnedRoomId,
+    pub alias: Option<OwnedRoomAliasId>,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub members: u64,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PublicRoomsPage {
+    pub rooms: Vec<PublicRoom>,
+    /// Token to fetch the next page with, `None` on the last page.
+    pub next_batch: Option<String>,
+}
+
+/// Fetches a page of the public room directory of `server`, or of our own
+/// homeserver if it is empty, optionally filtered by `search`.
+pub async fn public_rooms(
+    client: Client,
+    server: String,
+    search: String,
+    since: Option<String>,
+) -> anyhow::Result<PublicRoomsPage> {
+    let server = server.trim();
+    let search = search.trim();
+
+    let mut request = get_public_rooms_filtered::v3::Request::new();
+    request.server = if server.is_empty() {
+        None
+    } else {
+        Some(ServerName::parse(server).context("Not a valid server name")?)
+    };
+    request.limit = Some(PUBLIC_ROOMS_LIMIT.into());
+    request.since = since;
+    let mut filter = Filter::new();
+    filter.generic_search_term = Some(search.to_owned()).filter(|search| !search.is_empty());
+    request.filter = filter;
+
+    let response = client.public_rooms_filtered(request).await?;
+
+    Ok(PublicRoomsPage {
+        rooms: response
+            .chunk
+            .into_iter()
+            .map(|room| PublicRoom {
+                room_id: room.room_id,
+                alias: room.canonical_alias,
+                name: room.name,
+                topic: room.topic,
+                members: room.num_joined_members.into(),
+            })
+            .collect(),
+        next_batch: response.next_batch,
+    })
+}
+
+/// A room we are invited to, with what we know about it before joining.
+#[derive(Clone, Debug)]
+pub(crate) struct PendingInvite {
+    pub room_id: OwnedRoomId,
+    pub name: String,
+    pub alias: Option<OwnedRoomAliasId>,
+    pub topic: Option<String>,
+    pub members: u64,
+    pub inviter: Option<OwnedUserId>,
+    pub inviter_name: Option<String>,
+}
+
+/// Lists the rooms we are invited to.
+pub async fn pending_invites(client: Client) -> anyhow::Result<Vec<PendingInvite>> {
+    let mut invites = Vec::new();
+
+    for room in client.invited_rooms() {
+        // Still list the invite if we can't tell who sent it.
+        let inviter = match room.invite_details().await {
+            Ok(details) => details.inviter,
+            Err(error) => {
+                warn!(
+                    "Failed to get the details of the invite to {} with error {error}",
+                    room.room_id()
+                );
+                None
+            }
+        };
+        let name = match room.display_name().await {
+            Ok(name) => name.to_string(),
+            Err(_) => room.room_id().to_string(),
+        };
+
+        invites.push(PendingInvite {
+            room_id: room.room_id().to_owned(),
+            name,
+            alias: room.canonical_alias(),
+            topic: room.topic(),
+            members: room.joined_members_count(),
+            inviter_name: inviter
+                .as_ref()
+                .and_then(|inviter| inviter.display_name().map(str::to_owned)),
+            inviter: inviter.map(|inviter| inviter.user_id().to_owned()),
+        });
+    }
+
+    Ok(invites)
+}
+
+/// Accepts the invite to `room_id` by joining the room.
+pub async fn accept_invite(client: Client, room_id: OwnedRoomId) -> anyhow::Result<OwnedRoomId> {
+    let room = client
+        .get_room(&room_id)
+        .context("The invite is no longer available")?;
+
+    room.join().await?;
+
+    info!("Accepted invite to {room_id}");
+
+    Ok(room_id)
+}
+
+/// Declines the invite to `room_id`, optionally ignoring the user who sent it
+/// so we don't hear from them anymore.
+pub async fn decline_invite(
+    client: Client,
+    room_id: OwnedRoomId,
+    ignore_inviter: bool,
+) -> anyhow::Result<()> {
+    let room = client
+        .get_room(&room_id)
+        .context("The invite is no longer available")?;
+    // Looked up before leaving, as the invite is gone afterwards. A plain
+    // decline doesn't need to know who sent it.
+    let inviter = if ignore_inviter {
+        Some(room.invite_details().await.map(|details| details.inviter))
+    } else {
+        None
+    };
+
+    room.leave().await?;
+
+    info!("Declined invite to {room_id}");
+
+    if let Some(inviter) = inviter {
+        let inviter = inviter?.context("The sender of the invite is unknown")?;
+        client.account().ignore_user(inviter.user_id()).await?;
+
+        info!("Ignoring {}", inviter.user_id());
+    }
+
+    Ok(())
+}
+
+/// Leaves `room_id` if we are still in it, and forgets it if `forget` is set
+/// so it doesn't show up among the archived rooms anymore.
+pub async fn leave_room(client: Client, room_id: OwnedRoomId, forget: bool) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+
+    if room.state() != RoomState::Left {
+        room.leave().await?;
+        info!("Left room {room_id}");
+    }
+
+    if forget {
+        room.forget().await?;
+        info!("Forgot room {room_id}");
+    }
+
+    Ok(())
+}
+
+/// Invites `user_id` to `room_id`.
+pub async fn invite_user(
+    client: Client,
+    room_id: OwnedRoomId,
+    user_id: OwnedUserId,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    room.invite_user_by_id(&user_id).await.map_err(rejection)?;
+
+    info!("Invited {user_id} to {room_id}");
+
+    Ok(())
+}
+
+/// Changes the topic of `room_id`.
+pub async fn set_topic(client: Client, room_id: OwnedRoomId, topic: String) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    room.set_room_topic(&topic).await.map_err(rejection)?;
+
+    info!("Changed the topic of {room_id}");
+
+    Ok(())
+}
+
+/// Reacts to `event_id` in `room_id` with `key`, usually an emoji.
+pub async fn send_reaction(
+    client: Client,
+    room_id: OwnedRoomId,
+    event_id: OwnedEventId,
+    key: String,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    room.send(ReactionEventContent::new(Annotation::new(event_id, key)))
+        .await
+        .map_err(rejection)?;
+
+    info!("Reacted to a message in {room_id}");
+
+    Ok(())
+}
+
+/// Changes our display name in every room.
+pub async fn set_display_name(client: Client, name: String) -> anyhow::Result<()> {
+    client
+        .account()
+        .set_display_name(Some(&name))
+        .await
+        .map_err(rejection)?;
+
+    info!("Changed our display name to {name}");
+
+    Ok(())
+}
+
+/// A state event of a room, as inspected in the developer tools.
+#[derive(Clone, Debug)]
+pub(crate) struct StateEvent {
+    pub event_type: String,
+    pub state_key: String,
+    /// The whole event, pretty-printed.
+    pub json: String,
+}
+
+/// Fetches the current state of `room_id` from the homeserver, sorted by type
+/// and state key.
+pub async fn room_state(client: Client, room_id: OwnedRoomId) -> anyhow::Result<Vec<StateEvent>> {
+    let response = client
+        .send(get_state_events::v3::Request::new(room_id), None)
+        .await?;
+
+    let mut events = response
+        .room_state
+        .into_iter()
+        .map(|event| {
+            Ok(StateEvent {
+                event_type: event.get_field("type")?.unwrap_or_default(),
+                state_key: event.get_field("state_key")?.unwrap_or_default(),
+                json: serde_json::to_string_pretty(&event)?,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    events.sort_by(|a, b| (&a.event_type, &a.state_key).cmp(&(&b.event_type, &b.state_key)));
+
+    Ok(events)
+}
+
+/// Power level from which members are listed as admins.
+const ADMIN_POWER_LEVEL: i64 = 100;
+/// Power level from which members are listed as moderators.
+const MODERATOR_POWER_LEVEL: i64 = 50;
+
+/// What a member may do in a room, derived from their power level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Role {
+    Admin,
+    Moderator,
+    Member,
+}
+
+impl Role {
+    pub const ALL: [Self; 3] = [Self::Admin, Self::Moderator, Self::Member];
+
+    /// The lowest power level with this role.
+    pub fn power_level(self) -> i64 {
+        match self {
+            Self::Admin => ADMIN_POWER_LEVEL,
+            Self::Moderator => MODERATOR_POWER_LEVEL,
+            Self::Member => 0,
+        }
+    }
+
+    fn from_power_level(power_level: i64) -> Self {
+        match power_level {
+            ADMIN_POWER_LEVEL.. => Self::Admin,
+            MODERATOR_POWER_LEVEL.. => Self::Moderator,
+            _ => Self::Member,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Admin => write!(f, "Admins"),
+            Self::Moderator => write!(f, "Moderators"),
+            Self::Member => write!(f, "Members"),
+        }
+    }
+}
+
+/// A joined or invited member of a room.
+#[derive(Clone, Debug)]
+pub(crate) struct Member {
+    pub user_id: OwnedUserId,
+    pub display_name: Option<String>,
+    pub power_level: i64,
+    pub role: Role,
+    pub invited: bool,
+    pub banned: bool,
+    pub presence: Option<PresenceState>,
+    pub status_msg: Option<String>,
+    pub last_active_ago: Option<Duration>,
+}
+
+impl Member {
+    /// The display name if there is one, the user ID otherwise.
+    pub fn name(&self) -> &str {
+        self.display_name
+            .as_deref()
+            .unwrap_or(self.user_id.as_str())
+    }
+}
+
+/// What we are allowed to do to the other members of a room.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Permissions {
+    pub power_level: i64,
+    pub kick: bool,
+    pub ban: bool,
+    pub change_power_levels: bool,
+}
+
+impl Permissions {
+    /// Whether we rank above `member`, which is needed to moderate them.
+    pub fn outranks(&self, member: &Member) -> bool {
+        self.power_level > member.power_level
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MemberList {
+    pub members: Vec<Member>,
+    pub permissions: Permissions,
+}
+
+/// Loads the members of `room_id`, fetching them from the homeserver the first
+/// time since the sync only sends them lazily. Sorted by role, then name.
+pub async fn room_members(client: Client, room_id: OwnedRoomId) -> anyhow::Result<MemberList> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+
+    let room_members = room
+        .members(RoomMemberships::JOIN | RoomMemberships::INVITE | RoomMemberships::BAN)
+        .await?;
+
+    let user_ids: Vec<_> = room_members
+        .iter()
+        .map(|member| member.user_id().to_owned())
+        .collect();
+    let presence_events = client.store().get_presence_events(&user_ids).await?;
+    let presence: Vec<PresenceEvent> = presence_events
+        .iter()
+        .filter_map(|event| event.deserialize().ok())
+        .collect();
+
+    let mut members: Vec<_> = room_members
+        .into_iter()
+        .map(|member| {
+            let presence = presence
+                .iter()
+                .find(|event| event.sender == member.user_id())
+                .map(|event| &event.content);
+
+            Member {
+                user_id: member.user_id().to_owned(),
+                display_name: member.display_name().map(str::to_owned),
+                power_level: member.power_level(),
+                role: Role::from_power_level(member.power_level()),
+                invited: member.membership() == &MembershipState::Invite,
+                banned: member.membership() == &MembershipState::Ban,
+                presence: presence.map(|presence| presence.presence.clone()),
+                status_msg: presence.and_then(|presence| presence.status_msg.clone()),
+                last_active_ago: presence
+                    .and_then(|presence| presence.last_active_ago)
+                    .map(|ago| Duration::from_millis(ago.into())),
+            }
+        })
+        .collect();
+
+    members.sort_by(|a, b| {
+        a.role
+            .cmp(&b.role)
+            .then_with(|| a.name().to_lowercase().cmp(&b.name().to_lowercase()))
+    });
+
+    let own_user_id = client.user_id().context("Not logged in")?;
+    let permissions = match room_power_levels(&room).await {
+        Ok(power_levels) => Permissions {
+            power_level: power_levels.for_user(own_user_id).into(),
+            kick: power_levels.user_can_kick(own_user_id),
+            ban: power_levels.user_can_ban(own_user_id),
+            change_power_levels: power_levels
+                .user_can_send_state(own_user_id, StateEventType::RoomPowerLevels),
+        },
+        Err(error) => {
+            warn!("Error loading the power levels of {room_id}: {error}");
+            Permissions::default()
+        }
+    };
+
+    Ok(MemberList {
+        members,
+        permissions,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+}
+
+/// Kicks, bans or unbans `user_id` from `room_id`, with an optional reason.
+pub async fn moderate(
+    client: Client,
+    room_id: OwnedRoomId,
+    user_id: OwnedUserId,
+    action: ModerationAction,
+    reason: String,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    let reason = Some(reason.trim()).filter(|reason| !reason.is_empty());
+
+    let result = match action {
+        ModerationAction::Kick => room.kick_user(&user_id, reason).await,
+        ModerationAction::Ban => room.ban_user(&user_id, reason).await,
+        ModerationAction::Unban => room.unban_user(&user_id, reason).await,
+    };
+    result.map_err(rejection)?;
+
+    info!("{action:?} {user_id} in {room_id}");
+
+    Ok(())
+}
+
+/// Gives `user_id` the power level `power_level` in `room_id`.
+pub async fn set_member_power_level(
+    client: Client,
+    room_id: OwnedRoomId,
+    user_id: OwnedUserId,
+    power_level: i64,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+
+    room.update_power_levels(vec![(&user_id, Int::try_from(power_level)?)])
+        .await
+        .map_err(rejection)?;
+
+    info!("Set the power level of {user_id} in {room_id} to {power_level}");
+
+    Ok(())
+}
+
+/// A power level a room requires for an action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PowerLevelSetting {
+    UsersDefault,
+    EventsDefault,
+    StateDefault,
+    Invite,
+    Kick,
+    Ban,
+    Redact,
+    RoomNotification,
+}
+
+impl PowerLevelSetting {
+    pub const ALL: [Self; 8] = [
+        Self::UsersDefault,
+        Self::EventsDefault,
+        Self::StateDefault,
+        Self::Invite,
+        Self::Kick,
+        Self::Ban,
+        Self::Redact,
+        Self::RoomNotification,
+    ];
+
+    fn get(self, power_levels: &RoomPowerLevels) -> Int {
+        match self {
+            Self::UsersDefault => power_levels.users_default,
+            Self::EventsDefault => power_levels.events_default,
+            Self::StateDefault => power_levels.state_default,
+            Self::Invite => power_levels.invite,
+            Self::Kick => power_levels.kick,
+            Self::Ban => power_levels.ban,
+            Self::Redact => power_levels.redact,
+            Self::RoomNotification => power_levels.notifications.room,
+        }
+    }
+
+    fn set(self, power_levels: &mut RoomPowerLevels, value: Int) {
+        match self {
+            Self::UsersDefault => power_levels.users_default = value,
+            Self::EventsDefault => power_levels.events_default = value,
+            Self::StateDefault => power_levels.state_default = value,
+            Self::Invite => power_levels.invite = value,
+            Self::Kick => power_levels.kick = value,
+            Self::Ban => power_levels.ban = value,
+            Self::Redact => power_levels.redact = value,
+            Self::RoomNotification => power_levels.notifications.room = value,
+        }
+    }
+}
+
+impl fmt::Display for PowerLevelSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UsersDefault => write!(f, "Default level of members"),
+            Self::EventsDefault => write!(f, "Send messages"),
+            Self::StateDefault => write!(f, "Change room settings"),
+            Self::Invite => write!(f, "Invite users"),
+            Self::Kick => write!(f, "Kick users"),
+            Self::Ban => write!(f, "Ban users"),
+            Self::Redact => write!(f, "Remove messages of others"),
+            Self::RoomNotification => write!(f, "Notify the whole room"),
+        }
+    }
+}
+
+/// The power levels a room requires, for the power levels editor.
+#[derive(Clone, Debug)]
+pub(crate) struct PowerLevelSettings {
+    pub settings: Vec<(PowerLevelSetting, i64)>,
+    /// Levels required for specific event types, overriding the defaults.
+    pub events: Vec<(String, i64)>,
+}
+
+/// Loads the power levels `room_id` requires for each action.
+pub async fn power_levels(
+    client: Client,
+    room_id: OwnedRoomId,
+) -> anyhow::Result<PowerLevelSettings> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    let power_levels = room_power_levels(&room).await?;
+
+    Ok(PowerLevelSettings {
+        settings: PowerLevelSetting::ALL
+            .into_iter()
+            .map(|setting| (setting, setting.get(&power_levels).into()))
+            .collect(),
+        events: power_levels
+            .events
+            .iter()
+            .map(|(event_type, level)| (event_type.to_string(), (*level).into()))
+            .collect(),
+    })
+}
+
+/// Changes the power levels `room_id` requires, keeping those of its members.
+pub async fn set_power_levels(
+    client: Client,
+    room_id: OwnedRoomId,
+    settings: PowerLevelSettings,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    let mut power_levels = room_power_levels(&room).await?;
+
+    for (setting, level) in settings.settings {
+        setting.set(&mut power_levels, Int::try_from(level)?);
+    }
+    for (event_type, level) in settings.events {
+        power_levels
+            .events
+            .insert(TimelineEventType::from(event_type), Int::try_from(level)?);
+    }
+
+    room.send_state_event(RoomPowerLevelsEventContent::from(power_levels))
+        .await
+        .map_err(rejection)?;
+
+    info!("Updated the power levels of {room_id}");
+
+    Ok(())
+}
+
+async fn room_power_levels(room: &Room) -> anyhow::Result<RoomPowerLevels> {
+    Ok(room
+        .get_state_event_static::<RoomPowerLevelsEventContent>()
+        .await?
+        .context("The room has no power levels")?
+        .deserialize()?
+        .power_levels())
+}
+
+/// Who may join a room, as offered by the room settings editor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum JoinRule {
+    #[default]
+    Invite,
+    Public,
+    /// Anyone may ask to be invited.
+    Knock,
+    /// Members of the allowed rooms may join without an invite.
+    Restricted,
+}
+
+impl JoinRule {
+    pub const ALL: [Self; 4] = [Self::Invite, Self::Public, Self::Knock, Self::Restricted];
+}
+
+impl fmt::Display for JoinRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invite => write!(f, "Invite only"),
+            Self::Public => write!(f, "Anyone"),
+            Self::Knock => write!(f, "Ask to join"),
+            Self::Restricted => write!(f, "Members of other rooms"),
+        }
+    }
+}
+
+/// Who may read the history of a room.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum HistoryVisibility {
+    /// Members, including the messages from before they joined.
+    #[default]
+    Shared,
+    /// Members, from the moment they were invited.
+    Invited,
+    /// Members, from the moment they joined.
+    Joined,
+    /// Anyone, even without joining.
+    WorldReadable,
+}
+
+impl HistoryVisibility {
+    pub const ALL: [Self; 4] = [
+        Self::Shared,
+        Self::Invited,
+        Self::Joined,
+        Self::WorldReadable,
+    ];
+}
+
+impl fmt::Display for HistoryVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Shared => write!(f, "Members, all history"),
+            Self::Invited => write!(f, "Members, since their invite"),
+            Self::Joined => write!(f, "Members, since they joined"),
+            Self::WorldReadable => write!(f, "Anyone"),
+        }
+    }
+}
+
+/// The settings of a room, as shown and edited in the room settings editor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RoomSettings {
+    pub name: String,
+    pub topic: String,
+    pub avatar_url: Option<OwnedMxcUri>,
+    pub canonical_alias: String,
+    /// Alternative aliases, separated by commas or whitespace.
+    pub alt_aliases: String,
+    pub join_rule: JoinRule,
+    /// Rooms whose members may join with a restricted join rule, separated
+    /// by commas or whitespace.
+    pub allowed_rooms: String,
+    pub guest_access: bool,
+    pub history_visibility: HistoryVisibility,
+    /// Encryption can't be disabled again once enabled.
+    pub encrypted: bool,
+}
+
+/// Loads the current settings of `room_id`.
+pub async fn room_settings(client: Client, room_id: OwnedRoomId) -> anyhow::Result<RoomSettings> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+
+    let (join_rule, allowed_rooms) = match room.join_rule() {
+        join_rules::JoinRule::Public => (JoinRule::Public, Vec::new()),
+        join_rules::JoinRule::Knock => (JoinRule::Knock, Vec::new()),
+        join_rules::JoinRule::Restricted(restricted)
+        | join_rules::JoinRule::KnockRestricted(restricted) => (
+            JoinRule::Restricted,
+            restricted
+                .allow
+                .into_iter()
+                .filter_map(|rule| match rule {
+                    AllowRule::RoomMembership(membership) => Some(membership.room_id.to_string()),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => (JoinRule::Invite, Vec::new()),
+    };
+
+    Ok(RoomSettings {
+        name: room.name().unwrap_or_default(),
+        topic: room.topic().unwrap_or_default(),
+        avatar_url: room.avatar_url(),
+        canonical_alias: room
+            .canonical_alias()
+            .map(|alias| alias.to_string())
+            .unwrap_or_default(),
+        alt_aliases: room
+            .alt_aliases()
+            .iter()
+            .map(|alias| alias.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        join_rule,
+        allowed_rooms: allowed_rooms.join(", "),
+        guest_access: room.guest_access() == GuestAccess::CanJoin,
+        history_visibility: match room.history_visibility() {
+            history_visibility::HistoryVisibility::Invited => HistoryVisibility::Invited,
+            history_visibility::HistoryVisibility::Joined => HistoryVisibility::Joined,
+            history_visibility::HistoryVisibility::WorldReadable => {
+                HistoryVisibility::WorldReadable
+            }
+            _ => HistoryVisibility::Shared,
+        },
+        encrypted: room.is_encrypted().await?,
+    })
+}
+
+/// Sends a state event to `room_id` for each setting that differs between
+/// `old` and `new`, publishing new aliases in the room directory first.
+pub async fn save_room_settings(
+    client: Client,
+    room_id: OwnedRoomId,
+    old: RoomSettings,
+    new: RoomSettings,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+
+    if new.name.trim() != old.name {
+        room.set_name(new.name.trim().to_owned())
+            .await
+            .map_err(rejection)?;
+    }
+    if new.topic.trim() != old.topic {
+        room.set_room_topic(new.topic.trim())
+            .await
+            .map_err(rejection)?;
+    }
+
+    if new.canonical_alias != old.canonical_alias || new.alt_aliases != old.alt_aliases {
+        let alias = Some(new.canonical_alias.trim())
+            .filter(|alias| !alias.is_empty())
+            .map(|alias| {
+                RoomAliasId::parse(alias)
+                    .with_context(|| format!("`{alias}` is not a valid room alias"))
+            })
+            .transpose()?;
+        let alt_aliases = parse_list(&new.alt_aliases, |alias| {
+            RoomAliasId::parse(alias)
+                .with_context(|| format!("`{alias}` is not a valid room alias"))
+        })?;
+
+        let published = room.canonical_alias().into_iter().chain(room.alt_aliases());
+        let published = published.collect::<Vec<_>>();
+        for alias in alias.iter().chain(&alt_aliases) {
+            if !published.contains(alias) {
+                publish_alias(&client, &room_id, alias).await?;
+            }
+        }
+
+        let mut content = RoomCanonicalAliasEventContent::new();
+        content.alias = alias;
+        content.alt_aliases = alt_aliases;
+        room.send_state_event(content).await.map_err(rejection)?;
+    }
+
+    if new.join_rule != old.join_rule || new.allowed_rooms != old.allowed_rooms {
+        let join_rule = match new.join_rule {
+            JoinRule::Invite => join_rules::JoinRule::Invite,
+            JoinRule::Public => join_rules::JoinRule::Public,
+            JoinRule::Knock => join_rules::JoinRule::Knock,
+            JoinRule::Restricted => {
+                let allow = parse_list(&new.allowed_rooms, |room_id| {
+                    RoomId::parse(room_id)
+                        .map(AllowRule::room_membership)
+                        .with_context(|| format!("`{room_id}` is not a valid room ID"))
+                })?;
+                anyhow::ensure!(!allow.is_empty(), "Name the rooms whose members may join");
+                join_rules::JoinRule::Restricted(Restricted::new(allow))
+            }
+        };
+        room.send_state_event(RoomJoinRulesEventContent::new(join_rule))
+            .await
+            .map_err(rejection)?;
+    }
+
+    if new.guest_access != old.guest_access {
+        let guest_access = if new.guest_access {
+            GuestAccess::CanJoin
+        } else {
+            GuestAccess::Forbidden
+        };
+        room.send_state_event(RoomGuestAccessEventContent::new(guest_access))
+            .await
+            .map_err(rejection)?;
+    }
+
+    if new.history_visibility != old.history_visibility {
+        let history_visibility = match new.history_visibility {
+            HistoryVisibility::Shared => history_visibility::HistoryVisibility::Shared,
+            HistoryVisibility::Invited => history_visibility::HistoryVisibility::Invited,
+            HistoryVisibility::Joined => history_visibility::HistoryVisibility::Joined,
+            HistoryVisibility::WorldReadable => {
+                history_visibility::HistoryVisibility::WorldReadable
+            }
+        };
+        room.send_state_event(RoomHistoryVisibilityEventContent::new(history_visibility))
+            .await
+            .map_err(rejection)?;
+    }
+
+    if new.encrypted && !old.encrypted {
+        room.enable_encryption().await.map_err(rejection)?;
+    }
+
+    info!("Updated the settings of {room_id}");
+
+    Ok(())
+}
+
+/// Points `alias` at `room_id` in the room directory, unless it already does.
+async fn publish_alias(
+    client: &Client,
+    room_id: &RoomId,
+    alias: &RoomAliasId,
+) -> anyhow::Result<()> {
+    let request = create_alias::v3::Request::new(alias.to_owned(), room_id.to_owned());
+    let Err(error) = client.send(request, None).await else {
+        info!("Published {alias} for {room_id}");
+        return Ok(());
+    };
+
+    match client.resolve_room_alias(alias).await {
+        Ok(response) if response.room_id == room_id => Ok(()),
+        _ => Err(rejection(error.into())),
+    }
+}
+
+/// Parses the entries of a list separated by commas or whitespace.
+fn parse_list<T>(list: &str, parse: impl Fn(&str) -> anyhow::Result<T>) -> anyhow::Result<Vec<T>> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|entry| !entry.is_empty())
+        .map(parse)
+        .collect()
+}
+
+/// Uploads the image at `path` as the new avatar of `room_id` and returns its
+/// URL.
+pub async fn set_room_avatar(
+    client: Client,
+    room_id: OwnedRoomId,
+    path: PathBuf,
+) -> anyhow::Result<OwnedMxcUri> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+
+    let (url, _) = upload_image(&client, &path).await?;
+    room.set_avatar_url(&url, None).await.map_err(rejection)?;
+
+    info!("Changed the avatar of {room_id}");
+
+    Ok(url)
+}
+
+/// Uploads the image at `path`, telling its type from its extension.
+async fn upload_image(client: &Client, path: &Path) -> anyhow::Result<(OwnedMxcUri, ImageInfo)> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let mime: mime::Mime = match extension.as_deref() {
+        Some("png") => mime::IMAGE_PNG,
+        Some("jpg" | "jpeg") => mime::IMAGE_JPEG,
+        Some("gif") => mime::IMAGE_GIF,
+        Some("webp") => "image/webp".parse()?,
+        _ => anyhow::bail!("Only PNG, JPEG, GIF and WebP images are supported"),
+    };
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+
+    let mut info = ImageInfo::new();
+    info.mimetype = Some(mime.to_string());
+    info.size = UInt::new(data.len() as u64);
+
+    let url = client.media().upload(&mime, data).await?.content_uri;
+
+    Ok((url, info))
+}
+
+/// Removes the avatar of `room_id`.
+pub async fn remove_room_avatar(client: Client, room_id: OwnedRoomId) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    room.remove_avatar().await.map_err(rejection)?;
+
+    info!("Removed the avatar of {room_id}");
+
+    Ok(())
+}
+
+/// Width and height in pixels of the avatar thumbnails we request.
+const AVATAR_THUMBNAIL_SIZE: u32 = 64;
+
+/// The avatar of `room`, falling back to the one of the other user in a DM.
+async fn room_avatar_url(room: &Room) -> anyhow::Result<Option<OwnedMxcUri>> {
+    if let Some(url) = room.avatar_url() {
+        return Ok(Some(url));
+    }
+
+    let targets = room.direct_targets();
+    let Some(user_id) = targets.iter().next().filter(|_| targets.len() == 1) else {
+        return Ok(None);
+    };
+
+    Ok(room
+        .get_member_no_sync(user_id)
+        .await?
+        .and_then(|member| member.avatar_url().map(ToOwned::to_owned)))
+}
+
+/// Our own name and avatar in `room_id`, as shown on the messages we send.
+pub async fn own_member(
+    client: Client,
+    room_id: OwnedRoomId,
+) -> anyhow::Result<(String, Option<OwnedMxcUri>)> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    let user_id = client.user_id().context("Not logged in")?;
+
+    Ok(match room.get_member_no_sync(user_id).await? {
+        Some(member) => (
+            sender_name(&member),
+            member.avatar_url().map(ToOwned::to_owned),
+        ),
+        None => (user_id.to_string(), None),
+    })
+}
+
+/// Downloads a thumbnail of the avatar at `url`. Thumbnails are kept in the
+/// media cache of the store, so each is only downloaded once.
+pub async fn avatar_thumbnail(client: Client, url: OwnedMxcUri) -> anyhow::Result<Vec<u8>> {
+    let request = MediaRequest {
+        source: MediaSource::Plain(url),
+        format: MediaFormat::Thumbnail(MediaThumbnailSize {
+            method: Method::Crop,
+            width: AVATAR_THUMBNAIL_SIZE.into(),
+            height: AVATAR_THUMBNAIL_SIZE.into(),
+        }),
+    };
+
+    Ok(client.media().get_media_content(&request, true).await?)
+}
+
+/// Size of the thumbnails of custom emoji and stickers, scaled to fit.
+const MEDIA_THUMBNAIL_SIZE: u32 = 256;
+
+/// Downloads a thumbnail of a custom emoji or sticker, cached like avatars.
+pub async fn media_thumbnail(client: Client, url: OwnedMxcUri) -> anyhow::Result<Vec<u8>> {
+    let request = MediaRequest {
+        source: MediaSource::Plain(url),
+        format: MediaFormat::Thumbnail(MediaThumbnailSize {
+            method: Method::Scale,
+            width: MEDIA_THUMBNAIL_SIZE.into(),
+            height: MEDIA_THUMBNAIL_SIZE.into(),
+        }),
+    };
+
+    Ok(client.media().get_media_content(&request, true).await?)
+}
+
+/// Room state holding an image pack, one per state key.
+const ROOM_PACK_TYPE: &str = "im.ponies.room_emotes";
+
+/// Account data holding our personal image pack.
+const USER_PACK_TYPE: &str = "im.ponies.user_emotes";
+
+/// Account data listing the room packs we use in every room.
+const EMOTE_ROOMS_TYPE: &str = "im.ponies.emote_rooms";
+
+/// What the images of a pack are meant to be used as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PackUsage {
+    Emoticon,
+    Sticker,
+    #[serde(other)]
+    Unknown,
+}
+
+/// An image pack as described by MSC2545, in room state or account data.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ImagePackContent {
+    #[serde(default)]
+    images: BTreeMap<String, PackImageContent>,
+    #[serde(default)]
+    pack: PackInfo,
+    /// Fields we don't know about, kept when we edit our personal pack.
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PackImageContent {
+    url: OwnedMxcUri,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info: Option<ImageInfo>,
+    /// Falls back to the usage of the pack when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<Vec<PackUsage>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PackInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<OwnedMxcUri>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<Vec<PackUsage>>,
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct PackStateEvent {
+    state_key: String,
+    content: ImagePackContent,
+}
+
+/// The room packs we use in every room, by room and state key.
+#[derive(Default, Deserialize)]
+struct EmoteRooms {
+    #[serde(default)]
+    rooms: BTreeMap<OwnedRoomId, BTreeMap<String, serde_json::Value>>,
+}
+
+/// An image pack usable in a room.
+#[derive(Clone, Debug)]
+pub(crate) struct ImagePack {
+    pub name: String,
+    pub avatar_url: Option<OwnedMxcUri>,
+    /// Whether this is our personal pack, which we can edit.
+    pub personal: bool,
+    pub images: Vec<PackImage>,
+}
+
+/// A custom emoji or sticker of an image pack.
+#[derive(Clone, Debug)]
+pub(crate) struct PackImage {
+    pub shortcode: String,
+    pub url: OwnedMxcUri,
+    pub body: String,
+    pub info: ImageInfo,
+    pub emoticon: bool,
+    pub sticker: bool,
+}
+
+impl ImagePack {
+    fn new(content: ImagePackContent, name: String, personal: bool) -> Self {
+        let pack_usage = content.pack.usage;
+        let images = content
+            .images
+            .into_iter()
+            .map(|(shortcode, image)| {
+                // Images without a usage are both emoji and stickers.
+                let usage = image
+                    .usage
+                    .or_else(|| pack_usage.clone())
+                    .unwrap_or_default();
+                let any = usage.is_empty();
+
+                PackImage {
+                    body: image.body.unwrap_or_else(|| shortcode.clone()),
+                    shortcode,
+                    url: image.url,
+                    info: image.info.unwrap_or_default(),
+                    emoticon: any || usage.contains(&PackUsage::Emoticon),
+                    sticker: any || usage.contains(&PackUsage::Sticker),
+                }
+            })
+            .collect();
+
+        Self {
+            name: content.pack.display_name.unwrap_or(name),
+            avatar_url: content.pack.avatar_url,
+            personal,
+            images,
+        }
+    }
+
+    fn personal(content: ImagePackContent) -> Self {
+        Self::new(content, "Personal".to_owned(), true)
+    }
+}
+
+/// The image packs usable in `room_id`: our personal pack, the packs of the
+/// room and the room packs we enabled everywhere.
+pub async fn image_packs(client: Client, room_id: OwnedRoomId) -> anyhow::Result<Vec<ImagePack>> {
+    let mut packs = vec![ImagePack::personal(user_pack(&client).await?)];
+
+    let emote_rooms = match client
+        .account()
+        .account_data_raw(EMOTE_ROOMS_TYPE.into())
+        .await?
+    {
+        Some(raw) => raw.deserialize_as::<EmoteRooms>().unwrap_or_else(|error| {
+            warn!("Ignoring invalid {EMOTE_ROOMS_TYPE}: {error}");
+            EmoteRooms::default()
+        }),
+        None => EmoteRooms::default(),
+    };
+
+    // Every pack of the room itself, then the enabled packs of other rooms.
+    let mut sources = vec![(room_id.clone(), None)];
+    for (other_room_id, state_keys) in emote_rooms.rooms {
+        if other_room_id != room_id {
+            sources.extend(
+                state_keys
+                    .into_keys()
+                    .map(|state_key| (other_room_id.clone(), Some(state_key))),
+            );
+        }
+    }
+
+    for (room_id, state_key) in sources {
+        let Some(room) = client.get_room(&room_id) else {
+            continue;
+        };
+
+        for raw in room.get_state_events(ROOM_PACK_TYPE.into()).await? {
+            let event = match &raw {
+                RawAnySyncOrStrippedState::Sync(raw) => raw.deserialize_as::<PackStateEvent>(),
+                RawAnySyncOrStrippedState::Stripped(raw) => raw.deserialize_as::<PackStateEvent>(),
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(error) => {
+                    warn!("Ignoring invalid image pack in {room_id}: {error}");
+                    continue;
+                }
+            };
+
+            if state_key
+                .as_ref()
+                .is_some_and(|state_key| *state_key != event.state_key)
+                || event.content.images.is_empty()
+            {
+                continue;
+            }
+
+            packs.push(ImagePack::new(event.content, room_name(&room), false));
+        }
+    }
+
+    Ok(packs)
+}
+
+/// Our personal image pack, empty when we have none yet.
+async fn user_pack(client: &Client) -> anyhow::Result<ImagePackContent> {
+    let Some(raw) = client
+        .account()
+        .account_data_raw(USER_PACK_TYPE.into())
+        .await?
+    else {
+        return Ok(ImagePackContent::default());
+    };
+
+    Ok(raw.deserialize_as()?)
+}
+
+/// Our personal image pack as the homeserver has it. The store only catches
+/// up with our own changes on the next sync, so edits start from this one not
+/// to undo the previous edit.
+async fn fetch_user_pack(client: &Client) -> anyhow::Result<ImagePackContent> {
+    let Some(raw) = client
+        .account()
+        .fetch_account_data(USER_PACK_TYPE.into())
+        .await?
+    else {
+        return Ok(ImagePackContent::default());
+    };
+
+    Ok(raw.deserialize_as()?)
+}
+
+async fn save_user_pack(client: &Client, pack: &ImagePackContent) -> anyhow::Result<()> {
+    client
+        .account()
+        .set_account_data_raw(
+            USER_PACK_TYPE.into(),
+            Raw::new(pack)?.cast::<AnyGlobalAccountDataEventContent>(),
+        )
+        .await
+        .map_err(rejection)?;
+
+    Ok(())
+}
+
+/// Uploads the image at `path` and adds it to our personal pack as
+/// `shortcode`, as an emoji, a sticker or both. Returns the updated pack.
+pub async fn add_pack_image(
+    client: Client,
+    shortcode: String,
+    path: PathBuf,
+    emoticon: bool,
+    sticker: bool,
+) -> anyhow::Result<ImagePack> {
+    let shortcode = shortcode.trim().trim_matches(':').to_owned();
+    if shortcode.is_empty() || shortcode.contains(|c: char| c.is_whitespace() || c == ':') {
+        anyhow::bail!("Shortcodes can't be empty or contain spaces or colons");
+    }
+    if !emoticon && !sticker {
+        anyhow::bail!("The image has to be usable as an emoji, a sticker or both");
+    }
+
+    let mut pack = fetch_user_pack(&client).await?;
+    if pack.images.contains_key(&shortcode) {
+        anyhow::bail!("There already is an image called :{shortcode}:");
+    }
+
+    let (url, info) = upload_image(&client, &path).await?;
+    let usage = [
+        (emoticon, PackUsage::Emoticon),
+        (sticker, PackUsage::Sticker),
+    ]
+    .into_iter()
+    .filter_map(|(used, usage)| used.then_some(usage))
+    .collect();
+
+    pack.images.insert(
+        shortcode.clone(),
+        PackImageContent {
+            url,
+            body: None,
+            info: Some(info),
+            usage: Some(usage),
+        },
+    );
+    save_user_pack(&client, &pack).await?;
+
+    info!("Added :{shortcode}: to our image pack");
+
+    Ok(ImagePack::personal(pack))
+}
+
+/// Removes `shortcode` from our personal pack. Returns the updated pack.
+pub async fn remove_pack_image(client: Client, shortcode: String) -> anyhow::Result<ImagePack> {
+    let mut pack = fetch_user_pack(&client).await?;
+    pack.images.remove(&shortcode);
+    save_user_pack(&client, &pack).await?;
+
+    info!("Removed :{shortcode}: from our image pack");
+
+    Ok(ImagePack::personal(pack))
+}
+
+/// Sends `sticker` to `room_id`.
+pub async fn send_sticker(
+    client: Client,
+    room_id: OwnedRoomId,
+    sticker: PackImage,
+) -> anyhow::Result<()> {
+    let room = client.get_room(&room_id).context("Unknown room")?;
+    room.send(StickerEventContent::new(
+        sticker.body,
+        sticker.info,
+        sticker.url,
+    ))
+    .await
+    .map_err(rejection)?;
+
+    info!("Sent a sticker to {room_id}");
+
+    Ok(())
+}
+
+/// The custom emoji of an HTML message, by the text they stand for in its
+/// plain text body, e.g. `:blobcat:`.
+pub(crate) fn html_emotes(html: &str) -> Vec<(String, OwnedMxcUri)> {
+    html.split("<img")
+        .skip(1)
+        .filter_map(|rest| {
+            let attributes = html_attributes(rest);
+            let attribute = |name: &str| {
+                attributes
+                    .iter()
+                    .find(|(other, _)| other == name)
+                    .map(|(_, value)| value.clone())
+            };
+            attribute("data-mx-emoticon")?;
+
+            let url = OwnedMxcUri::from(attribute("src")?);
+            let alt = attribute("alt").or_else(|| attribute("title"))?;
+
+            (url.is_valid() && !alt.is_empty()).then_some((alt, url))
+        })
+        .collect()
+}
+
+/// The attributes of the tag starting at `tag`, right after its name, with
+/// their values unescaped. Values may be in double, single or no quotes.
+fn html_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() || rest.starts_with('>') {
+            return attributes;
+        }
+
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/'))
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = "";
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            (value, rest) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let quoted = &after[1..];
+                    let end = quoted.find(quote).unwrap_or(quoted.len());
+                    (&quoted[..end], quoted.get(end + 1..).unwrap_or_default())
+                }
+                _ => after.split_at(
+                    after
+                        .find(|c: char| c.is_whitespace() || c == '>')
+                        .unwrap_or(after.len()),
+                ),
+            };
+        }
+
+        attributes.push((
+            name,
+            value
+                .replace("&quot;", "\"")
+                .replace("&#39;", "'")
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&amp;", "&"),
+        ));
+    }
+}
+
+/// Turns an error of a request the homeserver refused into the reason it gave.
+fn rejection(error: Error) -> anyhow::Error {
+    match error.as_client_api_error().map(|error| &error.body) {
+        Some(ErrorBody::Standard {
+            kind: ErrorKind::Forbidden,
+            message,
+        }) => anyhow::anyhow!("Not allowed: {message}"),
+        Some(ErrorBody::Standard { message, .. }) => anyhow::anyhow!("{message}"),
+        _ => error.into(),
+    }
+}
+
+/// Capacity of the channel bridging the sync loop to the UI. Once it is full the
+/// sync loop waits for the UI to catch up instead of buffering without bound.
+const EVENT_CHANNEL_SIZE: usize = 100;
+
+/// Runs the sync loop as a subscription, forwarding its events to the UI.
+///
+/// The loop is torn down together with the subscription, i.e. as soon as the
+/// application stops asking for it.
+pub fn event_loop(
+    client: Client,
+    sync_token: Option<String>,
+    login: u64,
+) -> iced::Subscription<ClientMessage> {
+    struct EventLoop;
+
+    // Keyed by login, not by access token, so refreshing the tokens keeps the
+    // loop running while logging in again starts a fresh one.
+    iced::subscription::channel(
+        (TypeId::of::<EventLoop>(), login),
+        EVENT_CHANNEL_SIZE,
+        move |mut sender| async move {
+            let data_dir = Path::new("data");
+            let session_file = data_dir.join("session");
+
+            let result = tokio::select! {
+                result = sync(client.clone(), sync_token, &session_file, sender.clone()) => result,
+                result = persist_refreshed_tokens(&client, &session_file) => result,
+            };
+
+            if let Err(err) = result {
+                warn!("Sync loop stopped with error {}", err);
+                let _ = sender
+                    .send(ClientMessage::ConnectionChanged(ConnectionState::Offline))
+                    .await;
+            }
+
+            std::future::pending::<Infallible>().await
+        },
+    )
+}
+
+/// Loads the messages that were queued for sending but never confirmed by the
+/// server.
+pub async fn load_outbox() -> anyhow::Result<Vec<QueuedMessage>> {
+    let outbox_file = Path::new("data").join("outbox");
+
+    if !outbox_file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let serialized_outbox = fs::read_to_string(outbox_file).await?;
+
+    Ok(serde_json::from_str(&serialized_outbox)?)
+}
+
+/// Revision given to the next snapshot of the outbox to save.
+static OUTBOX_REVISION: AtomicU64 = AtomicU64::new(1);
+
+/// Revision of the snapshot of the outbox on disk. Held while writing, so
+/// writes don't interleave and a snapshot that finishes after a newer one is
+/// dropped.
+static OUTBOX_WRITTEN: tokio::sync::Mutex<u64> = tokio::sync::Mutex::const_new(0);
+
+/// Persists the messages that still have to be sent, so they survive a restart.
+///
+/// The snapshot is ordered when this is called rather than when the future
+/// runs, so saves finishing out of order can't leave an older outbox on disk.
+pub fn save_outbox(
+    outbox: Vec<QueuedMessage>,
+) -> impl std::future::Future<Output = anyhow::Result<()>> {
+    let revision = OUTBOX_REVISION.fetch_add(1, Ordering::SeqCst);
+
+    async move {
+        let mut written = OUTBOX_WRITTEN.lock().await;
+        if revision <= *written {
+            return Ok(());
+        }
+
+        let data_dir = Path::new("data");
+        let outbox_file = data_dir.join("outbox");
+        let temp_file = data_dir.join("outbox.tmp");
+
+        let serialized_outbox = serde_json::to_string(&outbox)?;
+        fs::write(&temp_file, serialized_outbox).await?;
+        fs::rename(temp_file, outbox_file).await?;
+        *written = revision;
+
+        Ok(())
+    }
+}
 
-use crate::{ClientMessage, Message};
+/// Loads the unsent text of the composer of each room, by room ID.
+pub async fn load_drafts() -> anyhow::Result<HashMap<String, String>> {
+    let drafts_file = Path::new("data").join("drafts");
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ClientSession {
-    homeserver: String,
-    db_path: PathBuf,
-    passphrase: String,
-}
+    if !drafts_file.exists() {
+        return Ok(HashMap::new());
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FullSession {
-    client_session: ClientSession,
-    user_session: MatrixSession,
+    let serialized_drafts = fs::read_to_string(drafts_file).await?;
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    sync_token: Option<String>,
+    Ok(serde_json::from_str(&serialized_drafts)?)
 }
 
-pub(crate) struct Credentials {
-    pub username: String,
-    pub password: String,
+/// Persists the drafts, so they survive a restart.
+pub async fn save_drafts(drafts: HashMap<String, String>) -> anyhow::Result<()> {
+    let drafts_file = Path::new("data").join("drafts");
+
+    let serialized_drafts = serde_json::to_string(&drafts)?;
+    fs::write(drafts_file, serialized_drafts).await?;
+
+    Ok(())
 }
 
-pub async fn run(credentials: Credentials) -> anyhow::Result<(Client, Option<String>)> {
-    let data_dir = Path::new("data");
-    let session_file = data_dir.join("session");
+/// Loads the emoji we used last, most recent first.
+pub async fn load_recent_emoji() -> anyhow::Result<Vec<String>> {
+    let recent_emoji_file = Path::new("data").join("recent_emoji");
 
-    let (client, sync_token) = if session_file.exists() {
-        restore_session(&session_file).await?
-    } else {
-        (login(credentials, &data_dir, &session_file).await?, None)
-    };
+    if !recent_emoji_file.exists() {
+        return Ok(Vec::new());
+    }
 
-    Ok((client, sync_token))
+    let serialized_recent_emoji = fs::read_to_string(recent_emoji_file).await?;
+
+    Ok(serde_json::from_str(&serialized_recent_emoji)?)
 }
 
-pub async fn start_event_loop(
-    client: Client,
-    sync_token: Option<String>,
-    sender: Sender<ClientMessage>,
-) -> anyhow::Result<()> {
-    let data_dir = Path::new("data");
-    let session_file = data_dir.join("session");
+/// Persists the emoji we used last, so the picker still offers them after a
+/// restart.
+pub async fn save_recent_emoji(recent_emoji: Vec<String>) -> anyhow::Result<()> {
+    let recent_emoji_file = Path::new("data").join("recent_emoji");
 
-    sync(client, sync_token, &session_file, sender)
-        .await
-        .map_err(Into::into)
+    let serialized_recent_emoji = serde_json::to_string(&recent_emoji)?;
+    fs::write(recent_emoji_file, serialized_recent_emoji).await?;
+
+    Ok(())
 }
 
 async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
@@ -83,12 +2594,28 @@ async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<
     let client = Client::builder()
         .homeserver_url(client_session.homeserver)
         .sqlite_store(client_session.db_path, Some(&client_session.passphrase))
+        .handle_refresh_tokens()
         .build()
         .await?;
 
-    info!("Restoring session for {}…", user_session.meta.user_id);
+    info!("Restoring session for {}…", user_session.user_id());
 
-    client.restore_session(user_session).await?;
+    match user_session {
+        UserSession::Matrix(session) => client.restore_session(session).await?,
+        UserSession::Oidc(session) => {
+            let session = *session;
+            client
+                .oidc()
+                .restore_session(oidc::OidcSession {
+                    credentials: ClientCredentials::None {
+                        client_id: session.client_id,
+                    },
+                    metadata: session.client_metadata,
+                    user: session.user,
+                })
+                .await?
+        }
+    }
 
     Ok((client, sync_token))
 }
@@ -100,41 +2627,125 @@ async fn login(
 ) -> anyhow::Result<Client> {
     info!("No previous session found, logging in…");
 
-    let (client, client_session) = build_client(&credentials, data_dir).await?;
+    let (client, client_session) = build_client(&credentials.username, data_dir).await?;
     let matrix_auth = client.matrix_auth();
 
-    match matrix_auth
+    if let Err(error) = matrix_auth
         .login_username(&credentials.username, &credentials.password)
         .initial_device_display_name(env!("CARGO_PKG_NAME"))
         .await
     {
-        Ok(_) => {
-            info!("Logged in as {}", &credentials.username);
-        }
-        Err(error) => {
-            info!("Error logging in: {}", error);
-        }
+        // Don't leave an unused store behind for every failed attempt.
+        let _ = fs::remove_dir_all(&client_session.db_path).await;
+        return Err(error.into());
     }
 
+    info!("Logged in as {}", &credentials.username);
+
     let user_session = matrix_auth
         .session()
         .expect("A logged-in client should have a session");
-    let serialized_session = serde_json::to_string(&FullSession {
+    persist_session(
+        session_file,
         client_session,
-        user_session,
-        sync_token: None,
-    })?;
-    fs::write(session_file, serialized_session).await?;
+        UserSession::Matrix(user_session),
+    )
+    .await?;
+
+    Ok(client)
+}
+
+async fn persist_session(
+    session_file: &Path,
+    client_session: ClientSession,
+    user_session: UserSession,
+) -> anyhow::Result<()> {
+    let _session_lock = SESSION_LOCK.lock().await;
+    write_session(
+        session_file,
+        &FullSession {
+            client_session,
+            user_session,
+            sync_token: None,
+        },
+    )
+    .await?;
 
     info!("Session persisted in {}", session_file.to_string_lossy());
 
-    Ok(client)
+    Ok(())
 }
 
-async fn build_client(
-    credentials: &Credentials,
-    data_dir: &Path,
-) -> anyhow::Result<(Client, ClientSession)> {
+/// Held while the session file is written, so the sync token and refreshed
+/// tokens saved at the same time don't undo each other.
+static SESSION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Writes `full_session` through a temporary file, so it is never read half
+/// written. The session lock has to be held.
+async fn write_session(session_file: &Path, full_session: &FullSession) -> anyhow::Result<()> {
+    let temp_file = session_file.with_extension("tmp");
+
+    fs::write(&temp_file, serde_json::to_string(full_session)?).await?;
+    fs::rename(temp_file, session_file).await?;
+
+    Ok(())
+}
+
+/// Reads the session file, changes it with `update` and writes it back.
+async fn update_session(
+    session_file: &Path,
+    update: impl FnOnce(&mut FullSession),
+) -> anyhow::Result<()> {
+    let _session_lock = SESSION_LOCK.lock().await;
+
+    let serialized_session = fs::read_to_string(session_file).await?;
+    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+    update(&mut full_session);
+
+    write_session(session_file, &full_session).await
+}
+
+/// Writes the new tokens to the session file whenever they are refreshed.
+async fn persist_refreshed_tokens(client: &Client, session_file: &Path) -> anyhow::Result<()> {
+    let mut session_changes = client.subscribe_to_session_changes();
+
+    loop {
+        match session_changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) => {}
+            Ok(SessionChange::UnknownToken { .. }) => continue,
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+
+        update_session(session_file, |full_session| {
+            match &mut full_session.user_session {
+                UserSession::Matrix(session) => {
+                    if let Some(tokens) = client.matrix_auth().session_tokens() {
+                        session.tokens = tokens;
+                    }
+                }
+                UserSession::Oidc(session) => {
+                    if let Some(tokens) = client.oidc().session_tokens() {
+                        session.user.tokens = tokens;
+                    }
+                }
+            }
+        })
+        .await?;
+
+        info!("Refreshed session tokens persisted");
+    }
+}
+
+fn server_name(username: &str) -> anyhow::Result<OwnedServerName> {
+    let Some((_, server_name)) = username.split_once(':') else {
+        anyhow::bail!("Expected a full user ID, e.g. `@alice:matrix.org`");
+    };
+
+    Ok(ServerName::parse(server_name)?)
+}
+
+async fn build_client(username: &str, data_dir: &Path) -> anyhow::Result<(Client, ClientSession)> {
     let mut rng = StdRng::from_entropy();
 
     let db_subfolder: String = (&mut rng)
@@ -150,50 +2761,31 @@ async fn build_client(
         .map(char::from)
         .collect();
 
-    let homeserver = format!(
-        "https://{}",
-        credentials.username.split_once(':').unwrap().1
-    );
+    let client = Client::builder()
+        .server_name(&server_name(username)?)
+        .sqlite_store(&db_path, Some(&passphrase))
+        .handle_refresh_tokens()
+        .build()
+        .await
+        .map_err(|error| anyhow::anyhow!("Error checking the homeserver: {error}"))?;
 
-    loop {
-        match Client::builder()
-            .homeserver_url(&homeserver)
-            .sqlite_store(&db_path, Some(&passphrase))
-            .build()
-            .await
-        {
-            Ok(client) => {
-                return Ok((
-                    client,
-                    ClientSession {
-                        homeserver: homeserver.to_owned(),
-                        db_path,
-                        passphrase,
-                    },
-                ))
-            }
-            Err(error) => match &error {
-                matrix_sdk::ClientBuildError::AutoDiscovery(_)
-                | matrix_sdk::ClientBuildError::Url(_)
-                | matrix_sdk::ClientBuildError::Http(_) => {
-                    println!("Error checking the homeserver: {error}");
-                    println!("Please try again\n");
-                }
-                _ => {
-                    return Err(error.into());
-                }
-            },
-        }
-    }
+    Ok((
+        client.clone(),
+        ClientSession {
+            homeserver: client.homeserver().to_string(),
+            db_path,
+            passphrase,
+        },
+    ))
 }
 
 async fn sync(
     client: Client,
     initial_sync_token: Option<String>,
     session_file: &Path,
-    sender: Sender<ClientMessage>,
+    mut sender: Sender<ClientMessage>,
 ) -> anyhow::Result<()> {
-    println!("Launching a first sync to ignore past messages…");
+    info!("Launching a first sync to ignore past messages…");
 
     let filter = FilterDefinition::with_lazy_loading();
 
@@ -203,59 +2795,288 @@ async fn sync(
         sync_settings = sync_settings.token(sync_token);
     }
 
+    let mut connection = ConnectionTracker::new(sender.clone());
+    connection.set(ConnectionState::Connecting).await?;
+
     loop {
         match client.sync_once(sync_settings.clone()).await {
             Ok(response) => {
                 sync_settings = sync_settings.token(response.next_batch.clone());
-                persist_sync_token(session_file, response.next_batch).await?;
+                persist_sync_token(session_file, response.next_batch.clone()).await;
+                sender
+                    .send(ClientMessage::SyncTokenChanged(response.next_batch))
+                    .await?;
                 break;
             }
             Err(error) => {
-                println!("An error occurred during initial sync: {error}");
-                println!("Trying again…");
+                warn!("An error occurred during initial sync: {error}");
+                if let Some(invalidated) = SessionInvalidated::from_error(&error) {
+                    sender
+                        .send(ClientMessage::SessionInvalidated(invalidated))
+                        .await?;
+                    return Ok(());
+                }
+                connection.failed().await?;
             }
         }
     }
 
-    println!("The client is ready! Listening to new messages…");
+    info!("The client is ready! Listening to new messages…");
+    connection.succeeded().await?;
+
+    let mut invites = HashSet::new();
+    check_invites(&client, &mut invites, &mut sender).await?;
+    let mut avatars = HashMap::new();
+    check_avatars(&client, &mut avatars, &mut sender).await?;
+
+    // Messages are collected while a sync response is processed and forwarded
+    // as a single batch, so a busy sync only causes a single redraw.
+    let batch: Arc<Mutex<Vec<Message>>> = Arc::default();
+    let reactions: Arc<Mutex<Vec<Reaction>>> = Arc::default();
 
-    client.add_event_handler(move |event, room| {
-        let sender = sender.clone();
-        async move {
-            on_room_message(event, room, sender).await;
+    let handle = client.add_event_handler({
+        let batch = batch.clone();
+        move |event, room| {
+            let batch = batch.clone();
+            async move {
+                on_room_message(event, room, batch).await;
+            }
+        }
+    });
+    let _guard = client.event_handler_drop_guard(handle);
+    let handle = client.add_event_handler({
+        let batch = batch.clone();
+        move |event, room| {
+            let batch = batch.clone();
+            async move {
+                on_room_state(event, room, batch).await;
+            }
+        }
+    });
+    let _state_guard = client.event_handler_drop_guard(handle);
+    let handle = client.add_event_handler({
+        let reactions = reactions.clone();
+        move |event, room| {
+            let reactions = reactions.clone();
+            async move {
+                on_reaction(event, room, reactions).await;
+            }
+        }
+    });
+    let _reaction_guard = client.event_handler_drop_guard(handle);
+    let handle = client.add_event_handler({
+        let batch = batch.clone();
+        move |event, room| {
+            let batch = batch.clone();
+            async move {
+                on_sticker(event, room, batch).await;
+            }
         }
     });
+    let _sticker_guard = client.event_handler_drop_guard(handle);
 
-    client
-        .sync_with_result_callback(sync_settings, |sync_result| async move {
-            let response = sync_result?;
+    loop {
+        match client.sync_once(sync_settings.clone()).await {
+            Ok(response) => {
+                sync_settings = sync_settings.token(response.next_batch.clone());
+                persist_sync_token(session_file, response.next_batch.clone()).await;
+                sender
+                    .send(ClientMessage::SyncTokenChanged(response.next_batch))
+                    .await?;
+                connection.succeeded().await?;
 
-            persist_sync_token(session_file, response.next_batch)
-                .await
-                .map_err(|err| Error::UnknownError(err.into()))?;
+                let messages = std::mem::take(&mut *batch.lock().unwrap());
+                if !messages.is_empty() {
+                    sender.send(ClientMessage::NewMessages(messages)).await?;
+                }
+                let reactions = std::mem::take(&mut *reactions.lock().unwrap());
+                if !reactions.is_empty() {
+                    sender.send(ClientMessage::NewReactions(reactions)).await?;
+                }
 
-            Ok(LoopCtrl::Continue)
-        })
-        .await?;
+                check_invites(&client, &mut invites, &mut sender).await?;
+                check_avatars(&client, &mut avatars, &mut sender).await?;
+            }
+            Err(error) => {
+                warn!("An error occurred during sync: {error}");
+                if let Some(invalidated) = SessionInvalidated::from_error(&error) {
+                    sender
+                        .send(ClientMessage::SessionInvalidated(invalidated))
+                        .await?;
+                    return Ok(());
+                }
+                connection.failed().await?;
+            }
+        }
+    }
+}
+
+/// Tells the UI when the rooms we are invited to differ from `known`.
+async fn check_invites(
+    client: &Client,
+    known: &mut HashSet<OwnedRoomId>,
+    sender: &mut Sender<ClientMessage>,
+) -> anyhow::Result<()> {
+    let invites: HashSet<_> = client
+        .invited_rooms()
+        .iter()
+        .map(|room| room.room_id().to_owned())
+        .collect();
+
+    if invites != *known {
+        *known = invites;
+        sender.send(ClientMessage::InvitesChanged).await?;
+    }
 
     Ok(())
 }
 
-async fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+/// Tells the UI about the rooms whose avatar changed since the last call, so it
+/// only downloads those.
+async fn check_avatars(
+    client: &Client,
+    known: &mut HashMap<OwnedRoomId, Option<OwnedMxcUri>>,
+    sender: &mut Sender<ClientMessage>,
+) -> anyhow::Result<()> {
+    let mut changed = Vec::new();
+
+    for room in client.rooms() {
+        if room.state() == RoomState::Invited {
+            continue;
+        }
+
+        // A single room failing must not stop the sync loop, try it again on
+        // the next sync instead.
+        let url = match room_avatar_url(&room).await {
+            Ok(url) => url,
+            Err(error) => {
+                warn!(
+                    "Failed to get the avatar of {} with error {error}",
+                    room.room_id()
+                );
+                continue;
+            }
+        };
+        if known.get(room.room_id()) != Some(&url) {
+            known.insert(room.room_id().to_owned(), url.clone());
+            changed.push((room.room_id().to_owned(), url));
+        }
+    }
 
-    full_session.sync_token = Some(sync_token);
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session).await?;
+    if !changed.is_empty() {
+        sender.send(ClientMessage::AvatarsChanged(changed)).await?;
+    }
 
     Ok(())
 }
 
+/// The display name of `member` in its room, followed by their user ID when
+/// another member uses the same name, as the spec requires.
+fn sender_name(member: &RoomMember) -> String {
+    if member.name_ambiguous() {
+        format!("{} ({})", member.name(), member.user_id())
+    } else {
+        member.name().to_owned()
+    }
+}
+
+/// State of the connection to the homeserver, as seen by the sync loop.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    #[default]
+    Connecting,
+    Online,
+    Reconnecting,
+    Offline,
+}
+
+/// Keeps track of consecutive sync failures, backing off between retries and
+/// forwarding connection state changes to the UI.
+struct ConnectionTracker {
+    sender: Sender<ClientMessage>,
+    state: ConnectionState,
+    failures: u32,
+}
+
+impl ConnectionTracker {
+    const BASE_DELAY: Duration = Duration::from_millis(500);
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+    /// Number of consecutive failures after which we consider ourselves offline.
+    const OFFLINE_AFTER: u32 = 4;
+
+    fn new(sender: Sender<ClientMessage>) -> Self {
+        Self {
+            sender,
+            state: ConnectionState::Connecting,
+            failures: 0,
+        }
+    }
+
+    async fn set(&mut self, state: ConnectionState) -> anyhow::Result<()> {
+        self.state = state;
+        self.sender
+            .send(ClientMessage::ConnectionChanged(state))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn succeeded(&mut self) -> anyhow::Result<()> {
+        self.failures = 0;
+
+        if self.state != ConnectionState::Online {
+            self.set(ConnectionState::Online).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a failed sync and waits before the next attempt, using
+    /// exponential backoff with full jitter.
+    async fn failed(&mut self) -> anyhow::Result<()> {
+        self.failures = self.failures.saturating_add(1);
+
+        let state = match self.state {
+            ConnectionState::Connecting if self.failures < Self::OFFLINE_AFTER => {
+                ConnectionState::Connecting
+            }
+            _ if self.failures < Self::OFFLINE_AFTER => ConnectionState::Reconnecting,
+            _ => ConnectionState::Offline,
+        };
+
+        if state != self.state {
+            self.set(state).await?;
+        }
+
+        let ceiling = Self::BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(Self::MAX_DELAY);
+        let delay = rand::thread_rng().gen_range(Duration::ZERO..=ceiling);
+
+        info!("Retrying sync in {}ms…", delay.as_millis());
+        tokio::time::sleep(delay).await;
+
+        Ok(())
+    }
+}
+
+/// Saves where the next start resumes syncing. Failing to do so is only
+/// logged, as it just means syncing more on the next start.
+async fn persist_sync_token(session_file: &Path, sync_token: String) {
+    let result = update_session(session_file, |full_session| {
+        full_session.sync_token = Some(sync_token);
+    })
+    .await;
+
+    if let Err(error) = result {
+        warn!("Failed to persist the sync token: {error}");
+    }
+}
+
 async fn on_room_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
-    sender: Sender<ClientMessage>,
+    batch: Arc<Mutex<Vec<Message>>>,
 ) {
     if room.state() != RoomState::Joined {
         return;
@@ -263,28 +3084,360 @@ async fn on_room_message(
     if room.client().user_id().unwrap() == event.sender {
         return;
     }
-    let MessageType::Text(text_content) = &event.content.msgtype else {
+    let (kind, body, formatted) = match &event.content.msgtype {
+        MessageType::Text(content) => (MessageKind::Text, &content.body, &content.formatted),
+        MessageType::Notice(content) => (MessageKind::Notice, &content.body, &content.formatted),
+        MessageType::Emote(content) => (MessageKind::Emote, &content.body, &content.formatted),
+        _ => return,
+    };
+
+    let mut message = timeline_message(
+        &room,
+        &event.sender,
+        &event.event_id,
+        event.origin_server_ts,
+        kind,
+        body.clone(),
+    )
+    .await;
+    if let Some(formatted) = formatted
+        .as_ref()
+        .filter(|f| f.format == MessageFormat::Html)
+    {
+        message.emotes = html_emotes(&formatted.body);
+    }
+
+    batch.lock().unwrap().push(message);
+}
+
+/// Shows stickers, including ours, which are only shown once the server
+/// echoes them.
+async fn on_sticker(event: OriginalSyncStickerEvent, room: Room, batch: Arc<Mutex<Vec<Message>>>) {
+    if room.state() != RoomState::Joined {
+        return;
+    }
+
+    let mut message = timeline_message(
+        &room,
+        &event.sender,
+        &event.event_id,
+        event.origin_server_ts,
+        MessageKind::Sticker,
+        event.content.body,
+    )
+    .await;
+    message.sticker = Some(event.content.url);
+
+    batch.lock().unwrap().push(message);
+}
+
+/// Collects reactions, including ours, which are only shown once the server
+/// echoes them.
+async fn on_reaction(
+    event: OriginalSyncReactionEvent,
+    room: Room,
+    reactions: Arc<Mutex<Vec<Reaction>>>,
+) {
+    if room.state() != RoomState::Joined {
+        return;
+    }
+
+    reactions.lock().unwrap().push(Reaction {
+        event_id: event.content.relates_to.event_id,
+        key: event.content.relates_to.key,
+        sender_id: event.sender,
+    });
+}
+
+/// Describes membership, name and topic changes in the timeline of a room.
+async fn on_room_state(event: AnySyncTimelineEvent, room: Room, batch: Arc<Mutex<Vec<Message>>>) {
+    if room.state() != RoomState::Joined {
+        return;
+    }
+    let AnySyncTimelineEvent::State(event) = event else {
         return;
     };
 
-    let room_name = match room.display_name().await {
-        Ok(room_name) => room_name.to_string(),
-        Err(error) => {
-            println!("Error getting room display name: {error}");
+    let sender = match room.get_member_no_sync(event.sender()).await {
+        Ok(Some(member)) => sender_name(&member),
+        _ => event.sender().to_string(),
+    };
+    let (kind, contents) = match &event {
+        AnySyncStateEvent::RoomMember(SyncStateEvent::Original(event)) => {
+            let Some(change) = describe_membership_change(event, &sender) else {
+                return;
+            };
+            (MessageKind::Membership, change)
+        }
+        AnySyncStateEvent::RoomName(SyncStateEvent::Original(event)) => (
+            MessageKind::RoomChange,
+            if event.content.name.is_empty() {
+                format!("{sender} removed the room name")
+            } else {
+                format!("{sender} changed the room name to {}", event.content.name)
+            },
+        ),
+        AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(event)) => (
+            MessageKind::RoomChange,
+            if event.content.topic.is_empty() {
+                format!("{sender} removed the topic")
+            } else {
+                format!("{sender} changed the topic to {}", event.content.topic)
+            },
+        ),
+        _ => return,
+    };
+
+    let message = timeline_message(
+        &room,
+        event.sender(),
+        event.event_id(),
+        event.origin_server_ts(),
+        kind,
+        contents,
+    )
+    .await;
+
+    batch.lock().unwrap().push(message);
+}
+
+/// A line such as "alice joined" for a membership event sent by `sender`, or
+/// `None` for changes that aren't worth showing.
+fn describe_membership_change(event: &OriginalSyncRoomMemberEvent, sender: &str) -> Option<String> {
+    let target = event
+        .content
+        .displayname
+        .clone()
+        .unwrap_or_else(|| event.state_key.to_string());
+
+    let change = match event.membership_change() {
+        MembershipChange::Joined | MembershipChange::InvitationAccepted => {
+            format!("{target} joined")
+        }
+        MembershipChange::Left => format!("{target} left"),
+        MembershipChange::Banned => format!("{sender} banned {target}"),
+        MembershipChange::Unbanned => format!("{sender} unbanned {target}"),
+        MembershipChange::Kicked => format!("{sender} removed {target}"),
+        MembershipChange::KickedAndBanned => format!("{sender} removed and banned {target}"),
+        MembershipChange::Invited => format!("{sender} invited {target}"),
+        MembershipChange::InvitationRejected => format!("{target} declined the invite"),
+        MembershipChange::InvitationRevoked => format!("{sender} withdrew the invite of {target}"),
+        MembershipChange::Knocked => format!("{target} asked to join"),
+        MembershipChange::KnockAccepted => format!("{sender} accepted {target}'s request to join"),
+        MembershipChange::KnockRetracted => format!("{target} withdrew their request to join"),
+        MembershipChange::KnockDenied => format!("{sender} denied {target}'s request to join"),
+        MembershipChange::ProfileChanged {
+            displayname_change: Some(change),
+            ..
+        } => match (change.old, change.new) {
+            (Some(old), Some(new)) => format!("{old} changed their name to {new}"),
+            (Some(old), None) => format!("{old} removed their name"),
+            (None, _) => format!("{} set their name to {target}", event.state_key),
+        },
+        MembershipChange::ProfileChanged {
+            avatar_url_change: Some(_),
+            ..
+        } => format!("{target} changed their avatar"),
+        _ => return None,
+    };
+
+    Some(match &event.content.reason {
+        Some(reason) if !reason.is_empty() => format!("{change}: {reason}"),
+        _ => change,
+    })
+}
 
-            room.room_id().to_string()
+/// A message of the timeline sent by `sender`, with their name and avatar
+/// in `room`.
+async fn timeline_message(
+    room: &Room,
+    sender_id: &UserId,
+    event_id: &EventId,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    kind: MessageKind,
+    contents: String,
+) -> Message {
+    let (sender, sender_avatar) = match room.get_member_no_sync(sender_id).await {
+        Ok(Some(member)) => (
+            sender_name(&member),
+            member.avatar_url().map(ToOwned::to_owned),
+        ),
+        Ok(None) => (sender_id.to_string(), None),
+        Err(error) => {
+            warn!("Failed to look up sender {sender_id}: {error}");
+            (sender_id.to_string(), None)
         }
     };
 
-    println!("[{room_name}] {}: {}", event.sender, text_content.body);
+    Message {
+        room_id: room.room_id().to_owned(),
+        event_id: Some(event_id.to_owned()),
+        sender_id: sender_id.to_owned(),
+        sender,
+        sender_avatar,
+        kind,
+        contents,
+        timestamp: origin_server_ts
+            .to_system_time()
+            .map_or_else(Local::now, DateTime::from),
+        emotes: Vec::new(),
+        sticker: None,
+        outgoing: None,
+    }
+}
 
-    let message = Message {
-        sender: event.sender.to_string(),
-        contents: text_content.body.clone(),
-        timestamp: Local::now(),
+#[cfg(test)]
+mod tests {
+    use iced::futures::{channel::mpsc, StreamExt};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
     };
 
-    if let Err(e) = sender.send(ClientMessage::NewMessage(message)) {
-        println!("Error sending message to Iced application: {}", e);
+    use super::*;
+
+    /// Starts a homeserver offering single sign-on through two identity
+    /// providers, which accepts `token` as a login token.
+    async fn sso_homeserver(token: &str) -> MockServer {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "versions": ["v1.8"],
+            })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/login"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "flows": [
+                    {
+                        "type": "m.login.sso",
+                        "identity_providers": [
+                            { "id": "oidc-github", "name": "GitHub", "brand": "github" },
+                            { "id": "oidc-gitlab", "name": "GitLab", "brand": "gitlab" },
+                        ],
+                    },
+                    { "type": "m.login.token" },
+                ],
+            })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/login"))
+            .and(body_partial_json(json!({
+                "type": "m.login.token",
+                "token": token,
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@alice:example.org",
+                "access_token": "access-token",
+                "device_id": "DEVICEID",
+            })))
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    async fn client(server: &MockServer) -> Client {
+        Client::builder()
+            .homeserver_url(server.uri())
+            .build()
+            .await
+            .unwrap()
+    }
+
+    /// Plays the part of the browser: waits for the login URL and follows the
+    /// redirect back to the loopback listener with `query` appended.
+    async fn redirect_browser(mut receiver: mpsc::Receiver<ClientMessage>, query: &str) -> Url {
+        let Some(ClientMessage::BrowserLoginOpened(url)) = receiver.next().await else {
+            panic!("The login URL was not sent");
+        };
+
+        let redirect_url = url
+            .query_pairs()
+            .find_map(|(key, value)| (key == "redirectUrl").then(|| value.into_owned()))
+            .expect("The login URL should carry the redirect URL");
+        let mut redirect_url = Url::parse(&redirect_url).unwrap();
+        redirect_url.set_query(Some(query));
+
+        let response = matrix_sdk::reqwest::get(redirect_url).await.unwrap();
+        assert!(response.status().is_success());
+
+        url
+    }
+
+    #[test]
+    fn finds_emotes_quoted_either_way() {
+        let html = concat!(
+            "Hi <img data-mx-emoticon src=\"mxc://example.org/blobcat\" alt=\":blobcat:\" /> ",
+            "<img src='mxc://example.org/wave' data-mx-emoticon title=':wave:'> ",
+            "<img src=mxc://example.org/tick alt=:tick: data-mx-emoticon> ",
+            "<img src=\"mxc://example.org/photo\" alt=\"A photo\">",
+        );
+
+        assert_eq!(
+            html_emotes(html),
+            [
+                (":blobcat:".to_owned(), "mxc://example.org/blobcat".into()),
+                (":wave:".to_owned(), "mxc://example.org/wave".into()),
+                (":tick:".to_owned(), "mxc://example.org/tick".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_identity_providers() {
+        let server = sso_homeserver("token").await;
+
+        let flows = login_flows(&client(&server).await).await.unwrap();
+
+        assert!(!flows.password);
+        let providers = flows.sso.expect("The homeserver offers single sign-on");
+        let names: Vec<_> = providers.iter().map(|idp| idp.name.as_str()).collect();
+        assert_eq!(names, ["GitHub", "GitLab"]);
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_the_sso_login_token() {
+        let server = sso_homeserver("token").await;
+        let client = client(&server).await;
+        let (sender, receiver) = mpsc::channel(1);
+
+        let (session, url) = tokio::join!(
+            authorize_sso(&client, Some("oidc-gitlab"), sender),
+            redirect_browser(receiver, "loginToken=token"),
+        );
+
+        assert!(url.path().ends_with("/login/sso/redirect/oidc-gitlab"));
+        let UserSession::Matrix(session) = session.unwrap() else {
+            panic!("Expected a Matrix session");
+        };
+        assert_eq!(session.meta.user_id, "@alice:example.org");
+        assert_eq!(session.meta.device_id, "DEVICEID");
+        assert_eq!(session.tokens.access_token, "access-token");
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_login_token() {
+        let server = sso_homeserver("token").await;
+        let client = client(&server).await;
+        let (sender, receiver) = mpsc::channel(1);
+
+        let (session, _) = tokio::join!(
+            authorize_sso(&client, None, sender),
+            redirect_browser(receiver, "error=denied"),
+        );
+
+        assert_eq!(
+            session.unwrap_err().to_string(),
+            "The homeserver did not send a login token"
+        );
+        assert!(client.user_id().is_none());
     }
 }
@@ -1,9 +1,9 @@
 use iced::{
     color,
     widget::{
-        button,
+        button, container,
         scrollable::{self, Scroller},
-        text_input,
+        text_editor, text_input,
     },
     Background, Color, Theme,
 };
@@ -131,3 +131,136 @@ impl text_input::StyleSheet for TextInputComposer {
         }
     }
 }
+
+pub(crate) struct TextEditorComposer;
+
+impl text_editor::StyleSheet for TextEditorComposer {
+    type Style = Theme;
+
+    fn active(&self, _style: &Self::Style) -> text_editor::Appearance {
+        text_editor::Appearance {
+            background: Background::Color(color!(0x4c4c4c)),
+            border: iced::Border::with_radius(20.0),
+        }
+    }
+
+    fn focused(&self, _style: &Self::Style) -> text_editor::Appearance {
+        self.active(&Theme::Dark)
+    }
+
+    fn placeholder_color(&self, _style: &Self::Style) -> Color {
+        color!(0x969696)
+    }
+
+    fn value_color(&self, _style: &Self::Style) -> Color {
+        color!(0xffffff)
+    }
+
+    fn disabled_color(&self, _style: &Self::Style) -> Color {
+        color!(0x969696)
+    }
+
+    fn selection_color(&self, _style: &Self::Style) -> Color {
+        color!(0x0000ff)
+    }
+
+    fn disabled(&self, _style: &Self::Style) -> text_editor::Appearance {
+        self.active(&Theme::Dark)
+    }
+}
+
+pub(crate) struct ButtonMessageAction;
+
+impl button::StyleSheet for ButtonMessageAction {
+    type Style = Theme;
+
+    fn active(&self, _style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            background: Some(Background::Color(color!(0x4c4c4c))),
+            border: iced::Border::with_radius(8.0),
+            text_color: Color::WHITE,
+            ..Default::default()
+        }
+    }
+
+    fn hovered(&self, _style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            background: Some(Background::Color(color!(0x004fee))),
+            border: iced::Border::with_radius(8.0),
+            text_color: Color::WHITE,
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) struct ButtonLink;
+
+impl button::StyleSheet for ButtonLink {
+    type Style = Theme;
+
+    fn active(&self, _style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            text_color: color!(0x6ea8fe),
+            ..Default::default()
+        }
+    }
+
+    fn hovered(&self, _style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            text_color: color!(0x9ec5fe),
+            ..Default::default()
+        }
+    }
+}
+
+/// The placeholder of a room without an avatar, a circle in its own color.
+pub(crate) struct ContainerAvatar {
+    pub color: Color,
+    pub size: f32,
+}
+
+impl container::StyleSheet for ContainerAvatar {
+    type Style = Theme;
+
+    fn appearance(&self, _style: &Self::Style) -> container::Appearance {
+        container::Appearance {
+            background: Some(Background::Color(self.color)),
+            border: iced::Border::with_radius(self.size / 2.0),
+            text_color: Some(Color::WHITE),
+            ..Default::default()
+        }
+    }
+}
+
+/// A reaction under a message, highlighted when it is one of ours.
+pub(crate) struct ButtonReaction {
+    pub ours: bool,
+}
+
+impl button::StyleSheet for ButtonReaction {
+    type Style = Theme;
+
+    fn active(&self, _style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            background: Some(Background::Color(color!(0x4c4c4c))),
+            border: iced::Border {
+                color: if self.ours {
+                    color!(0x004fee)
+                } else {
+                    Color::TRANSPARENT
+                },
+                width: 1.0,
+                radius: 12.0.into(),
+            },
+            text_color: Color::WHITE,
+            ..Default::default()
+        }
+    }
+
+    fn hovered(&self, style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            background: Some(Background::Color(color!(0x5c5c5c))),
+            ..self.active(style)
+        }
+    }
+}